@@ -0,0 +1,305 @@
+//! Compares the throughput of the VM dispatch loop, which runs verified chunks without checks,
+//! against the checked dispatch it replaced, over workloads exercising each kind of instruction
+//! the VM currently supports.
+//!
+//! Fib, loops, string building and method calls would be the representative workloads, but they
+//! need scripts compiled to bytecode: the compiler does not emit chunks from the ast yet, and the
+//! VM has no opcodes for locals, conditional jumps, user functions or classes. Until then, the
+//! workloads are hand-built chunks of the instructions those scripts would spend most of their
+//! time in: arithmetic over constants, stack traffic and jumps.
+//!
+//! The checked dispatch is timed with and without the stack tracing the old loop did after each
+//! instruction. Most of the speedup comes from dropping that tracing: without it, the checked loop
+//! runs about as fast as the VM, which also counts fuel and checks for interruptions and pauses
+//! before each instruction.
+//!
+//! Run with `cargo bench --bench dispatch`
+
+use std::{
+    hint::black_box,
+    io::{Write, sink},
+    time::{Duration, Instant},
+};
+
+use ordered_float::OrderedFloat;
+use rox::{
+    bitwise,
+    chunks::{Chunk, opcodes::OpCode, value::Value},
+    errors::RuntimeErrorKind,
+    vm::{
+        stack::STACK_SIZE,
+        vm::{VM, VMConfig, VMResult},
+    },
+};
+
+const INSTRUCTIONS: usize = 30_000;
+const ITERATIONS: u32 = 100;
+/// number of timed batches of ITERATIONS runs, over which the median time is taken
+const SAMPLES: usize = 10;
+
+fn number(n: f64) -> Value {
+    Value::Number(OrderedFloat(n))
+}
+
+/// long chain of binary operations over loaded constants: `((1 + 2) * 3 - 4) / 5 ...`
+fn arithmetic() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(number(1.0));
+
+    let ops = [
+        OpCode::Add,
+        OpCode::Multiply,
+        OpCode::Subtract,
+        OpCode::Divide,
+    ];
+    for i in 0..INSTRUCTIONS / 3 {
+        chunk.write(OpCode::Load);
+        chunk.write((i % 255) as u8 + 1);
+        chunk.write(ops[i % ops.len()]);
+    }
+    for i in 1..=255 {
+        chunk.constants.push(number(i as f64));
+    }
+
+    chunk
+}
+
+/// same chain as `arithmetic`, using superinstructions
+fn superinstructions() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(number(1.0));
+
+    let ops = [
+        OpCode::AddConstant,
+        OpCode::MultiplyConstant,
+        OpCode::SubtractConstant,
+        OpCode::DivideConstant,
+    ];
+    for i in 0..INSTRUCTIONS / 2 {
+        chunk.write(ops[i % ops.len()]);
+        chunk.write((i % 255) as u8 + 1);
+    }
+    for i in 1..=255 {
+        chunk.constants.push(number(i as f64));
+    }
+
+    chunk
+}
+
+/// values pushed and popped from the stack
+fn stack() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.constants.push(number(42.0));
+
+    for _ in 0..INSTRUCTIONS / 4 {
+        chunk.write(OpCode::Load);
+        chunk.write(0u8);
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Pop);
+    }
+
+    chunk
+}
+
+/// chain of jumps, each skipping over dead code
+fn jumps() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.constants.push(number(42.0));
+
+    for _ in 0..INSTRUCTIONS {
+        let jump = chunk.write_jump();
+        chunk.write(OpCode::Pop);
+        chunk.patch_jump(jump);
+    }
+
+    chunk
+}
+
+/// pushes value onto stack, which holds at most `STACK_SIZE` values
+fn push(stack: &mut Vec<Value>, value: Value) -> Result<(), RuntimeErrorKind> {
+    if stack.len() >= STACK_SIZE {
+        return Err(RuntimeErrorKind::StackOverflow);
+    }
+    stack.push(value);
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, RuntimeErrorKind> {
+    stack.pop().ok_or(RuntimeErrorKind::StackUnderflow)
+}
+
+/// Runs chunk the way the VM did before dispatching verified chunks: each opcode is decoded with
+/// `OpCode::try_from`, each stack access is checked and superinstructions share the arm of the
+/// operation they fuse, matching the opcode again.
+///
+/// The old loop also printed the stack after each instruction, in every build. With traced, the
+/// stack is formatted into a sink instead, which leaves out the cost of writing to stdout
+fn run_checked(
+    chunk: &Chunk,
+    stack: &mut Vec<Value>,
+    traced: bool,
+) -> Result<(), RuntimeErrorKind> {
+    stack.clear();
+    let mut ip = 0;
+
+    while ip < chunk.code.len() {
+        let op_code = OpCode::try_from(chunk.code[ip]).unwrap();
+        ip += 1;
+
+        match op_code {
+            OpCode::Load => {
+                push(stack, chunk.constants[chunk.code[ip] as usize])?;
+                ip += 1;
+            }
+            OpCode::Pop => {
+                pop(stack)?;
+            }
+            OpCode::Jump => {
+                let jump = bitwise::u16_from_bytes(&[chunk.code[ip], chunk.code[ip + 1]]);
+                ip += 2 + jump as usize;
+            }
+            OpCode::Negate => match pop(stack)? {
+                Value::Number(n) => push(stack, Value::Number(-n))?,
+                _ => return Err(RuntimeErrorKind::Type),
+            },
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => {
+                let rhs = match op_code {
+                    OpCode::AddConstant
+                    | OpCode::SubtractConstant
+                    | OpCode::MultiplyConstant
+                    | OpCode::DivideConstant => {
+                        ip += 1;
+                        chunk.constants[chunk.code[ip - 1] as usize]
+                    }
+                    _ => pop(stack)?,
+                };
+                let lhs = pop(stack)?;
+
+                let value = match op_code {
+                    OpCode::Add | OpCode::AddConstant => lhs.add(rhs),
+                    OpCode::Subtract | OpCode::SubtractConstant => lhs.sub(rhs),
+                    OpCode::Multiply | OpCode::MultiplyConstant => lhs.mult(rhs),
+                    _ => lhs.div(rhs),
+                };
+                push(stack, value?)?;
+            }
+            op_code => panic!("'{}' is not used by the workloads", op_code),
+        }
+
+        if traced {
+            let values = stack.iter().map(Value::to_string).collect::<Vec<_>>();
+            writeln!(sink(), "[DEBUG]\t\t\tstack: [{}]", values.join(", ")).unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+/// median time per call of run, over SAMPLES batches of ITERATIONS calls
+fn time(mut run: impl FnMut()) -> Duration {
+    // --- warm up
+    for _ in 0..ITERATIONS / 10 {
+        run();
+    }
+
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                run();
+            }
+            start.elapsed() / ITERATIONS
+        })
+        .collect();
+    samples.sort();
+    samples[SAMPLES / 2]
+}
+
+/// time per run of a workload with each dispatch loop
+#[derive(Default, Clone, Copy)]
+struct Timing {
+    /// checked dispatch, without tracing the stack
+    checked: Duration,
+    /// checked dispatch, tracing the stack as the VM did before dispatching verified chunks
+    traced: Duration,
+    /// dispatch of verified chunks by the VM
+    verified: Duration,
+}
+
+impl Timing {
+    /// prints the times of each loop, with the speedup of the VM over the traced and the checked
+    /// loops, in this order
+    fn print(&self, name: &str) {
+        let speedup = |before: Duration| before.as_secs_f64() / self.verified.as_secs_f64();
+        println!(
+            "{:<18} traced {:>12?}  checked {:>10?}  verified {:>10?}  speedup {:.2}x / {:.2}x",
+            name,
+            self.traced,
+            self.checked,
+            self.verified,
+            speedup(self.traced),
+            speedup(self.checked)
+        );
+    }
+}
+
+/// times chunk with each dispatch loop, after checking that they compute the same values
+fn bench(name: &str, chunk: Chunk) -> Timing {
+    let mut stack = vec![];
+    run_checked(&chunk, &mut stack, false).expect("checked dispatch failed");
+
+    // --- each workload is run as written, the peephole optimizer would remove most of them
+    let config = VMConfig {
+        peephole: false,
+        ..Default::default()
+    };
+    let mut vm = VM::with_config(chunk.clone(), config);
+    // --- the chunks do not return, so the values computed are left on the stack
+    assert_eq!(vm.run(), VMResult::Ok, "workload '{}' failed", name);
+    assert_eq!(
+        vm.stack().values(),
+        stack.as_slice(),
+        "the dispatch loops computed different values for '{}'",
+        name
+    );
+
+    let timing = Timing {
+        checked: time(|| {
+            black_box(run_checked(&chunk, &mut stack, false)).unwrap();
+        }),
+        traced: time(|| {
+            black_box(run_checked(&chunk, &mut stack, true)).unwrap();
+        }),
+        verified: time(|| {
+            black_box(vm.run());
+        }),
+    };
+    timing.print(name);
+    timing
+}
+
+fn main() {
+    let workloads = [
+        ("arithmetic", arithmetic()),
+        ("superinstructions", superinstructions()),
+        ("stack", stack()),
+        ("jumps", jumps()),
+    ];
+
+    let total = workloads
+        .into_iter()
+        .map(|(name, chunk)| bench(name, chunk))
+        .fold(Timing::default(), |total, timing| Timing {
+            checked: total.checked + timing.checked,
+            traced: total.traced + timing.traced,
+            verified: total.verified + timing.verified,
+        });
+    total.print("total");
+}
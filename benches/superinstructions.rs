@@ -0,0 +1,111 @@
+//! Compares executing arithmetic over constants with plain `Load c; <op>` pairs against the
+//! superinstructions the peephole optimizer fuses them into.
+//!
+//! Run with `cargo bench --bench superinstructions`
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use ordered_float::OrderedFloat;
+use rox::{
+    chunks::{Chunk, opcodes::OpCode, value::Value},
+    optimizer::peephole::Peephole,
+    vm::vm::{VM, VMConfig, VMResult},
+};
+
+const OPERATIONS: usize = 10_000;
+const ITERATIONS: u32 = 100;
+/// number of timed batches of ITERATIONS runs, over which the median and fastest times are taken
+const SAMPLES: usize = 10;
+
+/// builds `((((0 + 3) * 2) - 1) / 2 ...` with OPERATIONS operations, all over the same constants,
+/// leaving the result on the stack
+fn arithmetic_chunk() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(Value::Number(OrderedFloat(0.0)));
+
+    let operands = [
+        (OpCode::Add, 3.0),
+        (OpCode::Multiply, 2.0),
+        (OpCode::Subtract, 1.0),
+        (OpCode::Divide, 2.0),
+    ];
+    let first_operand = chunk.constants.len() as u8;
+    for (_, n) in operands {
+        chunk.constants.push(Value::Number(OrderedFloat(n)));
+    }
+
+    for i in 0..OPERATIONS {
+        let (op, _) = operands[i % operands.len()];
+        chunk.write(OpCode::Load);
+        chunk.write(first_operand + (i % operands.len()) as u8);
+        chunk.write(op);
+    }
+
+    chunk
+}
+
+/// median and fastest time per run of a variant
+struct Timing {
+    median: Duration,
+    fastest: Duration,
+}
+
+/// Runs chunk as written, without letting the VM optimize it, and returns the value it computes
+/// along with its timing
+fn bench(name: &str, chunk: Chunk) -> (Value, Timing) {
+    let config = VMConfig {
+        peephole: false,
+        ..Default::default()
+    };
+    let mut vm = VM::with_config(chunk, config);
+    // --- the chunk does not return, so the value computed is left on the stack
+    assert_eq!(vm.run(), VMResult::Ok, "variant '{}' failed", name);
+    let value = *vm.stack().values().last().expect("no value computed");
+
+    // --- warm up
+    for _ in 0..ITERATIONS / 10 {
+        black_box(vm.run());
+    }
+
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                black_box(vm.run());
+            }
+            start.elapsed() / ITERATIONS
+        })
+        .collect();
+    samples.sort();
+
+    let timing = Timing {
+        median: samples[SAMPLES / 2],
+        fastest: samples[0],
+    };
+    println!(
+        "{:<20} median {:>12?}   fastest {:>12?} per run",
+        name, timing.median, timing.fastest
+    );
+    (value, timing)
+}
+
+fn main() {
+    let chunk = arithmetic_chunk();
+    let fused = Peephole::optimize(chunk.clone());
+
+    let (plain_value, plain) = bench("load + op", chunk);
+    let (fused_value, fused) = bench("superinstructions", fused);
+    assert_eq!(
+        plain_value, fused_value,
+        "the variants computed different values"
+    );
+
+    println!(
+        "speedup: {:.2}x median, {:.2}x fastest",
+        plain.median.as_secs_f64() / fused.median.as_secs_f64(),
+        plain.fastest.as_secs_f64() / fused.fastest.as_secs_f64()
+    );
+}
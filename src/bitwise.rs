@@ -13,6 +13,11 @@ pub fn u32_from_bytes(bytes: &[u8; 3]) -> u32 {
     u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
 }
 
+/// returns a u16 from an array of 2 bytes
+pub fn u16_from_bytes(bytes: &[u8; 2]) -> u16 {
+    u16::from_be_bytes(*bytes)
+}
+
 /// computes the difference in bytes between dst and src (cur - start)
 /// start must be larger than src, as the value is returned in usize
 #[macro_export]
@@ -35,12 +35,26 @@ impl Chunk {
         }
     }
 
-    pub fn new_line(&mut self, offset: usize) {
-        let current_line = self.line_info.last().map_or(0, |l| l.line);
-        self.line_info.push(LineInfo {
-            line: current_line + 1,
-            op_offset: offset,
-        })
+    /// sets the source line of the instructions written from now on, usually the line of the
+    /// token being compiled. Lines are run-length encoded, so only changes of line are recorded
+    pub fn set_line(&mut self, line: usize) {
+        let offset = self.code.len();
+        match self.line_info.last_mut() {
+            Some(last) if last.line == line => {}
+            // --- nothing was written at the previous line, so it can be overwritten
+            Some(last) if last.op_offset == offset => {
+                last.line = line;
+                // --- merge with the run before it, if it is at the same line
+                if self.line_info.len() > 1 && self.line_info[self.line_info.len() - 2].line == line
+                {
+                    self.line_info.pop();
+                }
+            }
+            _ => self.line_info.push(LineInfo {
+                op_offset: offset,
+                line,
+            }),
+        }
     }
 
     pub fn write<T>(&mut self, byte: T)
@@ -50,7 +64,7 @@ impl Chunk {
         self.code.push(byte.into())
     }
 
-    fn write_24b(&mut self, val: u32) {
+    pub fn write_24b(&mut self, val: u32) {
         let (b4, b3, b2, b1) = bitwise::get_bytes(val);
         assert_eq!(
             b4, 0,
@@ -80,6 +94,26 @@ impl Chunk {
         }
     }
 
+    /// writes a jump with a placeholder offset and returns the offset of its operand, so that
+    /// it can be patched once the target of the jump is known
+    pub fn write_jump(&mut self) -> usize {
+        self.write(OpCode::Jump);
+        self.write(0xffu8);
+        self.write(0xffu8);
+        self.code.len() - 2
+    }
+
+    /// patches the operand of the jump at offset so that it jumps to the end of the code
+    pub fn patch_jump(&mut self, offset: usize) {
+        let jump = self.code.len() - offset - 2;
+        let [b2, b1] = u16::try_from(jump)
+            .expect("attempting to jump over more than 16bits of code")
+            .to_be_bytes();
+
+        self.code[offset] = b2;
+        self.code[offset + 1] = b1;
+    }
+
     /// pushes value into constant and returns the index into which it was pushed
     fn write_constant_aux(&mut self, value: Value) -> u32 {
         self.constants.push(value);
@@ -108,7 +142,11 @@ impl Chunk {
         idx += 1;
 
         let op_data: Option<String> = match op {
-            OpCode::Load => {
+            OpCode::Load
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => {
                 let operand_idx = self.code.get(idx).unwrap();
                 idx += 1;
                 let operand = self
@@ -128,6 +166,7 @@ impl Chunk {
                         .try_into()
                         .expect("should be an array of 3 bytes"),
                 );
+                idx += 3;
                 let operand = self
                     .constants
                     .get(operand_idx as usize)
@@ -135,12 +174,65 @@ impl Chunk {
 
                 Some(operand.to_string())
             }
+            OpCode::Jump => {
+                let jump_as_bytes = self
+                    .code
+                    .get(idx..=idx + 1)
+                    .expect("missing offset for jump");
+                let jump = bitwise::u16_from_bytes(
+                    jump_as_bytes
+                        .try_into()
+                        .expect("should be an array of 2 bytes"),
+                );
+                idx += 2;
+
+                Some(format!("+{} -> 0x{:0>6}", jump, idx + jump as usize))
+            }
+            OpCode::Call => {
+                let args = self
+                    .code
+                    .get(idx)
+                    .expect("missing number of arguments for call");
+                idx += 1;
+
+                Some(format!("{} args", args))
+            }
+            OpCode::BuildList | OpCode::BuildMap => {
+                let len_as_bytes = self
+                    .code
+                    .get(idx..=idx + 1)
+                    .expect("missing number of elements for list or map");
+                let len = bitwise::u16_from_bytes(
+                    len_as_bytes
+                        .try_into()
+                        .expect("should be an array of 2 bytes"),
+                );
+                idx += 2;
+
+                match op {
+                    OpCode::BuildMap => Some(format!("{} entries", len)),
+                    _ => Some(format!("{} elements", len)),
+                }
+            }
             OpCode::Return
+            | OpCode::Pop
+            | OpCode::GetIndex
+            | OpCode::SetIndex
             | OpCode::Negate
             | OpCode::Add
             | OpCode::Subtract
             | OpCode::Multiply
-            | OpCode::Divide => None,
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::BitNot
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::NotEqual => None,
         };
 
         log::debug!(
@@ -154,7 +246,7 @@ impl Chunk {
         idx
     }
 
-    fn get_line_info_from_offset(&self, offset: usize) -> &LineInfo {
+    pub fn get_line_info_from_offset(&self, offset: usize) -> &LineInfo {
         let mut low = 0;
         let mut high = self.line_info.len();
 
@@ -174,9 +266,49 @@ impl Chunk {
 }
 
 #[derive(Debug, Clone, Copy)]
-struct LineInfo {
+pub struct LineInfo {
     /// offset into Chunk::code
-    op_offset: usize,
+    pub op_offset: usize,
     /// line number of the operation at op_offset
-    line: usize,
+    pub line: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+
+    #[test]
+    fn line_info_is_run_length_encoded() {
+        let mut chunk = Chunk::new();
+        chunk.set_line(3);
+        chunk.write_constant(Value::Number(OrderedFloat(1.0)));
+        chunk.set_line(3);
+        chunk.write(OpCode::Negate);
+        chunk.set_line(7);
+        chunk.write(OpCode::Return);
+
+        assert_eq!(chunk.line_info.len(), 2);
+        assert_eq!(chunk.get_line_info_from_offset(0).line, 3);
+        assert_eq!(chunk.get_line_info_from_offset(2).line, 3);
+        assert_eq!(chunk.get_line_info_from_offset(3).line, 7);
+    }
+
+    #[test]
+    fn set_line_without_instructions() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Return);
+        chunk.set_line(2);
+        chunk.set_line(5);
+        chunk.write(OpCode::Return);
+        chunk.set_line(6);
+        chunk.set_line(5);
+        chunk.write(OpCode::Return);
+
+        assert_eq!(chunk.line_info.len(), 2);
+        assert_eq!(chunk.get_line_info_from_offset(0).line, 1);
+        assert_eq!(chunk.get_line_info_from_offset(1).line, 5);
+        assert_eq!(chunk.get_line_info_from_offset(2).line, 5);
+    }
 }
@@ -2,19 +2,142 @@ use std::fmt::Display;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[derive(Copy, Clone, Debug, IntoPrimitive, TryFromPrimitive)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
     Return,
     //
     Load,
     LoadLong,
+    Pop,
+    /// unconditional forward jump - the operand is a 16-bit offset from the next instruction
+    Jump,
+    /// calls the function below its arguments on the stack - the operand is the number of
+    /// arguments, which are replaced along with the function by the result of the call
+    Call,
+    /// builds a list from the values on top of the stack - the operand is the 16-bit number of
+    /// elements, which are replaced by the list, the first element being the deepest
+    BuildList,
+    /// builds a map from the pairs of keys and values on top of the stack - the operand is the
+    /// 16-bit number of entries, whose keys and values are replaced by the map
+    BuildMap,
+    /// replaces a list or a map and the index or key above it with the element at the index, or
+    /// the value of the key
+    GetIndex,
+    /// stores the value on top of the stack at the index or key below it in the list or map below
+    /// both, leaving the value as the result of the assignment
+    SetIndex,
     //
     Negate,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Power,
+    /// bitwise operations on the integers of their operands, see `Value::bit_and`
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    /// compares the contents of its operands, see `Heap::equals`
+    Equal,
+    NotEqual,
+    // --- superinstructions: fused `Load c; <op>`, with the index of c as operand
+    AddConstant,
+    SubtractConstant,
+    MultiplyConstant,
+    DivideConstant,
+}
+
+impl OpCode {
+    /// decodes byte without checking that it is a valid opcode
+    ///
+    /// # Safety
+    /// byte must encode an opcode, which holds for every opcode of a verified chunk
+    #[inline]
+    pub unsafe fn from_u8_unchecked(byte: u8) -> OpCode {
+        unsafe { std::mem::transmute::<u8, OpCode>(byte) }
+    }
+
+    /// returns the superinstruction fusing `Load c; self`, if any
+    pub fn with_constant_operand(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Add => Some(OpCode::AddConstant),
+            OpCode::Subtract => Some(OpCode::SubtractConstant),
+            OpCode::Multiply => Some(OpCode::MultiplyConstant),
+            OpCode::Divide => Some(OpCode::DivideConstant),
+            _ => None,
+        }
+    }
+
+    /// number of bytes of operands following the opcode
+    pub fn operand_len(&self) -> usize {
+        match self {
+            OpCode::Load
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant
+            | OpCode::Call => 1,
+            OpCode::LoadLong => 3,
+            OpCode::Jump | OpCode::BuildList | OpCode::BuildMap => 2,
+            OpCode::Return
+            | OpCode::Pop
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::Negate
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::BitNot
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::NotEqual => 0,
+        }
+    }
+
+    /// number of values the operation pops from and pushes onto the stack, as (pops, pushes). Calls
+    /// also pop their arguments and lists their elements, whose number is their operand, and maps
+    /// pop the key and value of each of their entries
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            OpCode::Load | OpCode::LoadLong | OpCode::BuildList | OpCode::BuildMap => (0, 1),
+            OpCode::Return | OpCode::Jump => (0, 0),
+            OpCode::Pop => (1, 0),
+            OpCode::Negate
+            | OpCode::BitNot
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant
+            | OpCode::Call => (1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+        }
+    }
 }
 
 impl Display for OpCode {
@@ -23,11 +146,32 @@ impl Display for OpCode {
             OpCode::Return => "RET",
             OpCode::Load => "LOAD",
             OpCode::LoadLong => "LOAD_LONG",
+            OpCode::Pop => "POP",
+            OpCode::Jump => "JUMP",
+            OpCode::Call => "CALL",
+            OpCode::BuildList => "BUILD_LIST",
+            OpCode::BuildMap => "BUILD_MAP",
+            OpCode::GetIndex => "GET_INDEX",
+            OpCode::SetIndex => "SET_INDEX",
             OpCode::Negate => "NEGATE",
             OpCode::Add => "ADD",
             OpCode::Subtract => "SUBTRACT",
             OpCode::Multiply => "MULTIPLY",
             OpCode::Divide => "DIVIDE",
+            OpCode::Modulo => "MODULO",
+            OpCode::Power => "POWER",
+            OpCode::BitAnd => "BIT_AND",
+            OpCode::BitOr => "BIT_OR",
+            OpCode::BitXor => "BIT_XOR",
+            OpCode::BitNot => "BIT_NOT",
+            OpCode::ShiftLeft => "SHIFT_LEFT",
+            OpCode::ShiftRight => "SHIFT_RIGHT",
+            OpCode::Equal => "EQUAL",
+            OpCode::NotEqual => "NOT_EQUAL",
+            OpCode::AddConstant => "ADD_CONSTANT",
+            OpCode::SubtractConstant => "SUBTRACT_CONSTANT",
+            OpCode::MultiplyConstant => "MULTIPLY_CONSTANT",
+            OpCode::DivideConstant => "DIVIDE_CONSTANT",
         };
         write!(f, "{}", display_data)
     }
@@ -3,22 +3,26 @@ use core::fmt;
 use anyhow::bail;
 use ordered_float::OrderedFloat;
 
+use crate::errors::{RuntimeError, RuntimeErrorKind};
 use crate::scanner::token::TokenType;
+use crate::vm::natives::Native;
 
-macro_rules! op_error {
-    ($lhs:expr, $rhs:expr) => {
-        anyhow::bail!(
-            "'{}' + '{}' is not a valid operation",
-            $lhs.value_type(),
-            $rhs.value_type()
-        )
-    };
-}
+/// Index of an object allocated in the heap of a VM
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ObjRef(pub u32);
 
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum Value {
     Number(OrderedFloat<f64>),
+    Bool(bool),
     Literal(&'static str),
+    /// string allocated at runtime, in the heap of the VM
+    String(ObjRef),
+    /// list allocated at runtime, in the heap of the VM
+    List(ObjRef),
+    /// map allocated at runtime, in the heap of the VM
+    Map(ObjRef),
+    Native(&'static Native),
     #[default]
     Empty,
 }
@@ -27,50 +31,202 @@ impl Value {
     pub fn value_type(&self) -> &str {
         match self {
             Value::Number(_) => "number",
+            Value::Bool(_) => "boolean",
             Value::Literal(_) => "string literal",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Native(_) => "native function",
             Value::Empty => "nil",
         }
     }
 
-    pub fn add(self, rhs: Self) -> anyhow::Result<Self> {
+    /// builds the error of a binary operation op between self and rhs that failed with kind.
+    /// Operations only return the kind of their errors, which keeps their results small in the
+    /// dispatch loop of the VM
+    #[cold]
+    #[inline(never)]
+    pub fn op_error(&self, kind: RuntimeErrorKind, op: &str, rhs: &Self) -> RuntimeError {
+        let msg = match kind {
+            RuntimeErrorKind::DivisionByZero => format!(
+                "right hand side of the {} is 0",
+                if op == "%" { "modulo" } else { "division" }
+            ),
+            RuntimeErrorKind::InvalidArgument if op == "<<" || op == ">>" => format!(
+                "'{}' requires integers and a non-negative shift amount, got {} and {}",
+                op, self, rhs
+            ),
+            RuntimeErrorKind::InvalidArgument => format!(
+                "'{}' requires integer operands, got {} and {}",
+                op, self, rhs
+            ),
+            _ => format!(
+                "'{}' {} '{}' is not a valid operation",
+                self.value_type(),
+                op,
+                rhs.value_type()
+            ),
+        };
+        RuntimeError::new(kind, msg)
+    }
+
+    /// builds the error of the unary operation op on self that failed with kind
+    #[cold]
+    #[inline(never)]
+    pub fn unary_error(&self, kind: RuntimeErrorKind, op: &str) -> RuntimeError {
+        let msg = match kind {
+            RuntimeErrorKind::InvalidArgument => {
+                format!("'{}' requires an integer operand, got {}", op, self)
+            }
+            _ => format!(
+                "'{}' is not a valid operand for '{}'",
+                self.value_type(),
+                op
+            ),
+        };
+        RuntimeError::new(kind, msg)
+    }
+
+    pub fn add(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
         match (&self, &rhs) {
             (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
-            _ => op_error!(self, rhs),
+            _ => Err(RuntimeErrorKind::Type),
         }
     }
 
-    pub fn sub(self, rhs: Self) -> anyhow::Result<Self> {
+    pub fn sub(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
         match (&self, &rhs) {
             (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l - r)),
-            _ => op_error!(self, rhs),
+            _ => Err(RuntimeErrorKind::Type),
         }
     }
 
-    pub fn mult(self, rhs: Self) -> anyhow::Result<Self> {
+    pub fn mult(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
         match (&self, &rhs) {
             (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l * r)),
-            _ => op_error!(self, rhs),
+            _ => Err(RuntimeErrorKind::Type),
         }
     }
 
-    pub fn div(self, rhs: Self) -> anyhow::Result<Self> {
+    pub fn div(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
         match (&self, &rhs) {
             (Value::Number(l), Value::Number(r)) => {
                 if r == &OrderedFloat(0.0) {
-                    anyhow::bail!("right hand side of the division is 0");
+                    return Err(RuntimeErrorKind::DivisionByZero);
                 }
                 Ok(Value::Number(l / r))
             }
-            _ => op_error!(self, rhs),
+            _ => Err(RuntimeErrorKind::Type),
+        }
+    }
+
+    /// remainder of the truncated division, which has the sign of the left hand side as in Rust,
+    /// e.g. `-7 % 3` is -1
+    pub fn modulo(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        match (&self, &rhs) {
+            (Value::Number(l), Value::Number(r)) => {
+                if r == &OrderedFloat(0.0) {
+                    return Err(RuntimeErrorKind::DivisionByZero);
+                }
+                Ok(Value::Number(l % r))
+            }
+            _ => Err(RuntimeErrorKind::Type),
+        }
+    }
+
+    pub fn pow(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        match (&self, &rhs) {
+            (Value::Number(l), Value::Number(r)) => Ok(Value::Number(OrderedFloat(l.powf(r.0)))),
+            _ => Err(RuntimeErrorKind::Type),
+        }
+    }
+
+    // --- bitwise operations, see `integer` for their operands
+
+    pub fn bit_and(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        let (l, r) = self.integers(rhs)?;
+        Ok(from_integer(l & r))
+    }
+
+    pub fn bit_or(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        let (l, r) = self.integers(rhs)?;
+        Ok(from_integer(l | r))
+    }
+
+    pub fn bit_xor(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        let (l, r) = self.integers(rhs)?;
+        Ok(from_integer(l ^ r))
+    }
+
+    pub fn bit_not(self) -> Result<Self, RuntimeErrorKind> {
+        match self {
+            Value::Number(n) => Ok(from_integer(!integer(n)?)),
+            _ => Err(RuntimeErrorKind::Type),
         }
     }
+
+    /// shifts the bits of self left by rhs, which must not be negative - bits shifted out are
+    /// lost, so shifting by 64 or more gives 0
+    pub fn shift_left(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        let (l, amount) = self.shift(rhs)?;
+        Ok(from_integer(l.checked_shl(amount).unwrap_or(0)))
+    }
+
+    /// arithmetic shift of the bits of self right by rhs, which must not be negative - the sign is
+    /// kept, so shifting by 64 or more gives 0 or -1
+    pub fn shift_right(self, rhs: Self) -> Result<Self, RuntimeErrorKind> {
+        let (l, amount) = self.shift(rhs)?;
+        Ok(from_integer(l.checked_shr(amount).unwrap_or(if l < 0 {
+            -1
+        } else {
+            0
+        })))
+    }
+
+    fn integers(self, rhs: Self) -> Result<(i64, i64), RuntimeErrorKind> {
+        match (self, rhs) {
+            (Value::Number(l), Value::Number(r)) => Ok((integer(l)?, integer(r)?)),
+            _ => Err(RuntimeErrorKind::Type),
+        }
+    }
+
+    fn shift(self, rhs: Self) -> Result<(i64, u32), RuntimeErrorKind> {
+        let (l, r) = self.integers(rhs)?;
+        if r < 0 {
+            return Err(RuntimeErrorKind::InvalidArgument);
+        }
+        Ok((l, u32::try_from(r).unwrap_or(u32::MAX)))
+    }
+}
+
+/// Bitwise operations work on the 64-bit two's complement integers of their operands, which must
+/// be integral numbers in the range of i64. Any other number, e.g. 1.5, NaN or 1e20, is an invalid
+/// argument rather than being truncated
+fn integer(n: OrderedFloat<f64>) -> Result<i64, RuntimeErrorKind> {
+    // --- i64::MAX rounds up to 2^63 as a f64, which is out of range
+    if n.fract() == 0.0 && n.0 >= i64::MIN as f64 && n.0 < i64::MAX as f64 {
+        Ok(n.0 as i64)
+    } else {
+        Err(RuntimeErrorKind::InvalidArgument)
+    }
+}
+
+/// the result of a bitwise operation as a number, rounded to the closest f64 beyond 2^53
+fn from_integer(n: i64) -> Value {
+    Value::Number(OrderedFloat(n as f64))
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let display_data = match self {
             Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
             Value::Literal(s) => String::from(*s),
+            // --- the contents of objects are only known to the heap, see `Heap::display`
+            Value::String(obj) => format!("<string #{}>", obj.0),
+            Value::List(obj) => format!("<list #{}>", obj.0),
+            Value::Map(obj) => format!("<map #{}>", obj.0),
+            Value::Native(native) => format!("<native fn {}>", native.name),
             Value::Empty => String::from("NONE"),
         };
 
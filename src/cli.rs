@@ -0,0 +1,245 @@
+use crate::optimizer::passes::{OptLevel, Pass};
+use crate::vm::{profiler::ProfileFormat, trace::TraceFormat};
+
+pub const USAGE: &str = "Usage: rox [run|debug] [-O0|-O1|-O2] [--enable-pass <pass>] [--disable-pass <pass>] [--strict] [--trace[=text|json]]
+                [--profile[=report|folded]] <file_path>
+       rox dap [-O0|-O1|-O2] [--enable-pass <pass>] [--disable-pass <pass>] [--strict]";
+
+/// What to do with the script
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Mode {
+    #[default]
+    Run,
+    /// run the script in the step debugger
+    Debug,
+    /// serve the Debug Adapter Protocol over stdio, with scripts given by the launch requests
+    Dap,
+}
+
+/// Options provided through the command line
+#[derive(Debug)]
+pub struct Options {
+    pub mode: Mode,
+    /// path of the script - empty in dap mode
+    pub path: String,
+    pub opt_level: OptLevel,
+    /// passes to run on top of the ones enabled by the optimization level
+    pub enabled_passes: Vec<Pass>,
+    /// passes not to run, even if enabled by the optimization level
+    pub disabled_passes: Vec<Pass>,
+    /// report operations guaranteed to fail at runtime as errors
+    pub strict: bool,
+    /// format of the execution trace written to stderr, if the run is traced
+    pub trace: Option<TraceFormat>,
+    /// format of the profile written to stderr once the run ends, if the run is profiled
+    pub profile: Option<ProfileFormat>,
+}
+
+impl Options {
+    /// parses the options from args, which should not include the name of the binary
+    pub fn parse(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.peekable();
+        let mode = match args.peek().map(String::as_str) {
+            Some("debug") => Mode::Debug,
+            Some("dap") => Mode::Dap,
+            _ => Mode::Run,
+        };
+        if matches!(
+            args.peek().map(String::as_str),
+            Some("run" | "debug" | "dap")
+        ) {
+            args.next();
+        }
+
+        let mut path = None;
+        let mut opt_level = OptLevel::default();
+        let mut enabled_passes = vec![];
+        let mut disabled_passes = vec![];
+        let mut strict = false;
+        let mut trace = None;
+        let mut profile = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--enable-pass" | "--disable-pass" => {
+                    let pass = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("missing pass name for '{}'", arg))?
+                        .parse()?;
+
+                    if arg == "--enable-pass" {
+                        enabled_passes.push(pass);
+                    } else {
+                        disabled_passes.push(pass);
+                    }
+                }
+                "--strict" => strict = true,
+                "--trace" => trace = Some(TraceFormat::default()),
+                "--profile" => profile = Some(ProfileFormat::default()),
+                _ if arg.starts_with("--trace=") => trace = Some(arg["--trace=".len()..].parse()?),
+                _ if arg.starts_with("--profile=") => {
+                    profile = Some(arg["--profile=".len()..].parse()?)
+                }
+                _ if arg.starts_with("-O") => opt_level = arg["-O".len()..].parse()?,
+                _ if arg.starts_with('-') => anyhow::bail!("unknown option '{}'", arg),
+                _ => {
+                    if path.replace(arg).is_some() {
+                        anyhow::bail!("expected a single file path");
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            mode,
+            path: match (mode, path) {
+                (Mode::Dap, None) => String::new(),
+                (Mode::Dap, Some(_)) => anyhow::bail!("dap mode takes no file path"),
+                (_, path) => path.ok_or_else(|| anyhow::anyhow!("missing file path"))?,
+            },
+            opt_level,
+            enabled_passes,
+            disabled_passes,
+            strict,
+            trace,
+            profile,
+        })
+    }
+
+    /// Fails for the modes and options which run the script as bytecode: the ast is not compiled
+    /// into a chunk yet, so they have nothing to run
+    pub fn check_supported(&self) -> anyhow::Result<()> {
+        if self.trace.is_some() {
+            anyhow::bail!("'--trace' is not supported yet: tracing needs compiled bytecode");
+        }
+        if self.profile.is_some() {
+            anyhow::bail!("'--profile' is not supported yet: profiling needs compiled bytecode");
+        }
+
+        match self.mode {
+            Mode::Run => Ok(()),
+            Mode::Debug => {
+                anyhow::bail!("'debug' is not supported yet: debugging needs compiled bytecode")
+            }
+            Mode::Dap => anyhow::bail!(
+                "'dap' is not supported yet: launch requests need scripts compiled to bytecode"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Options> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_path() {
+        let options = parse(&["script.lox"]).unwrap();
+        assert_eq!(options.mode, Mode::Run);
+        assert_eq!(options.path, "script.lox");
+        assert_eq!(options.opt_level, OptLevel::O2);
+        assert!(!options.strict);
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(parse(&["run", "script.lox"]).unwrap().mode, Mode::Run);
+
+        let options = parse(&["debug", "-O0", "script.lox"]).unwrap();
+        assert_eq!(options.mode, Mode::Debug);
+        assert_eq!(options.opt_level, OptLevel::O0);
+        assert_eq!(options.path, "script.lox");
+
+        assert!(parse(&["debug"]).is_err());
+
+        let options = parse(&["dap", "--strict"]).unwrap();
+        assert_eq!(options.mode, Mode::Dap);
+        assert!(options.strict);
+        assert!(parse(&["dap", "script.lox"]).is_err());
+    }
+
+    #[test]
+    fn unsupported_modes() {
+        assert!(parse(&["script.lox"]).unwrap().check_supported().is_ok());
+
+        let err = parse(&["debug", "script.lox"])
+            .unwrap()
+            .check_supported()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'debug' is not supported yet: debugging needs compiled bytecode"
+        );
+
+        let err = parse(&["dap"]).unwrap().check_supported().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'dap' is not supported yet: launch requests need scripts compiled to bytecode"
+        );
+    }
+
+    #[test]
+    fn parse_optimization_options() {
+        let options = parse(&[
+            "-O1",
+            "--enable-pass",
+            "dce",
+            "--disable-pass",
+            "fold",
+            "--strict",
+            "script.lox",
+        ])
+        .unwrap();
+        assert_eq!(options.opt_level, OptLevel::O1);
+        assert_eq!(options.enabled_passes, vec![Pass::DeadCodeElimination]);
+        assert_eq!(options.disabled_passes, vec![Pass::ConstantFolding]);
+        assert!(options.strict);
+        assert_eq!(options.trace, None);
+    }
+
+    #[test]
+    fn parse_trace_options() {
+        let options = parse(&["--trace", "script.lox"]).unwrap();
+        assert_eq!(options.trace, Some(TraceFormat::Text));
+
+        let options = parse(&["run", "--trace=json", "script.lox"]).unwrap();
+        assert_eq!(options.trace, Some(TraceFormat::Json));
+
+        assert!(parse(&["--trace=xml", "script.lox"]).is_err());
+
+        let err = options.check_supported().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'--trace' is not supported yet: tracing needs compiled bytecode"
+        );
+    }
+
+    #[test]
+    fn parse_profile_options() {
+        let options = parse(&["run", "--profile", "script.lox"]).unwrap();
+        assert_eq!(options.profile, Some(ProfileFormat::Report));
+
+        let options = parse(&["--profile=folded", "script.lox"]).unwrap();
+        assert_eq!(options.profile, Some(ProfileFormat::Folded));
+
+        assert!(parse(&["--profile=svg", "script.lox"]).is_err());
+
+        let err = options.check_supported().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'--profile' is not supported yet: profiling needs compiled bytecode"
+        );
+    }
+
+    #[test]
+    fn parse_invalid_options() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-O7", "script.lox"]).is_err());
+        assert!(parse(&["--enable-pass"]).is_err());
+        assert!(parse(&["--verbose", "script.lox"]).is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Context;
+use serde_json::{Value as Json, json};
+
+use super::debugger::{Debugger, StepMode};
+use crate::chunks::Chunk;
+use crate::vm::vm::{SCRIPT, VM, VMResult};
+
+/// id of the only thread of the VM
+const THREAD_ID: i64 = 1;
+
+/// variable references of the scopes of the only frame
+const STACK_REFERENCE: i64 = 1;
+const LOCALS_REFERENCE: i64 = 2;
+const GLOBALS_REFERENCE: i64 = 3;
+
+/// Reads a message of the Debug Adapter Protocol, framed by a `Content-Length` header. Returns None
+/// once input is closed
+pub fn read_message<R: BufRead>(input: &mut R) -> anyhow::Result<Option<Json>> {
+    let mut content_length = None;
+
+    // --- headers end with an empty line
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = Some(length.trim().parse::<usize>()?);
+        }
+    }
+
+    let mut content = vec![0; content_length.context("missing Content-Length header")?];
+    input.read_exact(&mut content)?;
+
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+/// Writes message framed by a `Content-Length` header
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> anyhow::Result<()> {
+    let content = message.to_string();
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Debug Adapter Protocol server, running a single program in the VM under the `Debugger`.
+/// Programs are loaded into chunks by load, given the path from the launch request
+pub struct DapServer<L> {
+    load: L,
+    debugger: Debugger,
+    vm: Option<VM>,
+    /// path of the program being debugged
+    program: String,
+    stop_on_entry: bool,
+    /// whether the program ran to its end - it is not run again until the next launch
+    terminated: bool,
+    /// sequence number of the last message sent
+    seq: i64,
+}
+
+impl<L> DapServer<L>
+where
+    L: FnMut(&str) -> anyhow::Result<Chunk>,
+{
+    pub fn new(load: L) -> Self {
+        Self {
+            load,
+            debugger: Debugger::new(),
+            vm: None,
+            program: String::new(),
+            stop_on_entry: false,
+            terminated: false,
+            seq: 0,
+        }
+    }
+
+    /// Serves requests read from input until the client disconnects or closes input
+    pub fn serve<R: BufRead, W: Write>(
+        &mut self,
+        mut input: R,
+        mut output: W,
+    ) -> anyhow::Result<()> {
+        while let Some(request) = read_message(&mut input)? {
+            if !self.handle(&request, &mut output)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// handles a single request, returning whether to keep serving
+    fn handle<W: Write>(&mut self, request: &Json, output: &mut W) -> anyhow::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+
+        match command {
+            "initialize" => {
+                let capabilities = json!({ "supportsConfigurationDoneRequest": true });
+                self.respond(output, request, Ok(capabilities))?;
+                self.event(output, "initialized", json!({}))?;
+            }
+            "launch" => {
+                let launched = self.launch(args);
+                self.respond(output, request, launched.map(|_| json!({})))?;
+            }
+            "setBreakpoints" => {
+                self.debugger.clear_breakpoints();
+                let lines = args["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .map(|line| line as usize)
+                    .collect::<Vec<_>>();
+                lines
+                    .iter()
+                    .for_each(|line| self.debugger.add_breakpoint(*line));
+
+                let breakpoints = lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect::<Vec<_>>();
+                self.respond(output, request, Ok(json!({ "breakpoints": breakpoints })))?;
+            }
+            "configurationDone" => {
+                self.respond(output, request, Ok(json!({})))?;
+                let mode = match self.stop_on_entry {
+                    true => StepMode::Into,
+                    false => StepMode::Continue,
+                };
+                self.resume(output, mode, "entry")?;
+            }
+            "threads" => {
+                let threads = json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] });
+                self.respond(output, request, Ok(threads))?;
+            }
+            "stackTrace" => {
+                let frame = json!({
+                    "id": 0,
+                    "name": SCRIPT,
+                    "line": self.debugger.line(),
+                    "column": 1,
+                    "source": { "path": self.program },
+                });
+                let body = json!({ "stackFrames": [frame], "totalFrames": 1 });
+                self.respond(output, request, Ok(body))?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    json!({
+                        "name": name,
+                        "variablesReference": reference,
+                        "expensive": false,
+                    })
+                };
+                let scopes = json!({ "scopes": [
+                    scope("Stack", STACK_REFERENCE),
+                    scope("Locals", LOCALS_REFERENCE),
+                    scope("Globals", GLOBALS_REFERENCE),
+                ]});
+                self.respond(output, request, Ok(scopes))?;
+            }
+            "variables" => {
+                // --- the VM has no local variables yet
+                let variables = match (args["variablesReference"].as_i64(), &self.vm) {
+                    (Some(STACK_REFERENCE), Some(vm)) => vm
+                        .stack()
+                        .values()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, value)| {
+                            json!({
+                                "name": format!("[{}]", i),
+                                "value": vm.heap().display(value),
+                                "type": value.value_type(),
+                                "variablesReference": 0,
+                            })
+                        })
+                        .collect(),
+                    (Some(GLOBALS_REFERENCE), Some(vm)) => vm
+                        .globals()
+                        .into_iter()
+                        .map(|(name, value)| {
+                            json!({
+                                "name": name,
+                                "value": vm.heap().display(&value),
+                                "type": value.value_type(),
+                                "variablesReference": 0,
+                            })
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                self.respond(output, request, Ok(json!({ "variables": variables })))?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" if self.terminated => {
+                let terminated = Err(anyhow::anyhow!("the program has terminated"));
+                self.respond(output, request, terminated)?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let (mode, reason) = match command {
+                    "continue" => (StepMode::Continue, "breakpoint"),
+                    "next" => (
+                        StepMode::Over {
+                            depth: self.debugger.depth(),
+                        },
+                        "step",
+                    ),
+                    "stepIn" => (StepMode::Into, "step"),
+                    _ => (
+                        StepMode::Out {
+                            depth: self.debugger.depth(),
+                        },
+                        "step",
+                    ),
+                };
+
+                let body = match command {
+                    "continue" => json!({ "allThreadsContinued": true }),
+                    _ => json!({}),
+                };
+                self.respond(output, request, Ok(body))?;
+                self.resume(output, mode, reason)?;
+            }
+            "disconnect" => {
+                self.respond(output, request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => {
+                let unsupported = Err(anyhow::anyhow!("unsupported request '{}'", command));
+                self.respond(output, request, unsupported)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> anyhow::Result<()> {
+        let program = args["program"]
+            .as_str()
+            .context("missing 'program' in launch arguments")?;
+        let chunk = (self.load)(program)?;
+
+        self.program = program.to_string();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm = Some(VM::new(chunk));
+        self.terminated = false;
+        Ok(())
+    }
+
+    /// resumes the program until it pauses, reporting the pause with reason unless it is at a
+    /// breakpoint, or until it ends
+    fn resume<W: Write>(
+        &mut self,
+        output: &mut W,
+        mode: StepMode,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        let Some(vm) = self.vm.as_mut() else {
+            return self.output(output, "stderr", "no program was launched");
+        };
+
+        self.debugger.set_mode(mode);
+        let result = match vm.is_paused() {
+            true => vm.resume(&mut self.debugger),
+            false => vm.run_with_hook(&mut self.debugger),
+        };
+
+        match &result {
+            VMResult::Paused => {
+                let reason = match self.debugger.has_breakpoint(self.debugger.line()) {
+                    true => "breakpoint",
+                    false => reason,
+                };
+                let body = json!({ "reason": reason, "threadId": THREAD_ID });
+                return self.event(output, "stopped", body);
+            }
+            // --- the run can be resumed like a paused one
+            VMResult::OutOfFuel | VMResult::Interrupted => {
+                let body = json!({ "reason": "pause", "threadId": THREAD_ID });
+                return self.event(output, "stopped", body);
+            }
+            VMResult::Ok | VMResult::CompileError => {}
+            VMResult::RuntimeError(e) => self.output(output, "stderr", &format!("{}\n", e))?,
+            VMResult::InvalidBytecode(e) => {
+                self.output(output, "stderr", &format!("invalid bytecode: {}\n", e))?
+            }
+        }
+
+        let exit_code = match result {
+            VMResult::Ok => 0,
+            _ => 1,
+        };
+        self.terminated = true;
+        self.event(output, "exited", json!({ "exitCode": exit_code }))?;
+        self.event(output, "terminated", json!({}))
+    }
+
+    fn respond<W: Write>(
+        &mut self,
+        output: &mut W,
+        request: &Json,
+        body: anyhow::Result<Json>,
+    ) -> anyhow::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+
+        match body {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(e) => {
+                response["success"] = json!(false);
+                response["message"] = json!(e.to_string());
+            }
+        }
+
+        write_message(output, &response)
+    }
+
+    fn event<W: Write>(&mut self, output: &mut W, event: &str, body: Json) -> anyhow::Result<()> {
+        self.seq += 1;
+        let event = json!({ "seq": self.seq, "type": "event", "event": event, "body": body });
+        write_message(output, &event)
+    }
+
+    fn output<W: Write>(
+        &mut self,
+        output: &mut W,
+        category: &str,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        self.event(
+            output,
+            "output",
+            json!({ "category": category, "output": text }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::{opcodes::OpCode, value::Value};
+    use crate::debugger::tests::chunk;
+
+    /// serves requests, given as (command, arguments), and returns the messages sent back
+    fn serve(
+        load: impl FnMut(&str) -> anyhow::Result<Chunk>,
+        requests: &[(&str, Json)],
+    ) -> Vec<Json> {
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let request = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let mut output = vec![];
+        DapServer::new(load)
+            .serve(input.as_slice(), &mut output)
+            .unwrap();
+
+        let mut output = output.as_slice();
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    /// short description of each message, as `<command>` for responses and `!<event>` for events
+    fn summary(messages: &[Json]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| match message["type"].as_str() {
+                Some("event") => format!("!{}", message["event"].as_str().unwrap()),
+                _ => message["command"].as_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    fn launch(stop_on_entry: bool) -> Vec<(&'static str, Json)> {
+        vec![
+            ("initialize", json!({ "adapterID": "rox" })),
+            (
+                "launch",
+                json!({ "program": "script.lox", "stopOnEntry": stop_on_entry }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn message_framing() {
+        let mut output = vec![];
+        write_message(&mut output, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "Content-Length: 9\r\n\r\n{\"seq\":1}"
+        );
+
+        let mut input = output.as_slice();
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn run_to_completion() {
+        let mut requests = launch(false);
+        requests.push(("configurationDone", json!({})));
+        requests.push(("disconnect", json!({})));
+
+        let messages = serve(|_| Ok(chunk()), &requests);
+        assert_eq!(
+            summary(&messages),
+            vec![
+                "initialize",
+                "!initialized",
+                "launch",
+                "configurationDone",
+                "!exited",
+                "!terminated",
+                "disconnect"
+            ]
+        );
+        assert!(messages.iter().all(|message| message["success"] != false));
+        assert_eq!(messages[4]["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn resume_after_termination() {
+        let mut requests = launch(false);
+        requests.push(("configurationDone", json!({})));
+        requests.push(("continue", json!({ "threadId": 1 })));
+        requests.push(("next", json!({ "threadId": 1 })));
+        requests.push(("disconnect", json!({})));
+
+        // --- the program is not run a second time
+        let messages = serve(|_| Ok(chunk()), &requests);
+        assert_eq!(
+            summary(&messages),
+            vec![
+                "initialize",
+                "!initialized",
+                "launch",
+                "configurationDone",
+                "!exited",
+                "!terminated",
+                "continue",
+                "next",
+                "disconnect"
+            ]
+        );
+        for response in &messages[6..8] {
+            assert_eq!(response["success"], false);
+            assert_eq!(response["message"], "the program has terminated");
+        }
+    }
+
+    #[test]
+    fn stop_at_breakpoint() {
+        let mut requests = launch(false);
+        requests.push((
+            "setBreakpoints",
+            json!({ "source": { "path": "script.lox" }, "breakpoints": [{ "line": 4 }] }),
+        ));
+        requests.push(("configurationDone", json!({})));
+        requests.push(("stackTrace", json!({ "threadId": THREAD_ID })));
+        requests.push(("scopes", json!({ "frameId": 0 })));
+        requests.push((
+            "variables",
+            json!({ "variablesReference": STACK_REFERENCE }),
+        ));
+        requests.push((
+            "variables",
+            json!({ "variablesReference": GLOBALS_REFERENCE }),
+        ));
+        requests.push(("continue", json!({ "threadId": THREAD_ID })));
+
+        let messages = serve(|_| Ok(chunk()), &requests);
+        assert_eq!(
+            summary(&messages),
+            vec![
+                "initialize",
+                "!initialized",
+                "launch",
+                "setBreakpoints",
+                "configurationDone",
+                "!stopped",
+                "stackTrace",
+                "scopes",
+                "variables",
+                "variables",
+                "continue",
+                "!exited",
+                "!terminated"
+            ]
+        );
+        assert_eq!(messages[3]["body"]["breakpoints"][0]["line"], 4);
+        assert_eq!(messages[5]["body"]["reason"], "breakpoint");
+
+        let frame = &messages[6]["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 4);
+        assert_eq!(frame["source"]["path"], "script.lox");
+
+        assert_eq!(messages[7]["body"]["scopes"].as_array().unwrap().len(), 3);
+        assert_eq!(messages[8]["body"]["variables"][0]["value"], "-3");
+
+        let globals = messages[9]["body"]["variables"].as_array().unwrap();
+        assert!(
+            globals
+                .iter()
+                .any(|global| global["name"] == "clock" && global["value"] == "<native fn clock>")
+        );
+    }
+
+    #[test]
+    fn step_from_entry() {
+        let mut requests = launch(true);
+        requests.push(("configurationDone", json!({})));
+        requests.push(("next", json!({ "threadId": THREAD_ID })));
+        requests.push(("stackTrace", json!({ "threadId": THREAD_ID })));
+        requests.push(("stepIn", json!({ "threadId": THREAD_ID })));
+        requests.push(("stackTrace", json!({ "threadId": THREAD_ID })));
+
+        let messages = serve(|_| Ok(chunk()), &requests);
+        let stopped = messages
+            .iter()
+            .filter(|message| message["event"] == "stopped")
+            .map(|message| message["body"]["reason"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(stopped, vec!["entry", "step", "step"]);
+
+        let lines = messages
+            .iter()
+            .filter(|message| message["command"] == "stackTrace")
+            .map(|message| message["body"]["stackFrames"][0]["line"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 4]);
+    }
+
+    #[test]
+    fn runtime_error_output() {
+        let mut chunk = chunk();
+        chunk.write_constant(Value::Literal("Hello, world!"));
+        chunk.write(OpCode::Add);
+
+        let mut requests = launch(false);
+        requests.push(("configurationDone", json!({})));
+
+        let messages = serve(move |_| Ok(chunk.clone()), &requests);
+        let output = messages
+            .iter()
+            .find(|message| message["event"] == "output")
+            .unwrap();
+        assert_eq!(output["body"]["category"], "stderr");
+        assert!(
+            output["body"]["output"]
+                .as_str()
+                .unwrap()
+                .contains("[line 4] in script")
+        );
+
+        let exited = messages
+            .iter()
+            .find(|message| message["event"] == "exited")
+            .unwrap();
+        assert_eq!(exited["body"]["exitCode"], 1);
+    }
+
+    #[test]
+    fn failed_requests() {
+        let requests = vec![
+            ("launch", json!({ "program": "missing.lox" })),
+            ("evaluate", json!({ "expression": "1 + 2" })),
+        ];
+
+        let messages = serve(|path| anyhow::bail!("cannot read '{}'", path), &requests);
+        assert_eq!(messages[0]["success"], false);
+        assert_eq!(messages[0]["message"], "cannot read 'missing.lox'");
+        assert_eq!(messages[1]["success"], false);
+        assert_eq!(messages[1]["message"], "unsupported request 'evaluate'");
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+
+use crate::vm::vm::{Flow, Hook, VM, VMResult, VMState};
+
+pub const HELP: &str = "Commands:
+  break <line>   (b)  pause when reaching line
+  delete <line>  (d)  remove the breakpoint at line
+  step           (s)  run until the next line, stepping into calls
+  next           (n)  run until the next line, stepping over calls
+  finish         (f)  run until the current function returns
+  continue       (c)  run until the next breakpoint
+  stack               print the values in the stack
+  locals              print the local variables
+  globals             print the global variables
+  help           (h)  print this message
+  quit           (q)  stop debugging";
+
+/// Commands accepted by the debugger while paused
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    Break(usize),
+    Delete(usize),
+    Step,
+    Next,
+    Finish,
+    Continue,
+    Stack,
+    Locals,
+    Globals,
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let mut line = || -> anyhow::Result<usize> {
+            let line = words
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("missing line for '{}'", command))?;
+            line.parse()
+                .map_err(|_| anyhow::anyhow!("invalid line '{}'", line))
+        };
+
+        let command = match command {
+            "break" | "b" => Command::Break(line()?),
+            "delete" | "d" => Command::Delete(line()?),
+            "step" | "s" => Command::Step,
+            "next" | "n" => Command::Next,
+            "finish" | "f" => Command::Finish,
+            "continue" | "c" => Command::Continue,
+            "stack" => Command::Stack,
+            "locals" => Command::Locals,
+            "globals" => Command::Globals,
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => anyhow::bail!(
+                "unknown command '{}', type 'help' for a list of commands",
+                s
+            ),
+        };
+
+        Ok(command)
+    }
+}
+
+/// When the debugger pauses the VM, besides breakpoints
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StepMode {
+    /// only at breakpoints
+    Continue,
+    /// at the next line, in any frame
+    Into,
+    /// at the next line in the frame at depth or in the frames calling it
+    Over { depth: usize },
+    /// at the next line once the frame at depth returned
+    Out { depth: usize },
+}
+
+/// Step debugger, attached to the VM as a hook. Pauses are decided per line: the debugger only
+/// pauses at the first instruction of a line, or of a frame
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    mode: StepMode,
+    /// line of the last instruction seen
+    line: usize,
+    /// frame depth of the last instruction seen
+    depth: usize,
+}
+
+impl Debugger {
+    /// creates a debugger that pauses at the first line
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            mode: StepMode::Into,
+            line: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn has_breakpoint(&self, line: usize) -> bool {
+        self.breakpoints.contains(&line)
+    }
+
+    /// sets when to pause next, relative to the last instruction seen
+    pub fn set_mode(&mut self, mode: StepMode) {
+        self.mode = mode;
+    }
+
+    /// line of the last instruction seen, which is the one paused at while the VM is paused
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// frame depth of the last instruction seen
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Runs vm, reading commands from input whenever it pauses. Returns the result of the run,
+    /// which is still `VMResult::Paused` if the session was quit before the run finished
+    pub fn debug<R: BufRead, W: Write>(
+        &mut self,
+        vm: &mut VM,
+        input: R,
+        mut output: W,
+    ) -> anyhow::Result<VMResult> {
+        let mut commands = input.lines();
+        let mut result = vm.run_with_hook(self);
+
+        while result == VMResult::Paused {
+            writeln!(output, "paused at line {}", self.line)?;
+
+            // --- read commands until one of them resumes the run
+            let mode = loop {
+                write!(output, "(rox) ")?;
+                output.flush()?;
+
+                let Some(command) = commands.next().transpose()? else {
+                    return Ok(result);
+                };
+                if command.trim().is_empty() {
+                    continue;
+                }
+
+                match command.parse::<Command>() {
+                    Ok(Command::Break(line)) => {
+                        self.add_breakpoint(line);
+                        writeln!(output, "breakpoint at line {}", line)?;
+                    }
+                    Ok(Command::Delete(line)) => match self.remove_breakpoint(line) {
+                        true => writeln!(output, "deleted breakpoint at line {}", line)?,
+                        false => writeln!(output, "no breakpoint at line {}", line)?,
+                    },
+                    Ok(Command::Step) => break StepMode::Into,
+                    Ok(Command::Next) => break StepMode::Over { depth: self.depth },
+                    Ok(Command::Finish) => break StepMode::Out { depth: self.depth },
+                    Ok(Command::Continue) => break StepMode::Continue,
+                    Ok(Command::Stack) => {
+                        let values = vm.stack().values().iter();
+                        let values = values.map(|value| vm.heap().display(value)).join(", ");
+                        writeln!(output, "[{}]", values)?
+                    }
+                    // --- the VM has no local variables yet
+                    Ok(Command::Locals) => writeln!(output, "no locals")?,
+                    Ok(Command::Globals) => {
+                        for (name, value) in vm.globals() {
+                            writeln!(output, "{} = {}", name, vm.heap().display(&value))?;
+                        }
+                    }
+                    Ok(Command::Help) => writeln!(output, "{}", HELP)?,
+                    Ok(Command::Quit) => return Ok(result),
+                    Err(e) => writeln!(output, "{}", e)?,
+                }
+            };
+
+            self.mode = mode;
+            result = vm.resume(self);
+        }
+
+        Ok(result)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hook for Debugger {
+    fn on_instruction(&mut self, state: &VMState) -> Flow {
+        let line = state.line();
+        let new_line = line != self.line || state.depth != self.depth;
+        self.line = line;
+        self.depth = state.depth;
+
+        if !new_line {
+            return Flow::Continue;
+        }
+
+        let step = match self.mode {
+            StepMode::Continue => false,
+            StepMode::Into => true,
+            StepMode::Over { depth } => state.depth <= depth,
+            StepMode::Out { depth } => state.depth < depth,
+        };
+
+        match step || self.breakpoints.contains(&line) {
+            true => Flow::Pause,
+            false => Flow::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::chunk;
+
+    fn debug(commands: &str) -> (VMResult, String) {
+        let mut vm = VM::new(chunk());
+        let mut output = vec![];
+        let result = Debugger::new()
+            .debug(&mut vm, commands.as_bytes(), &mut output)
+            .unwrap();
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    fn paused_lines(output: &str) -> Vec<&str> {
+        output
+            .lines()
+            .filter_map(|line| line.split("paused at line ").nth(1))
+            .collect()
+    }
+
+    #[test]
+    fn parse_command() {
+        assert_eq!("break 3".parse::<Command>().unwrap(), Command::Break(3));
+        assert_eq!("d 3".parse::<Command>().unwrap(), Command::Delete(3));
+        assert_eq!("  n ".parse::<Command>().unwrap(), Command::Next);
+        assert!("break".parse::<Command>().is_err());
+        assert!("break x".parse::<Command>().is_err());
+        assert!("jump 3".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn step_through_lines() {
+        let (result, output) = debug("step\nstep\nstep\n");
+        assert_eq!(result, VMResult::Ok);
+        assert_eq!(paused_lines(&output), vec!["1", "2", "4"]);
+    }
+
+    #[test]
+    fn continue_to_breakpoint() {
+        let (result, output) = debug("break 4\ncontinue\nstack\ncontinue\n");
+        assert_eq!(result, VMResult::Ok);
+        assert_eq!(paused_lines(&output), vec!["1", "4"]);
+        assert!(output.contains("[-3]"));
+    }
+
+    #[test]
+    fn delete_breakpoint() {
+        let (result, output) = debug("b 2\nb 4\nd 2\nc\nc\n");
+        assert_eq!(result, VMResult::Ok);
+        assert_eq!(paused_lines(&output), vec!["1", "4"]);
+    }
+
+    #[test]
+    fn finish_script() {
+        let (result, output) = debug("finish\n");
+        assert_eq!(result, VMResult::Ok);
+        assert_eq!(paused_lines(&output), vec!["1"]);
+    }
+
+    #[test]
+    fn quit() {
+        let (result, output) = debug("next\nquit\n");
+        assert_eq!(result, VMResult::Paused);
+        assert_eq!(paused_lines(&output), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn print_globals() {
+        let (_, output) = debug("globals\nc\n");
+        assert!(output.contains("clock = <native fn clock>\n"));
+        assert!(output.contains("sqrt = <native fn sqrt>\n"));
+    }
+
+    #[test]
+    fn invalid_command() {
+        let (result, output) = debug("jump\nc\n");
+        assert_eq!(result, VMResult::Ok);
+        assert!(output.contains("unknown command 'jump'"));
+    }
+}
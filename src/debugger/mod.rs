@@ -0,0 +1,24 @@
+pub mod dap;
+pub mod debugger;
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use crate::chunks::{Chunk, opcodes::OpCode, value::Value};
+
+    /// chunk computing `1 + 2` in line 1, negating it in line 2 and adding 3 in line 4
+    pub(super) fn chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.set_line(1);
+        chunk.write_constant(Value::Number(OrderedFloat(1.0)));
+        chunk.write_constant(Value::Number(OrderedFloat(2.0)));
+        chunk.write(OpCode::Add);
+        chunk.set_line(2);
+        chunk.write(OpCode::Negate);
+        chunk.set_line(4);
+        chunk.write_constant(Value::Number(OrderedFloat(3.0)));
+        chunk.write(OpCode::Add);
+        chunk
+    }
+}
@@ -2,20 +2,172 @@ use std::fmt::Display;
 
 use crate::scanner::token::Token;
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
 #[derive(Debug, Clone)]
 pub struct RoxError<'a> {
     pub token: Token<'a>,
     pub msg: String,
+    pub severity: Severity,
 }
 
 impl<'a> RoxError<'a> {
     pub fn new(token: Token<'a>, msg: String) -> Self {
-        Self { token, msg }
+        Self {
+            token,
+            msg,
+            severity: Severity::Error,
+        }
+    }
+
+    pub fn warning(token: Token<'a>, msg: String) -> Self {
+        Self {
+            token,
+            msg,
+            severity: Severity::Warning,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let display_data = match self {
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        };
+        write!(f, "{}", display_data)
     }
 }
 
 impl<'a> Display for RoxError<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[ERROR]: at line {}: {}", self.token.line, self.msg)
+        write!(
+            f,
+            "[{}]: at line {}: {}",
+            self.severity, self.token.line, self.msg
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// operands of an operation are of the wrong type
+    Type,
+    DivisionByZero,
+    StackOverflow,
+    /// an operation popped more values than the stack held
+    StackUnderflow,
+    /// the heap is over the limit of the VM, even after a collection
+    OutOfMemory,
+    /// a function was called with the wrong number of arguments
+    Arity,
+    /// an argument has the right type but an invalid value
+    InvalidArgument,
+    /// a list was indexed out of its bounds
+    Index,
+    /// a map was indexed with a key it does not hold
+    Key,
+}
+
+impl Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let display_data = match self {
+            RuntimeErrorKind::Type => "type error",
+            RuntimeErrorKind::DivisionByZero => "division by zero",
+            RuntimeErrorKind::StackOverflow => "stack overflow",
+            RuntimeErrorKind::StackUnderflow => "stack underflow",
+            RuntimeErrorKind::OutOfMemory => "out of memory",
+            RuntimeErrorKind::Arity => "wrong number of arguments",
+            RuntimeErrorKind::InvalidArgument => "invalid argument",
+            RuntimeErrorKind::Index => "index out of bounds",
+            RuntimeErrorKind::Key => "key not found",
+        };
+        write!(f, "{}", display_data)
+    }
+}
+
+/// Call frame active when a runtime error was raised
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Frame {
+    /// name of the function being run, `script` for the top-level code
+    pub function: String,
+    /// line of the instruction being run in the frame
+    pub line: usize,
+}
+
+/// Error raised while running a chunk. Errors are created without a location by the operations
+/// that fail, and the VM attaches the line and frames of the failing instruction to them
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub msg: String,
+    /// line of the instruction that failed
+    pub line: usize,
+    /// frames active when the error was raised, innermost first. Until the VM runs functions in
+    /// call frames of their own, this is a single `script` frame
+    pub frames: Vec<Frame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, msg: String) -> Self {
+        Self {
+            kind,
+            msg,
+            line: 0,
+            frames: vec![],
+        }
+    }
+
+    /// sets the location of the error, with frames ordered innermost first
+    pub fn at(mut self, frames: Vec<Frame>) -> Self {
+        self.line = frames.first().map_or(0, |frame| frame.line);
+        self.frames = frames;
+        self
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[RUNTIME ERROR]: at line {}: {}", self.line, self.msg)?;
+        for frame in &self.frames {
+            write!(f, "\n\t[line {}] in {}", frame.line, frame.function)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_runtime_error() {
+        let err =
+            RuntimeError::new(RuntimeErrorKind::StackOverflow, "Stack overflow".into()).at(vec![
+                Frame {
+                    function: "fib".into(),
+                    line: 3,
+                },
+                Frame {
+                    function: "script".into(),
+                    line: 7,
+                },
+            ]);
+
+        assert_eq!(err.line, 3);
+        assert_eq!(
+            err.to_string(),
+            "[RUNTIME ERROR]: at line 3: Stack overflow\n\t[line 3] in fib\n\t[line 7] in script"
+        );
     }
 }
@@ -1,8 +1,9 @@
-mod bitwise;
-mod chunks;
-mod compiler;
-mod errors;
-mod optimizer;
-mod parser;
-mod scanner;
-mod vm;
+pub mod bitwise;
+pub mod chunks;
+pub mod compiler;
+pub mod debugger;
+pub mod errors;
+pub mod optimizer;
+pub mod parser;
+pub mod scanner;
+pub mod vm;
@@ -1,11 +1,15 @@
 use std::{env, fs::read_to_string, io::Write};
 
+use cli::{Options, USAGE};
+use optimizer::optimizer::Optimizer;
 use parser::parser::Parser;
 use scanner::scanner::Scanner;
 
 mod bitwise;
 mod chunks;
+mod cli;
 mod compiler;
+mod debugger;
 mod errors;
 mod optimizer;
 mod parser;
@@ -19,13 +23,13 @@ fn init_logger() {
         .try_init();
 }
 
-fn run_file(path: &str) -> anyhow::Result<()> {
-    let src = read_to_string(path)?;
-    interpret(&src)?;
+fn run_file(options: &Options) -> anyhow::Result<()> {
+    let src = read_to_string(&options.path)?;
+    interpret(&src, options)?;
     Ok(())
 }
 
-fn interpret(src: &str) -> anyhow::Result<()> {
+fn interpret(src: &str, options: &Options) -> anyhow::Result<()> {
     let mut scanner = Scanner::new(src);
     let tokens = scanner.scan()?;
 
@@ -36,14 +40,32 @@ fn interpret(src: &str) -> anyhow::Result<()> {
         anyhow::bail!("failure during parsing");
     }
 
+    let mut optimizer = Optimizer::new(options.opt_level, options.strict);
+    options
+        .enabled_passes
+        .iter()
+        .for_each(|pass| optimizer.enable(*pass));
+    options
+        .disabled_passes
+        .iter()
+        .for_each(|pass| optimizer.disable(*pass));
+    let _ast = optimizer.optimize(ast);
+    optimizer.log_diagnostics();
+    if optimizer.has_errors() {
+        anyhow::bail!("failure during optimization");
+    }
+
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     init_logger();
 
-    match env::args().nth(1) {
-        Some(path) => run_file(&path),
-        None => anyhow::bail!("Usage: rox <file_path>"),
+    match Options::parse(env::args().skip(1)) {
+        Ok(options) => {
+            options.check_supported()?;
+            run_file(&options)
+        }
+        Err(e) => anyhow::bail!("{}\n{}", e, USAGE),
     }
 }
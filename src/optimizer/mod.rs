@@ -1 +1,3 @@
 pub mod optimizer;
+pub mod passes;
+pub mod peephole;
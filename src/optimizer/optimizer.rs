@@ -1,31 +1,117 @@
-use crate::parser::{ast::AstNode, statements::Stmt};
+use std::time::Instant;
 
-pub struct Optimizer;
+use crate::{
+    errors::{RoxError, Severity},
+    parser::{ast::AstNode, statements::Stmt},
+};
 
-impl Optimizer {
-    pub fn optimize(ast: Vec<Stmt>) -> Vec<Stmt> {
-        let initial_node_count = Optimizer::count_nodes(&ast);
-        println!("Optimization started at {} nodes", initial_node_count);
+use super::passes::{OptLevel, Pass, PassContext, PassStats};
 
-        let mut optimized_stmts = vec![];
-        for stmt in ast {
-            optimized_stmts.push(stmt.optimize());
+pub struct Optimizer<'a> {
+    /// passes to be run, as enabled by the optimization level or individually
+    passes: Vec<Pass>,
+    /// warnings and errors detected while optimizing
+    diagnostics: Vec<RoxError<'a>>,
+    /// if set, operations that are guaranteed to fail at runtime are reported as errors instead
+    /// of warnings
+    strict: bool,
+    /// statistics of each pass run by the last optimization
+    stats: Vec<PassStats>,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn new(level: OptLevel, strict: bool) -> Self {
+        Self {
+            passes: level.passes(),
+            diagnostics: vec![],
+            strict,
+            stats: vec![],
+        }
+    }
+
+    pub fn enable(&mut self, pass: Pass) {
+        if !self.passes.contains(&pass) {
+            self.passes.push(pass);
+        }
+    }
+
+    pub fn disable(&mut self, pass: Pass) {
+        self.passes.retain(|p| *p != pass);
+    }
+
+    pub fn optimize(&mut self, mut ast: Vec<Stmt<'a>>) -> Vec<Stmt<'a>> {
+        self.stats.clear();
+
+        for pass in Pass::ALL {
+            if !self.passes.contains(&pass) {
+                continue;
+            }
+
+            let nodes_before = Optimizer::count_nodes(&ast);
+            let start = Instant::now();
+
+            let mut ctx = PassContext::new(pass);
+            ast = Stmt::optimize_block(&ast, &mut ctx);
+
+            let stats = PassStats {
+                pass,
+                nodes_before,
+                nodes_after: Optimizer::count_nodes(&ast),
+                diagnostics: ctx.diagnostics.len(),
+                elapsed: start.elapsed(),
+            };
+            log::info!("{}", stats);
+            self.stats.push(stats);
+
+            // --- nodes report every issue as a warning, escalate them if running in strict mode
+            if self.strict {
+                ctx.diagnostics
+                    .iter_mut()
+                    .for_each(|diagnostic| diagnostic.severity = Severity::Error);
+            }
+            self.diagnostics.extend(ctx.diagnostics);
         }
 
-        let final_node_count = Optimizer::count_nodes(&optimized_stmts);
-        println!("Optimization ended at {} nodes", final_node_count);
-        optimized_stmts
+        ast
     }
 
     pub fn count_nodes(ast: &Vec<Stmt>) -> usize {
         ast.iter().map(|m| m.count_nodes()).sum()
     }
+
+    /// number of nodes removed from the ast by the last optimization
+    pub fn removed_nodes(&self) -> usize {
+        self.stats.iter().map(PassStats::removed_nodes).sum()
+    }
+
+    pub fn stats(&self) -> &[PassStats] {
+        &self.stats
+    }
+
+    pub fn diagnostics(&self) -> &[RoxError<'a>] {
+        &self.diagnostics
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(RoxError::is_error)
+    }
+
+    pub fn log_diagnostics(&self) {
+        for diagnostic in self.diagnostics.iter() {
+            eprintln!("{}", diagnostic);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        parser::{parser::Parser, statements::Stmt},
+        optimizer::passes::{OptLevel, Pass},
+        parser::{
+            expressions::{Expr, Value},
+            parser::Parser,
+            statements::Stmt,
+        },
         scanner::scanner::Scanner,
     };
 
@@ -102,7 +188,7 @@ mod tests {
     fn optimize_1() {
         let ast = scan_and_parse("var myVar = 42 + 1 * 1 + 4 * 2;");
         ast.iter().for_each(|stmt| println!("Before: {}", stmt));
-        let optimized = Optimizer::optimize(ast);
+        let optimized = Optimizer::new(OptLevel::O2, false).optimize(ast);
         assert_eq!(Optimizer::count_nodes(&optimized), 1);
     }
 
@@ -110,7 +196,7 @@ mod tests {
     fn optimize_2() {
         let ast = scan_and_parse("var myVar = 42 + 4 * 2 - 40 / (2 * 5);");
         ast.iter().for_each(|stmt| println!("Before: {}", stmt));
-        let optimized = Optimizer::optimize(ast);
+        let optimized = Optimizer::new(OptLevel::O2, false).optimize(ast);
         assert_eq!(Optimizer::count_nodes(&optimized), 1);
     }
 
@@ -120,9 +206,395 @@ mod tests {
             "obj.myFunc(2 * 5, 42 + 3 * 6, test.hello.method((10 + 3) * 10 + 20 / 2));",
         );
         ast.iter().for_each(|stmt| println!("Before: {}", stmt));
-        let optimized = Optimizer::optimize(ast);
+        let optimized = Optimizer::new(OptLevel::O2, false).optimize(ast);
         optimized
             .iter()
             .for_each(|stmt| println!("After: {}", stmt));
     }
+
+    #[test]
+    fn fold_division_by_zero() {
+        let ast = scan_and_parse("var myVar = 1 / 0;");
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        // --- the division is kept so that it still fails at runtime
+        assert_eq!(Optimizer::count_nodes(&optimized), 3);
+        assert_eq!(optimizer.diagnostics().len(), 1);
+        assert_eq!(optimizer.diagnostics()[0].token.line, 1);
+        assert!(!optimizer.has_errors());
+    }
+
+    #[test]
+    fn fold_invalid_operands() {
+        let ast = scan_and_parse(
+            "false - 1;
+            true + 1;
+            1 + 2;",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(Optimizer::count_nodes(&optimized), 7);
+        let lines = optimizer
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.token.line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn fold_nested_invalid_operands() {
+        let ast = scan_and_parse("var myVar = (2 * 3) - (true + 1);");
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        // --- valid subtrees are still folded
+        assert_eq!(Optimizer::count_nodes(&optimized), 6);
+        assert_eq!(optimizer.diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn fold_errors_in_strict_mode() {
+        let ast = scan_and_parse("1 / 0;");
+        let mut optimizer = Optimizer::new(OptLevel::O2, true);
+        optimizer.optimize(ast);
+
+        assert!(optimizer.has_errors());
+    }
+
+    #[test]
+    fn fold_equality_of_different_types() {
+        let ast = scan_and_parse("var myVar = 1 == true;");
+        let mut optimizer = Optimizer::new(OptLevel::O2, true);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(Optimizer::count_nodes(&optimized), 1);
+        assert!(optimizer.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn dce_after_return() {
+        let ast = scan_and_parse(
+            "fun myFunc(a) {
+                return a + 1;
+                a = 2;
+                var myVar = a * 3;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(Optimizer::count_nodes(&optimized), 3);
+        assert_eq!(optimizer.removed_nodes(), 5);
+    }
+
+    #[test]
+    fn dce_if_true() {
+        let ast = scan_and_parse(
+            "if (true) {
+                a + 1;
+            } else {
+                b;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(optimized.len(), 1);
+        assert!(matches!(optimized[0], Stmt::Expression(_)));
+        assert_eq!(optimizer.removed_nodes(), 2);
+    }
+
+    #[test]
+    fn dce_if_false() {
+        let ast = scan_and_parse(
+            "if (1 > 2) {
+                a;
+            }
+            b;",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(optimized.len(), 1);
+        assert_eq!(Optimizer::count_nodes(&optimized), 1);
+        assert_eq!(optimizer.removed_nodes(), 4);
+    }
+
+    #[test]
+    fn dce_if_with_declarations() {
+        let ast = scan_and_parse(
+            "if (false) {
+                a;
+            } else {
+                var myVar = 42;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        // --- the declaration is kept in its own scope
+        assert_eq!(optimized.len(), 1);
+        match &optimized[0] {
+            Stmt::If(payload) => {
+                assert_eq!(payload.if_body.len(), 1);
+                assert!(payload.else_body.is_empty());
+            }
+            _ => panic!("Should be an if statement"),
+        }
+    }
+
+    #[test]
+    fn dce_while_false() {
+        let ast = scan_and_parse(
+            "while (false) {
+                a = a + 1;
+            }
+            while (true) {
+                b;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(optimized.len(), 1);
+        assert!(matches!(optimized[0], Stmt::While(_)));
+        assert_eq!(optimizer.removed_nodes(), 5);
+    }
+
+    #[test]
+    fn dce_return_in_taken_branch() {
+        let ast = scan_and_parse(
+            "fun myFunc(a) {
+                if (true) {
+                    return a;
+                }
+                a = a + 1;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(Optimizer::count_nodes(&optimized), 1);
+    }
+
+    #[test]
+    fn opt_level_0() {
+        let ast = scan_and_parse(
+            "var myVar = 1 + 2;
+            if (false) {
+                myVar;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O0, false);
+        let optimized = optimizer.optimize(ast);
+
+        assert_eq!(Optimizer::count_nodes(&optimized), 5);
+        assert!(optimizer.stats().is_empty());
+    }
+
+    #[test]
+    fn disabled_folding() {
+        let ast = scan_and_parse(
+            "if (1 > 2) {
+                a;
+            }
+            if (false) {
+                b;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        optimizer.disable(Pass::ConstantFolding);
+        optimizer.disable(Pass::AlgebraicSimplification);
+        let optimized = optimizer.optimize(ast);
+
+        // --- without folding, only the branch with a literal condition can be eliminated
+        assert_eq!(optimized.len(), 1);
+        assert_eq!(optimizer.stats().len(), 1);
+        assert_eq!(optimizer.stats()[0].pass, Pass::DeadCodeElimination);
+    }
+
+    #[test]
+    fn enabled_pass() {
+        let ast = scan_and_parse(
+            "if (false) {
+                b;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O1, false);
+        optimizer.enable(Pass::DeadCodeElimination);
+        let optimized = optimizer.optimize(ast);
+
+        assert!(optimized.is_empty());
+    }
+
+    #[test]
+    fn pass_stats() {
+        let ast = scan_and_parse(
+            "var myVar = 2 * 3;
+            if (myVar > 10 * 10) {
+                myVar;
+            }
+            while (1 > 2) {
+                myVar = myVar + 1;
+            }",
+        );
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        optimizer.optimize(ast);
+
+        let stats = optimizer.stats();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].pass, Pass::ConstantFolding);
+        assert_eq!(stats[0].removed_nodes(), 6);
+        assert_eq!(stats[1].pass, Pass::AlgebraicSimplification);
+        assert_eq!(stats[1].removed_nodes(), 0);
+        assert_eq!(stats[2].pass, Pass::DeadCodeElimination);
+        assert_eq!(stats[2].removed_nodes(), 5);
+        assert_eq!(optimizer.removed_nodes(), 11);
+    }
+
+    fn simplify(src: &str) -> usize {
+        let ast = scan_and_parse(src);
+        let mut optimizer = Optimizer::new(OptLevel::O2, false);
+        let optimized = optimizer.optimize(ast);
+        Optimizer::count_nodes(&optimized)
+    }
+
+    #[test]
+    fn simplify_identities() {
+        assert_eq!(simplify("var myVar = (a - b) * 1;"), 3);
+        assert_eq!(simplify("var myVar = 1 * (a * b);"), 3);
+        assert_eq!(simplify("var myVar = -a - 0;"), 2);
+        assert_eq!(simplify("var myVar = (a / b) - 0;"), 3);
+        assert_eq!(simplify("var myVar = (a - b) / 1;"), 3);
+    }
+
+    #[test]
+    fn simplify_keeps_unknown_operands() {
+        // --- a may not be a number, in which case the operation must still fail at runtime
+        assert_eq!(simplify("var myVar = a * 1;"), 3);
+        assert_eq!(simplify("var myVar = a - 0;"), 3);
+        assert_eq!(simplify("var myVar = (a + b) - 0;"), 5);
+        assert_eq!(simplify("var myVar = --a;"), 3);
+    }
+
+    #[test]
+    fn simplify_keeps_additive_zeros() {
+        // --- -0 + 0 is 0, while -0 is observable through `1 / x`
+        assert_eq!(simplify("var myVar = -a + 0;"), 4);
+        assert_eq!(simplify("var myVar = 0 + -a;"), 4);
+    }
+
+    #[test]
+    fn simplify_negations() {
+        assert_eq!(simplify("var myVar = --(a * b);"), 3);
+        assert_eq!(simplify("var myVar = (a - b) * -1;"), 4);
+        assert_eq!(simplify("var myVar = !!!a;"), 2);
+        // --- outside a boolean context, !! converts its operand into a bool
+        assert_eq!(simplify("var myVar = !!a;"), 3);
+    }
+
+    #[test]
+    fn simplify_conditions() {
+        assert_eq!(
+            simplify(
+                "if (!!a) {
+                    b;
+                }
+                while (!!(!!c)) {
+                    d;
+                }"
+            ),
+            4
+        );
+    }
+
+    #[test]
+    fn simplify_groupings() {
+        assert_eq!(simplify("var myVar = (a + b);"), 3);
+        assert_eq!(simplify("var myVar = ((a)) * (((b)));"), 3);
+        // --- groupings required by precedence are kept
+        assert_eq!(simplify("var myVar = (a + b) * c;"), 6);
+        assert_eq!(simplify("var myVar = a - (b - c);"), 6);
+        assert_eq!(simplify("var myVar = (a * b) + c;"), 5);
+    }
+
+    #[test]
+    fn fold_unary() {
+        assert_eq!(simplify("var myVar = -(2 * 3);"), 1);
+        assert_eq!(simplify("var myVar = !0;"), 1);
+
+        let ast = scan_and_parse("var myVar = -true;");
+        let mut optimizer = Optimizer::new(OptLevel::O1, false);
+        let optimized = optimizer.optimize(ast);
+        assert_eq!(Optimizer::count_nodes(&optimized), 2);
+        assert_eq!(optimizer.diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn fold_interpolation() {
+        let ast = scan_and_parse(r#"var myVar = "a ${1 + 2} b ${"c ${nil}"} ${true}";"#);
+        let optimized = Optimizer::new(OptLevel::O1, false).optimize(ast);
+        assert_eq!(Optimizer::count_nodes(&optimized), 1);
+        match &optimized[0] {
+            Stmt::VarDecl(decl) => match &decl.initializer.as_ref().unwrap().node {
+                Expr::Constant(Value::StringLiteral(s)) => assert_eq!(s, "a 3 b c nil true"),
+                _ => panic!("Should be folded into a string literal"),
+            },
+            _ => panic!("Should be a var declaration"),
+        }
+
+        // --- parts around an unknown expression are still folded
+        assert_eq!(simplify(r#"var myVar = "${1} ${a}";"#), 4);
+    }
+
+    #[test]
+    fn fold_operators() {
+        let cases = [
+            ("7 % 3", 1.0),
+            ("-7 % 3", -1.0),
+            ("2 ** 3 ** 2", 512.0),
+            ("-2 ** 2", -4.0),
+            ("2 ** -1", 0.5),
+            ("6 & 3", 2.0),
+            ("6 | 3", 7.0),
+            ("6 ^ 3", 5.0),
+            ("~5", -6.0),
+            ("1 << 4", 16.0),
+            ("-16 >> 2", -4.0),
+            ("1 + 2 << 1", 6.0),
+            ("1 | 2 ^ 3 & 4", 3.0),
+            ("2 * 3 % 4", 2.0),
+        ];
+        for (expr, expected) in cases {
+            let src = format!("var myVar = {};", expr);
+            let ast = scan_and_parse(&src);
+            let optimized = Optimizer::new(OptLevel::O1, false).optimize(ast);
+            match &optimized[0] {
+                Stmt::VarDecl(decl) => match &decl.initializer.as_ref().unwrap().node {
+                    Expr::Constant(Value::Number(n)) => assert_eq!(n.0, expected, "{}", expr),
+                    _ => panic!("{} should be folded into a number", expr),
+                },
+                _ => panic!("Should be a var declaration"),
+            }
+        }
+
+        // --- `(a & 1) == 1`, which would be folded into `a & true` if '==' bound tighter
+        assert_eq!(simplify("var myVar = a & 1 == 1;"), 5);
+
+        // --- operations failing at runtime are reported and left as is
+        for src in [
+            "var myVar = 1.5 & 1;",
+            "var myVar = 1 << -1;",
+            "var myVar = 5 % 0;",
+            "var myVar = ~0.5;",
+        ] {
+            let ast = scan_and_parse(src);
+            let mut optimizer = Optimizer::new(OptLevel::O1, false);
+            optimizer.optimize(ast);
+            assert_eq!(optimizer.diagnostics().len(), 1, "{}", src);
+        }
+    }
 }
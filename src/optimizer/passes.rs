@@ -0,0 +1,155 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::errors::RoxError;
+
+/// Optimization passes run over the ast. Each pass is a full traversal of the ast, and passes are
+/// always run in the order in which they are declared in `Pass::ALL`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Pass {
+    /// folds operations over constants into a single constant
+    ConstantFolding,
+    /// simplifies algebraic identities (e.g., `x * 1`) and redundant groupings and negations
+    AlgebraicSimplification,
+    /// removes statements that can never be executed
+    DeadCodeElimination,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 3] = [
+        Pass::ConstantFolding,
+        Pass::AlgebraicSimplification,
+        Pass::DeadCodeElimination,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::ConstantFolding => "fold",
+            Pass::AlgebraicSimplification => "simplify",
+            Pass::DeadCodeElimination => "dce",
+        }
+    }
+}
+
+impl Display for Pass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL
+            .into_iter()
+            .find(|pass| pass.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown optimization pass '{}'", s))
+    }
+}
+
+/// Optimization levels, each enabling a predefined set of passes
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OptLevel {
+    /// no optimizations
+    O0,
+    O1,
+    #[default]
+    O2,
+}
+
+impl OptLevel {
+    pub fn passes(&self) -> Vec<Pass> {
+        match self {
+            OptLevel::O0 => vec![],
+            OptLevel::O1 => vec![Pass::ConstantFolding],
+            OptLevel::O2 => vec![
+                Pass::ConstantFolding,
+                Pass::AlgebraicSimplification,
+                Pass::DeadCodeElimination,
+            ],
+        }
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => anyhow::bail!("unknown optimization level '{}'", s),
+        }
+    }
+}
+
+/// State shared by the nodes of the ast while a pass traverses it
+pub struct PassContext<'a> {
+    /// pass currently being run - nodes only apply the transformations belonging to it
+    pub pass: Pass,
+    /// issues found by the pass, always reported as warnings
+    pub diagnostics: Vec<RoxError<'a>>,
+}
+
+impl<'a> PassContext<'a> {
+    pub fn new(pass: Pass) -> Self {
+        Self {
+            pass,
+            diagnostics: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PassStats {
+    pub pass: Pass,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    pub diagnostics: usize,
+    pub elapsed: Duration,
+}
+
+impl PassStats {
+    pub fn removed_nodes(&self) -> usize {
+        self.nodes_before.saturating_sub(self.nodes_after)
+    }
+}
+
+impl Display for PassStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pass '{}': {} -> {} nodes ({} removed), {} diagnostics in {:?}",
+            self.pass,
+            self.nodes_before,
+            self.nodes_after,
+            self.removed_nodes(),
+            self.diagnostics,
+            self.elapsed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pass() {
+        assert_eq!("fold".parse::<Pass>().unwrap(), Pass::ConstantFolding);
+        assert_eq!(
+            "simplify".parse::<Pass>().unwrap(),
+            Pass::AlgebraicSimplification
+        );
+        assert_eq!("dce".parse::<Pass>().unwrap(), Pass::DeadCodeElimination);
+        assert!("inline".parse::<Pass>().is_err());
+    }
+
+    #[test]
+    fn parse_opt_level() {
+        assert_eq!("0".parse::<OptLevel>().unwrap(), OptLevel::O0);
+        assert_eq!("2".parse::<OptLevel>().unwrap(), OptLevel::O2);
+        assert!("3".parse::<OptLevel>().is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashSet;
+
+use crate::{
+    bitwise,
+    chunks::{Chunk, chunks::LineInfo, opcodes::OpCode, value::Value},
+};
+
+/// Instruction decoded from a chunk
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    op: OpCode,
+    /// index into the constants for loads and absolute offset of the target for jumps
+    operand: usize,
+    /// offset of the instruction in the chunk it was decoded from
+    offset: usize,
+    line: usize,
+}
+
+pub struct Peephole;
+
+impl Peephole {
+    /// Runs the following optimizations over the bytecode of chunk until none can be applied:
+    ///   - values pushed and immediately popped are removed
+    ///   - `Load c; Negate` is folded into a load of `-c`
+    ///   - jumps to jumps are threaded to their final target
+    ///   - jumps to the next instruction are removed
+    ///   - `Load c; <op>` is fused into a superinstruction taking c as operand (e.g., AddConstant)
+    ///
+    /// Instructions that are the target of a jump are never merged with the previous instruction.
+    /// Jump offsets and line info are remapped to the optimized code
+    pub fn optimize(chunk: Chunk) -> Chunk {
+        let mut instructions = Peephole::decode(&chunk);
+        let mut constants = chunk.constants;
+        let end = chunk.code.len();
+
+        loop {
+            let (optimized, changed) = Peephole::run(&instructions, &mut constants, end);
+            instructions = optimized;
+
+            if !changed {
+                break;
+            }
+        }
+
+        Peephole::encode(&instructions, constants, end)
+    }
+
+    fn decode(chunk: &Chunk) -> Vec<Instruction> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+
+        while offset < chunk.code.len() {
+            let op = OpCode::try_from(chunk.code[offset]).expect("invalid opcode");
+            let operands = &chunk.code[offset + 1..offset + 1 + op.operand_len()];
+
+            let operand = match op {
+                OpCode::Load
+                | OpCode::AddConstant
+                | OpCode::SubtractConstant
+                | OpCode::MultiplyConstant
+                | OpCode::DivideConstant
+                | OpCode::Call => operands[0] as usize,
+                OpCode::LoadLong => bitwise::u32_from_bytes(
+                    operands.try_into().expect("should be an array of 3 bytes"),
+                ) as usize,
+                OpCode::Jump => {
+                    let jump = bitwise::u16_from_bytes(
+                        operands.try_into().expect("should be an array of 2 bytes"),
+                    );
+                    offset + 3 + jump as usize
+                }
+                OpCode::BuildList | OpCode::BuildMap => bitwise::u16_from_bytes(
+                    operands.try_into().expect("should be an array of 2 bytes"),
+                ) as usize,
+                _ => 0,
+            };
+
+            instructions.push(Instruction {
+                op,
+                operand,
+                offset,
+                line: chunk.get_line_info_from_offset(offset).line,
+            });
+            offset += 1 + op.operand_len();
+        }
+
+        instructions
+    }
+
+    /// Runs a single pass over instructions, returning the optimized instructions and whether
+    /// any optimization was applied
+    fn run(
+        instructions: &[Instruction],
+        constants: &mut Vec<Value>,
+        end: usize,
+    ) -> (Vec<Instruction>, bool) {
+        let targets: HashSet<usize> = instructions
+            .iter()
+            .filter(|instr| instr.op == OpCode::Jump)
+            .map(|instr| instr.operand)
+            .collect();
+
+        let mut optimized = Vec::with_capacity(instructions.len());
+        let mut changed = false;
+        let mut i = 0;
+
+        while i < instructions.len() {
+            let instr = instructions[i];
+            // --- the next instruction can only be merged with this one if nothing jumps to it
+            let next = instructions
+                .get(i + 1)
+                .filter(|next| !targets.contains(&next.offset))
+                .map(|next| next.op);
+
+            match (instr.op, next) {
+                (OpCode::Load | OpCode::LoadLong, Some(OpCode::Pop)) => {
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+                (OpCode::Load | OpCode::LoadLong, Some(OpCode::Negate)) => {
+                    if let Value::Number(n) = constants[instr.operand] {
+                        let idx = constants.len();
+
+                        // --- never grow the code, so that jump offsets always fit
+                        if instr.op == OpCode::LoadLong || idx <= u8::MAX as usize {
+                            constants.push(Value::Number(-n));
+                            optimized.push(Instruction {
+                                operand: idx,
+                                ..instr
+                            });
+
+                            changed = true;
+                            i += 2;
+                            continue;
+                        }
+                    }
+                }
+                (OpCode::Load, Some(op)) if op.with_constant_operand().is_some() => {
+                    changed = true;
+                    // --- errors of the superinstruction are those of the operation, so it is
+                    // located at its line rather than at the line of the load
+                    optimized.push(Instruction {
+                        op: op.with_constant_operand().unwrap(),
+                        line: instructions[i + 1].line,
+                        ..instr
+                    });
+                    i += 2;
+                    continue;
+                }
+                (OpCode::Jump, _) => {
+                    let target = Peephole::thread_jump(instructions, instr.operand, end);
+                    let next_offset = instructions.get(i + 1).map_or(end, |next| next.offset);
+
+                    if target == next_offset {
+                        changed = true;
+                        i += 1;
+                        continue;
+                    }
+
+                    if target != instr.operand {
+                        changed = true;
+                        optimized.push(Instruction {
+                            operand: target,
+                            ..instr
+                        });
+                        i += 1;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            optimized.push(instr);
+            i += 1;
+        }
+
+        (optimized, changed)
+    }
+
+    /// Returns the offset of the instruction execution ends up at when jumping to target
+    fn thread_jump(instructions: &[Instruction], mut target: usize, end: usize) -> usize {
+        loop {
+            // --- removed instructions fall through to the next remaining one
+            let idx = instructions.partition_point(|instr| instr.offset < target);
+
+            match instructions.get(idx) {
+                // --- jumps only go forward, so this always terminates
+                Some(instr) if instr.op == OpCode::Jump => target = instr.operand,
+                Some(instr) => return instr.offset,
+                None => return end,
+            }
+        }
+    }
+
+    fn encode(instructions: &[Instruction], constants: Vec<Value>, end: usize) -> Chunk {
+        let mut offsets = Vec::with_capacity(instructions.len());
+        let mut offset = 0;
+        for instr in instructions.iter() {
+            offsets.push(offset);
+            offset += 1 + instr.op.operand_len();
+        }
+        let new_end = offset;
+
+        let remap = |target: usize| {
+            if target >= end {
+                return new_end;
+            }
+            let idx = instructions.partition_point(|instr| instr.offset < target);
+            offsets.get(idx).copied().unwrap_or(new_end)
+        };
+
+        let mut chunk = Chunk::new();
+        chunk.constants = constants;
+        let mut line_info: Vec<LineInfo> = vec![];
+
+        for (instr, offset) in instructions.iter().zip(offsets.iter()) {
+            if line_info.last().is_none_or(|info| info.line != instr.line) {
+                line_info.push(LineInfo {
+                    op_offset: *offset,
+                    line: instr.line,
+                });
+            }
+
+            chunk.write(instr.op);
+            match instr.op {
+                OpCode::Load
+                | OpCode::AddConstant
+                | OpCode::SubtractConstant
+                | OpCode::MultiplyConstant
+                | OpCode::DivideConstant
+                | OpCode::Call => chunk.write(instr.operand as u8),
+                OpCode::LoadLong => chunk.write_24b(instr.operand as u32),
+                OpCode::BuildList | OpCode::BuildMap => {
+                    let [b2, b1] = (instr.operand as u16).to_be_bytes();
+                    chunk.write(b2);
+                    chunk.write(b1);
+                }
+                OpCode::Jump => {
+                    let jump = u16::try_from(remap(instr.operand) - (offset + 3))
+                        .expect("jumps never grow during peephole optimization");
+                    let [b2, b1] = jump.to_be_bytes();
+                    chunk.write(b2);
+                    chunk.write(b1);
+                }
+                _ => {}
+            }
+        }
+
+        if !line_info.is_empty() {
+            chunk.line_info = line_info;
+        }
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+
+    fn number(n: f64) -> Value {
+        Value::Number(OrderedFloat(n))
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        Peephole::decode(chunk)
+            .iter()
+            .map(|instr| instr.op)
+            .collect()
+    }
+
+    #[test]
+    fn remove_push_pop() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Pop);
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::Return]);
+        assert_eq!(chunk.constants[chunk.code[1] as usize], number(2.0));
+    }
+
+    #[test]
+    fn fold_negated_constant() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(42.0));
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::Return]);
+        assert_eq!(chunk.constants[chunk.code[1] as usize], number(42.0));
+    }
+
+    #[test]
+    fn keep_negated_literal() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Literal("Hello, world!"));
+        chunk.write(OpCode::Negate);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::Negate]);
+    }
+
+    #[test]
+    fn thread_jumps() {
+        let mut chunk = Chunk::new();
+        let first_jump = chunk.write_jump();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Return);
+        chunk.patch_jump(first_jump);
+        let second_jump = chunk.write_jump();
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Return);
+        chunk.patch_jump(second_jump);
+        chunk.write_constant(number(3.0));
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        let instructions = Peephole::decode(&chunk);
+        assert_eq!(instructions[0].op, OpCode::Jump);
+        assert_eq!(instructions[0].operand, 12);
+        assert_eq!(chunk.constants[chunk.code[13] as usize], number(3.0));
+    }
+
+    #[test]
+    fn remove_jump_to_next_instruction() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump();
+        chunk.patch_jump(jump);
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::Return]);
+    }
+
+    #[test]
+    fn remap_jump_over_removed_instructions() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Return);
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Pop);
+        chunk.patch_jump(jump);
+        chunk.write_constant(number(3.0));
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(
+            ops(&chunk),
+            vec![
+                OpCode::Jump,
+                OpCode::Load,
+                OpCode::Return,
+                OpCode::Load,
+                OpCode::Return
+            ]
+        );
+        assert_eq!(&chunk.code[0..3], &[OpCode::Jump.into(), 0, 3]);
+        assert_eq!(chunk.constants[chunk.code[7] as usize], number(3.0));
+    }
+
+    #[test]
+    fn keep_pop_targeted_by_jump() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        let jump = chunk.write_jump();
+        chunk.write_constant(number(2.0));
+        chunk.patch_jump(jump);
+        chunk.write(OpCode::Pop);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(
+            ops(&chunk),
+            vec![OpCode::Load, OpCode::Jump, OpCode::Load, OpCode::Pop]
+        );
+    }
+
+    #[test]
+    fn fuse_superinstructions() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Add);
+        chunk.write_constant(number(3.0));
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Multiply);
+        chunk.write_constant(number(4.0));
+        chunk.write(OpCode::Subtract);
+        chunk.write_constant(number(5.0));
+        chunk.write(OpCode::Divide);
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(
+            ops(&chunk),
+            vec![
+                OpCode::Load,
+                OpCode::AddConstant,
+                OpCode::MultiplyConstant,
+                OpCode::SubtractConstant,
+                OpCode::DivideConstant,
+                OpCode::Return
+            ]
+        );
+        assert_eq!(chunk.constants[chunk.code[5] as usize], number(-3.0));
+    }
+
+    #[test]
+    fn keep_operation_targeted_by_jump() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(2.0));
+        let jump = chunk.write_jump();
+        chunk.write_constant(number(3.0));
+        chunk.patch_jump(jump);
+        chunk.write(OpCode::Add);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(
+            ops(&chunk),
+            vec![
+                OpCode::Load,
+                OpCode::Load,
+                OpCode::Jump,
+                OpCode::Load,
+                OpCode::Add
+            ]
+        );
+    }
+
+    #[test]
+    fn remap_line_info() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Pop);
+        chunk.set_line(2);
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Negate);
+        chunk.set_line(3);
+        chunk.write(OpCode::Return);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::Return]);
+        assert_eq!(chunk.line_info.len(), 2);
+        assert_eq!(chunk.get_line_info_from_offset(0).line, 2);
+        assert_eq!(chunk.get_line_info_from_offset(2).line, 3);
+    }
+
+    #[test]
+    fn locate_superinstruction_at_operation() {
+        // --- 1 /
+        //     0
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.set_line(2);
+        chunk.write_constant(number(0.0));
+        chunk.set_line(1);
+        chunk.write(OpCode::Divide);
+
+        let chunk = Peephole::optimize(chunk);
+        assert_eq!(ops(&chunk), vec![OpCode::Load, OpCode::DivideConstant]);
+        assert_eq!(chunk.get_line_info_from_offset(2).line, 1);
+    }
+}
@@ -2,13 +2,26 @@ use std::fmt::Display;
 
 use itertools::Itertools;
 
-use crate::scanner::token::Token;
+use crate::{
+    errors::RoxError,
+    optimizer::passes::{Pass, PassContext},
+    scanner::token::{Token, TokenType},
+};
 
-use super::expressions::{AssignmentExpr, CallExpr, Expr, PropertyAccessExpr, UnaryExpr};
+use super::{
+    expressions::{
+        AssignmentExpr, BinaryExpr, CallExpr, Expr, IndexAssignmentExpr, IndexExpr,
+        PropertyAccessExpr, UnaryExpr, Value,
+    },
+    parser::{infix_binding_power, prefix_binding_power},
+};
 
-pub trait AstNode {
+pub trait AstNode<'a> {
     fn count_nodes(&self) -> usize;
-    fn optimize(&self) -> Self;
+    /// returns a copy of the node optimized by the pass in ctx. Problems detected while optimizing
+    /// (e.g., constant folding of an operation that is guaranteed to fail at runtime) are pushed
+    /// into the diagnostics of ctx
+    fn optimize(&self, ctx: &mut PassContext<'a>) -> Self;
 }
 
 #[derive(Clone)]
@@ -33,13 +46,13 @@ impl<'a> Display for ExprNode<'a> {
     }
 }
 
-impl<'a> AstNode for ExprNode<'a> {
+impl<'a> AstNode<'a> for ExprNode<'a> {
     fn count_nodes(&self) -> usize {
         let nodes_in_subtrees = match &self.node {
             Expr::Error | Expr::Var(_) | Expr::Constant(_) => 0,
             Expr::Assignment(assignment) => assignment.expr.count_nodes(),
             Expr::Unary(unary) => unary.operand.count_nodes(),
-            Expr::Grouping(group) => group.count_nodes(),
+            Expr::Grouping(group) | Expr::Stringify(group) => group.count_nodes(),
             Expr::PropertyAccess(prop) => prop.object.count_nodes(),
             Expr::BinOp(binop) => {
                 let left = binop.left.count_nodes();
@@ -51,35 +64,99 @@ impl<'a> AstNode for ExprNode<'a> {
                 let arg_nodes = call.args.iter().map(|m| m.count_nodes()).sum::<usize>();
                 calee_nodes + arg_nodes
             }
+            Expr::List(elements) => elements.iter().map(|m| m.count_nodes()).sum::<usize>(),
+            Expr::Map(entries) => entries
+                .iter()
+                .map(|(key, value)| key.count_nodes() + value.count_nodes())
+                .sum::<usize>(),
+            Expr::Index(index) => index.object.count_nodes() + index.index.count_nodes(),
+            Expr::IndexAssignment(assignment) => {
+                assignment.object.count_nodes()
+                    + assignment.index.count_nodes()
+                    + assignment.expr.count_nodes()
+            }
         };
 
         nodes_in_subtrees + 1
     }
 
-    fn optimize(&self) -> Self {
+    fn optimize(&self, ctx: &mut PassContext<'a>) -> Self {
         let expr = match &self.node {
             Expr::BinOp(binop) => {
-                let optimized_left = binop.left.optimize();
-                let optimized_right = binop.right.optimize();
+                let optimized_left = binop.left.optimize(ctx);
+                let optimized_right = binop.right.optimize(ctx);
 
-                // --- if both the subtrees evaluated to constants, fold them
-                match (optimized_left.node, optimized_right.node) {
-                    (Expr::Constant(c1), Expr::Constant(c2)) => {
-                        Expr::fold_constants(c1, c2, binop.op)
-                    }
-                    _ => self.node.clone(),
+                if ctx.pass == Pass::AlgebraicSimplification {
+                    let left = binop.left.regroup(optimized_left, |operand| {
+                        needs_grouping(binop.op, Operand::Left, operand)
+                    });
+                    let right = binop.right.regroup(optimized_right, |operand| {
+                        needs_grouping(binop.op, Operand::Right, operand)
+                    });
+                    return ExprNode::simplify_binop(self.token.clone(), binop.op, left, right);
                 }
+
+                // --- if both the subtrees evaluated to constants, attempt to fold them
+                let folded = match (&optimized_left.node, &optimized_right.node) {
+                    (Expr::Constant(c1), Expr::Constant(c2))
+                        if ctx.pass == Pass::ConstantFolding =>
+                    {
+                        match Expr::fold_constants(c1.clone(), c2.clone(), binop.op) {
+                            Ok(folded) => folded,
+                            Err(e) => {
+                                // --- keep the original expression so the error is still raised
+                                // at runtime, should the user decide to proceed
+                                ctx.diagnostics
+                                    .push(RoxError::warning(self.token.clone(), e.to_string()));
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                folded.unwrap_or_else(|| {
+                    Expr::BinOp(BinaryExpr {
+                        op: binop.op,
+                        left: Box::new(optimized_left),
+                        right: Box::new(optimized_right),
+                    })
+                })
             }
             Expr::Unary(unary) => {
-                let optimized_operand = unary.operand.optimize();
+                let optimized_operand = unary.operand.optimize(ctx);
+
+                if ctx.pass == Pass::AlgebraicSimplification {
+                    let operand = unary.operand.regroup(optimized_operand, |operand| {
+                        needs_grouping(unary.op, Operand::Prefix, operand)
+                    });
+                    return ExprNode::simplify_unary(self.token.clone(), unary.op, operand);
+                }
 
-                Expr::Unary(UnaryExpr {
-                    op: unary.op,
-                    operand: Box::new(optimized_operand),
+                // --- if the operand evaluated to a constant, attempt to fold it
+                let folded = match &optimized_operand.node {
+                    Expr::Constant(c) if ctx.pass == Pass::ConstantFolding => {
+                        match Value::compute_unary(c.clone(), unary.op) {
+                            Ok(folded) => folded.map(Expr::Constant),
+                            Err(e) => {
+                                ctx.diagnostics
+                                    .push(RoxError::warning(self.token.clone(), e.to_string()));
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                folded.unwrap_or_else(|| {
+                    Expr::Unary(UnaryExpr {
+                        op: unary.op,
+                        operand: Box::new(optimized_operand),
+                    })
                 })
             }
             Expr::Assignment(assignment) => {
-                let optimized_expr = assignment.expr.optimize();
+                let optimized_expr = assignment.expr.optimize(ctx);
 
                 Expr::Assignment(AssignmentExpr {
                     name: assignment.name.clone(),
@@ -87,16 +164,46 @@ impl<'a> AstNode for ExprNode<'a> {
                 })
             }
             Expr::Call(call) => {
-                let optimized_args = call.args.iter().map(ExprNode::optimize).collect_vec();
-                let optimized_calee = call.calee.optimize();
+                let optimized_args = call.args.iter().map(|arg| arg.optimize(ctx)).collect_vec();
+                let optimized_calee = call.calee.optimize(ctx);
 
                 Expr::Call(CallExpr {
                     calee: Box::new(optimized_calee),
                     args: optimized_args,
                 })
             }
+            Expr::List(elements) => Expr::List(
+                elements
+                    .iter()
+                    .map(|element| element.optimize(ctx))
+                    .collect_vec(),
+            ),
+            Expr::Map(entries) => Expr::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.optimize(ctx), value.optimize(ctx)))
+                    .collect_vec(),
+            ),
+            Expr::Stringify(expr) => {
+                let optimized = expr.optimize(ctx);
+                match optimized.node {
+                    Expr::Constant(val) if ctx.pass == Pass::ConstantFolding => {
+                        Expr::Constant(Value::StringLiteral(val.stringify()))
+                    }
+                    _ => Expr::Stringify(Box::new(optimized)),
+                }
+            }
+            Expr::Index(index) => Expr::Index(IndexExpr {
+                object: Box::new(index.object.optimize(ctx)),
+                index: Box::new(index.index.optimize(ctx)),
+            }),
+            Expr::IndexAssignment(assignment) => Expr::IndexAssignment(IndexAssignmentExpr {
+                object: Box::new(assignment.object.optimize(ctx)),
+                index: Box::new(assignment.index.optimize(ctx)),
+                expr: Box::new(assignment.expr.optimize(ctx)),
+            }),
             Expr::PropertyAccess(prop) => {
-                let optimized_object = prop.object.optimize();
+                let optimized_object = prop.object.optimize(ctx);
 
                 Expr::PropertyAccess(PropertyAccessExpr {
                     object: Box::new(optimized_object),
@@ -104,10 +211,16 @@ impl<'a> AstNode for ExprNode<'a> {
                 })
             }
             Expr::Grouping(group) => {
-                let optimized = group.optimize();
+                let optimized = group.optimize(ctx);
+
+                // --- once the ast is built, groupings carry no meaning other than precedence:
+                // they are dropped here and restored by the operators whose operands need them
+                if ctx.pass == Pass::AlgebraicSimplification {
+                    return optimized;
+                }
 
                 match optimized.node {
-                    Expr::Constant(val) => Expr::Constant(val),
+                    Expr::Constant(val) if ctx.pass == Pass::ConstantFolding => Expr::Constant(val),
                     _ => Expr::Grouping(Box::new(optimized)),
                 }
             }
@@ -120,3 +233,153 @@ impl<'a> AstNode for ExprNode<'a> {
         }
     }
 }
+
+impl<'a> ExprNode<'a> {
+    /// Optimizes an expression whose value is only used for its truthiness (e.g., the condition
+    /// of an if statement), in which case double negations can be dropped
+    pub fn optimize_condition(&self, ctx: &mut PassContext<'a>) -> Self {
+        let optimized = self.optimize(ctx);
+        if ctx.pass != Pass::AlgebraicSimplification {
+            return optimized;
+        }
+
+        optimized.strip_double_negation()
+    }
+
+    fn strip_double_negation(self) -> Self {
+        if let Expr::Unary(outer) = &self.node
+            && outer.op == TokenType::Bang
+            && let Expr::Unary(inner) = &outer.operand.node
+            && inner.op == TokenType::Bang
+        {
+            return inner
+                .operand
+                .as_ref()
+                .clone()
+                .ungrouped()
+                .strip_double_negation();
+        }
+
+        self
+    }
+
+    /// Wraps optimized, the simplified version of self, in the grouping self was wrapped in if the
+    /// operator it is an operand of still needs it
+    fn regroup(&self, optimized: Self, needs_grouping: impl Fn(&Expr) -> bool) -> Self {
+        match &self.node {
+            Expr::Grouping(_) if needs_grouping(&optimized.node) => {
+                ExprNode::new(self.token.clone(), Expr::Grouping(Box::new(optimized)))
+            }
+            _ => optimized,
+        }
+    }
+
+    /// Strips the grouping of an operand whose operator was simplified away
+    fn ungrouped(self) -> Self {
+        match self.node {
+            Expr::Grouping(group) => *group,
+            _ => self,
+        }
+    }
+
+    /// Simplifies algebraic identities of `left op right`. Identities are only applied when the
+    /// remaining operand is known to be numeric, so that programs operating on invalid operands
+    /// still fail at runtime. `x + 0` is not `x` when x is -0, and it is kept as is
+    fn simplify_binop(token: Token<'a>, op: TokenType, left: Self, right: Self) -> Self {
+        let is = |node: &ExprNode, n: f64| matches!(node.node, Expr::Constant(Value::Number(v)) if v.0 == n);
+
+        match op {
+            TokenType::Minus if is(&right, 0.0) && left.node.is_numeric() => {
+                return left.ungrouped();
+            }
+            TokenType::Star if is(&right, 1.0) && left.node.is_numeric() => {
+                return left.ungrouped();
+            }
+            TokenType::Star if is(&left, 1.0) && right.node.is_numeric() => {
+                return right.ungrouped();
+            }
+            TokenType::Slash if is(&right, 1.0) && left.node.is_numeric() => {
+                return left.ungrouped();
+            }
+            // --- strength reduction: a negation is cheaper than a multiplication
+            TokenType::Star if is(&right, -1.0) && left.node.is_numeric() => {
+                return ExprNode::negate(token, left.ungrouped());
+            }
+            TokenType::Star if is(&left, -1.0) && right.node.is_numeric() => {
+                return ExprNode::negate(token, right.ungrouped());
+            }
+            _ => {}
+        }
+
+        ExprNode::new(
+            token,
+            Expr::BinOp(BinaryExpr {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            }),
+        )
+    }
+
+    /// Simplifies repeated unary operators:
+    ///   - `--x` is `x` if x is known to be numeric
+    ///   - `!!!x` is `!x`, since `!` only depends on the truthiness of its operand
+    fn simplify_unary(token: Token<'a>, op: TokenType, operand: Self) -> Self {
+        if let Expr::Unary(inner) = &operand.node
+            && inner.op == op
+        {
+            match op {
+                TokenType::Minus if inner.operand.node.is_numeric() => {
+                    return inner.operand.as_ref().clone().ungrouped();
+                }
+                TokenType::Bang if matches!(&inner.operand.node, Expr::Unary(u) if u.op == TokenType::Bang) =>
+                {
+                    return inner.operand.as_ref().clone();
+                }
+                _ => {}
+            }
+        }
+
+        ExprNode::new(
+            token,
+            Expr::Unary(UnaryExpr {
+                op,
+                operand: Box::new(operand),
+            }),
+        )
+    }
+
+    fn negate(token: Token<'a>, operand: Self) -> Self {
+        ExprNode::new(
+            token,
+            Expr::Unary(UnaryExpr {
+                op: TokenType::Minus,
+                operand: Box::new(operand),
+            }),
+        )
+    }
+}
+
+/// Position of an operand relative to its operator
+#[derive(Clone, Copy)]
+enum Operand {
+    Prefix,
+    Left,
+    Right,
+}
+
+/// Returns true if expr must be grouped to be parsed back as the operand of op at position:
+/// `(a + b) * c` must be, `(a * b) + c` need not
+fn needs_grouping(op: TokenType, position: Operand, expr: &Expr) -> bool {
+    let infix = |op| infix_binding_power(op).expect("binary operators have a binding power");
+    let prefix = |op| prefix_binding_power(op).1;
+
+    match (position, expr) {
+        (_, Expr::Assignment(_) | Expr::IndexAssignment(_)) => true,
+        (Operand::Prefix, Expr::BinOp(operand)) => infix(operand.op).0 < prefix(op),
+        (Operand::Left, Expr::BinOp(operand)) => infix(op).0 >= infix(operand.op).1,
+        (Operand::Right, Expr::BinOp(operand)) => infix(operand.op).0 < infix(op).1,
+        (Operand::Left, Expr::Unary(operand)) => infix(op).0 >= prefix(operand.op),
+        _ => false,
+    }
+}
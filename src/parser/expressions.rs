@@ -1,9 +1,22 @@
 use anyhow::bail;
+use ordered_float::OrderedFloat;
 
+use crate::chunks::value as runtime;
 use crate::scanner::token::{Token, TokenType};
 
 use super::ast::ExprNode;
 
+macro_rules! fold_error {
+    ($lhs_type:expr, $op:expr, $rhs_type:expr) => {
+        bail!(
+            "'{}' {} '{}' is not a valid operation",
+            $lhs_type,
+            $op,
+            $rhs_type
+        )
+    };
+}
+
 #[derive(Clone)]
 pub struct BinaryExpr<'a> {
     pub op: TokenType,
@@ -29,6 +42,19 @@ pub struct CallExpr<'a> {
     pub args: Vec<ExprNode<'a>>,
 }
 
+#[derive(Clone)]
+pub struct IndexExpr<'a> {
+    pub object: Box<ExprNode<'a>>,
+    pub index: Box<ExprNode<'a>>,
+}
+
+#[derive(Clone)]
+pub struct IndexAssignmentExpr<'a> {
+    pub object: Box<ExprNode<'a>>,
+    pub index: Box<ExprNode<'a>>,
+    pub expr: Box<ExprNode<'a>>,
+}
+
 #[derive(Clone)]
 pub struct PropertyAccessExpr<'a> {
     pub object: Box<ExprNode<'a>>,
@@ -36,10 +62,10 @@ pub struct PropertyAccessExpr<'a> {
 }
 
 // --- may be subject to constant folding
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Value {
     StringLiteral(String),
-    Number(i32),
+    Number(OrderedFloat<f64>),
     Bool(bool),
     Nil,
 }
@@ -118,17 +144,83 @@ pub enum Expr<'a> {
     /// ```
     PropertyAccess(PropertyAccessExpr<'a>),
 
+    /// List literal, holding the nodes of its elements
+    /// ```
+    /// // [1, a + b, "rox"]
+    /// ```
+    List(Vec<ExprNode<'a>>),
+
+    /// Index expression, reading the element at index of object
+    /// ```
+    /// // list[i + 1]
+    /// ```
+    Index(IndexExpr<'a>),
+
+    /// Assignment to the element at index of object
+    /// ```
+    /// // list[i] = 42
+    /// ```
+    IndexAssignment(IndexAssignmentExpr<'a>),
+
+    /// Map literal, holding the nodes of its keys and values
+    /// ```
+    /// // {"name": "rox", 1: a + b}
+    /// ```
+    Map(Vec<(ExprNode<'a>, ExprNode<'a>)>),
+
+    /// Conversion of a value to a string, as done by the `str` native. Interpolated strings are
+    /// desugared into the concatenation of their parts, with each interpolated expression
+    /// stringified
+    /// ```
+    /// // "Hello ${name}!" is parsed as "Hello " + stringify(name) + "!"
+    /// ```
+    Stringify(Box<ExprNode<'a>>),
+
     /// Represents an error
     Error,
 }
 
 impl<'a> Expr<'a> {
-    pub fn fold_constants(c1: Value, c2: Value, op: TokenType) -> Expr<'a> {
-        let computed_value = Value::compute(c1, c2, op);
+    /// Attempts to fold `c1 op c2` into a constant expression.
+    /// Returns Ok(None) if the result cannot be represented as a constant, and an error if the
+    /// operation is guaranteed to fail at runtime
+    pub fn fold_constants(c1: Value, c2: Value, op: TokenType) -> anyhow::Result<Option<Expr<'a>>> {
+        let computed_value = Value::compute(c1, c2, op)?;
+
+        Ok(computed_value.map(Expr::Constant))
+    }
 
-        match computed_value {
-            Ok(v) => Expr::Constant(v),
-            Err(_) => Expr::Error,
+    /// returns the value of the expression if it is a constant
+    pub fn as_constant(&self) -> Option<&Value> {
+        match self {
+            Expr::Constant(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// returns true if the expression is known to either evaluate to a number or to fail at
+    /// runtime
+    pub fn is_numeric(&self) -> bool {
+        match self {
+            Expr::Constant(Value::Number(_)) => true,
+            Expr::Grouping(group) => group.node.is_numeric(),
+            Expr::Unary(unary) => matches!(unary.op, TokenType::Minus | TokenType::Tilde),
+            Expr::BinOp(binop) => match binop.op {
+                TokenType::Minus
+                | TokenType::Star
+                | TokenType::Slash
+                | TokenType::Percent
+                | TokenType::StarStar
+                | TokenType::Ampersand
+                | TokenType::Pipe
+                | TokenType::Caret
+                | TokenType::LessLess
+                | TokenType::GreaterGreater => true,
+                // --- '+' may also concatenate strings
+                TokenType::Plus => binop.left.node.is_numeric() && binop.right.node.is_numeric(),
+                _ => false,
+            },
+            _ => false,
         }
     }
 
@@ -221,6 +313,70 @@ impl<'a> Expr<'a> {
                 s
             }
 
+            Expr::List(elements) => {
+                if elements.is_empty() {
+                    return format!("{}List: []", spaces);
+                }
+
+                let mut s = format!("{}List: [", spaces);
+                for element in elements.iter() {
+                    s += &format!("\n{}", element.node.to_yaml(next_level).trim_end());
+                }
+                s += &format!("\n{}]", spaces);
+                s
+            }
+
+            Expr::Map(entries) => {
+                if entries.is_empty() {
+                    return format!("{}Map: {{}}", spaces);
+                }
+
+                let mut s = format!("{}Map: {{", spaces);
+                for (key, value) in entries.iter() {
+                    s += &format!(
+                        "\n{}Key:\n{}",
+                        indent,
+                        key.node.to_yaml(next_level + 1).trim_end()
+                    );
+                    s += &format!(
+                        "\n{}Val:\n{}",
+                        indent,
+                        value.node.to_yaml(next_level + 1).trim_end()
+                    );
+                }
+                s += &format!("\n{}}}", spaces);
+                s
+            }
+
+            Expr::Stringify(expr) => {
+                let mut s = format!("{}Stringify:\n", spaces);
+                s += &format!("{}Expr:\n{}", indent, expr.node.to_yaml(next_level + 1));
+                s
+            }
+
+            Expr::Index(index) => {
+                let mut s = format!("{}Index:\n", spaces);
+                s += &format!(
+                    "{}Obj:\n{}",
+                    indent,
+                    index.object.node.to_yaml(next_level + 1)
+                );
+                s += &format!(
+                    "\n{}Idx:\n{}",
+                    indent,
+                    index.index.node.to_yaml(next_level + 1)
+                );
+                s
+            }
+
+            Expr::IndexAssignment(a) => {
+                let mut s = format!("{}IndexAssignment:\n", spaces);
+                s += &format!("{}Obj:\n{}", indent, a.object.node.to_yaml(next_level + 1));
+                s += &format!("\n{}Idx:\n{}", indent, a.index.node.to_yaml(next_level + 1));
+                s += &format!("\n{}Val:\n{}", indent, a.expr.node.to_yaml(next_level + 1));
+                s
+            }
+
             Expr::PropertyAccess(prop) => {
                 let mut s = format!("{}PropAccess:\n", spaces);
                 s += &format!(
@@ -242,48 +398,150 @@ impl<'a> std::fmt::Display for Expr<'a> {
 }
 
 impl Value {
-    pub fn compute(lhs: Value, rhs: Value, op: TokenType) -> anyhow::Result<Value> {
-        match op {
+    pub fn value_type(&self) -> &'static str {
+        match self {
+            Value::StringLiteral(_) => "string literal",
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+        }
+    }
+
+    /// the value as a string, as stringified at runtime
+    pub fn stringify(&self) -> String {
+        match self {
+            Value::StringLiteral(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Nil => String::from("nil"),
+        }
+    }
+
+    /// nil and false are falsey, every other value is truthy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Computes `op operand` at compile time, with the same semantics as `Value::compute`
+    pub fn compute_unary(operand: Value, op: TokenType) -> anyhow::Result<Option<Value>> {
+        let value = match (op, operand) {
+            (TokenType::Minus, Value::Number(n)) => Some(Value::Number(-n)),
+            (TokenType::Tilde, Value::Number(n)) => {
+                let operand = runtime::Value::Number(n);
+                match operand.bit_not() {
+                    Ok(runtime::Value::Number(n)) => Some(Value::Number(n)),
+                    Ok(_) => unreachable!("bitwise operations compute numbers"),
+                    Err(kind) => bail!(operand.unary_error(kind, "~").msg),
+                }
+            }
+            (TokenType::Minus | TokenType::Tilde, operand) => bail!(
+                "'{}' is not a valid operand for '{}'",
+                operand.value_type(),
+                op
+            ),
+            (TokenType::Bang, operand) => Some(Value::Bool(!operand.is_truthy())),
+            _ => None,
+        };
+
+        Ok(value)
+    }
+
+    /// Computes `lhs op rhs` at compile time.
+    /// Returns Ok(None) when the result is not known at compile time and an error when the
+    /// operation would fail at runtime
+    pub fn compute(lhs: Value, rhs: Value, op: TokenType) -> anyhow::Result<Option<Value>> {
+        let (lhs_type, rhs_type) = (lhs.value_type(), rhs.value_type());
+
+        let value = match op {
             TokenType::Plus => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
+                (Value::Number(l), Value::Number(r)) => Some(Value::Number(l + r)),
                 (Value::StringLiteral(l), Value::StringLiteral(r)) => {
-                    Ok(Value::StringLiteral(format!("{}{}", l, r)))
+                    Some(Value::StringLiteral(format!("{}{}", l, r)))
                 }
-                _ => bail!("invalid op for numbers"),
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
             TokenType::Minus => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l - r)),
-                _ => bail!("invalid op for numbers"),
+                (Value::Number(l), Value::Number(r)) => Some(Value::Number(l - r)),
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
             TokenType::Star => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l * r)),
-                _ => bail!("invalid op for numbers"),
+                (Value::Number(l), Value::Number(r)) => Some(Value::Number(l * r)),
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
             TokenType::Slash => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l / r)),
-                _ => bail!("invalid op for numbers"),
-            },
-            TokenType::EqualEqual => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l == r)),
-                (Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(l == r)),
-                (Value::StringLiteral(l), Value::StringLiteral(r)) => Ok(Value::Bool(l == r)),
-                _ => bail!("invalid op for numbers"),
+                (Value::Number(_), Value::Number(r)) if r.0 == 0.0 => {
+                    bail!("right hand side of the division is 0")
+                }
+                (Value::Number(l), Value::Number(r)) => Some(Value::Number(l / r)),
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
-            TokenType::GreaterEqual => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l >= r)),
-                _ => bail!("invalid op"),
+            TokenType::Percent
+            | TokenType::StarStar
+            | TokenType::Ampersand
+            | TokenType::Pipe
+            | TokenType::Caret
+            | TokenType::LessLess
+            | TokenType::GreaterGreater => match (lhs, rhs) {
+                (Value::Number(l), Value::Number(r)) => {
+                    Some(Value::Number(fold_numeric(l, r, op)?))
+                }
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
-            TokenType::LessEqual => match (lhs, rhs) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l <= r)),
-                _ => bail!("invalid op"),
+            // --- values of different types are never equal, so equality never fails
+            TokenType::EqualEqual => Some(Value::Bool(lhs == rhs)),
+            TokenType::BangEqual => Some(Value::Bool(lhs != rhs)),
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => match (lhs, rhs) {
+                (Value::Number(l), Value::Number(r)) => Some(Value::Bool(match op {
+                    TokenType::Greater => l > r,
+                    TokenType::GreaterEqual => l >= r,
+                    TokenType::Less => l < r,
+                    _ => l <= r,
+                })),
+                _ => fold_error!(lhs_type, op, rhs_type),
             },
+            // --- logical operators evaluate to one of their operands
+            TokenType::And => Some(if lhs.is_truthy() { rhs } else { lhs }),
+            TokenType::Or => Some(if lhs.is_truthy() { lhs } else { rhs }),
             _ => unreachable!(),
-        }
+        };
+
+        Ok(value)
+    }
+}
+
+/// Computes `l op r` with the implementation of the VM, so that folded operations always agree
+/// with their evaluation at runtime, e.g. on the bitwise operations of non-integral numbers
+fn fold_numeric(
+    l: OrderedFloat<f64>,
+    r: OrderedFloat<f64>,
+    op: TokenType,
+) -> anyhow::Result<OrderedFloat<f64>> {
+    let (l, r) = (runtime::Value::Number(l), runtime::Value::Number(r));
+    let result = match op {
+        TokenType::Percent => l.modulo(r),
+        TokenType::StarStar => l.pow(r),
+        TokenType::Ampersand => l.bit_and(r),
+        TokenType::Pipe => l.bit_or(r),
+        TokenType::Caret => l.bit_xor(r),
+        TokenType::LessLess => l.shift_left(r),
+        TokenType::GreaterGreater => l.shift_right(r),
+        _ => unreachable!(),
+    };
+
+    match result {
+        Ok(runtime::Value::Number(n)) => Ok(n),
+        Ok(_) => unreachable!("numeric operations compute numbers"),
+        Err(kind) => bail!(l.op_error(kind, &op.to_string(), &r).msg),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use ordered_float::OrderedFloat;
+
     use crate::{
         parser::{expressions::Expr, parser::Parser},
         scanner::{
@@ -517,6 +775,213 @@ mod tests {
         assert!(matches!(node.node, Expr::Call(_)));
     }
 
+    #[test]
+    fn parse_string() {
+        let tokens = scan(r#""say \"hi\"\n\u{1F980}";"#);
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        assert!(!parser.has_errors());
+        match &node.node {
+            Expr::Constant(super::Value::StringLiteral(s)) => assert_eq!(s, "say \"hi\"\n🦀"),
+            _ => panic!("Should be a string literal"),
+        }
+    }
+
+    #[test]
+    fn parse_numbers() {
+        for (src, expected) in [("0x1F;", 31.0), ("1_000.5;", 1000.5), ("1e-3;", 0.001)] {
+            let tokens = scan(src);
+            let mut parser = Parser::new(tokens);
+            let node = parser.parse_expression(true);
+
+            assert!(!parser.has_errors());
+            match &node.node {
+                Expr::Constant(super::Value::Number(n)) => assert_eq!(*n, OrderedFloat(expected)),
+                _ => panic!("Should be a number"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_interpolation() {
+        let tokens = scan(r#""Hello ${name}!";"#);
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        // --- "Hello " + stringify(name) + "!"
+        assert!(!parser.has_errors());
+        let Expr::BinOp(outer) = &node.node else {
+            panic!("Should be binop");
+        };
+        let Expr::BinOp(inner) = &outer.left.node else {
+            panic!("Should be binop");
+        };
+        assert_eq!((outer.op, inner.op), (TokenType::Plus, TokenType::Plus));
+        assert!(
+            matches!(&inner.left.node, Expr::Constant(super::Value::StringLiteral(s)) if s == "Hello ")
+        );
+        assert!(
+            matches!(&inner.right.node, Expr::Stringify(expr) if matches!(expr.node, Expr::Var("name")))
+        );
+        assert!(
+            matches!(&outer.right.node, Expr::Constant(super::Value::StringLiteral(s)) if s == "!")
+        );
+
+        // --- empty parts are dropped
+        let tokens = scan(r#""${a}${b}";"#);
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+        assert!(!parser.has_errors());
+        let Expr::BinOp(binop) = &node.node else {
+            panic!("Should be binop");
+        };
+        assert!(matches!(binop.left.node, Expr::Stringify(_)));
+        assert!(matches!(binop.right.node, Expr::Stringify(_)));
+    }
+
+    #[test]
+    fn parse_invalid_interpolation() {
+        for src in [r#""a ${1 2} b";"#, r#""a ${} b";"#] {
+            let tokens = scan(src);
+            let mut parser = Parser::new(tokens);
+            parser.parse_expression(true);
+            assert!(parser.has_errors(), "{} should not parse", src);
+        }
+    }
+
+    #[test]
+    fn parse_list() {
+        let tokens = scan("[1, a + b, [c]];");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        assert!(!parser.has_errors());
+        match &node.node {
+            Expr::List(elements) => {
+                assert_eq!(elements.len(), 3);
+                assert!(matches!(elements[1].node, Expr::BinOp(_)));
+                assert!(matches!(elements[2].node, Expr::List(_)));
+            }
+            _ => panic!("Should be list"),
+        }
+
+        let tokens = scan("[];");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+        assert!(!parser.has_errors());
+        assert!(matches!(&node.node, Expr::List(elements) if elements.is_empty()));
+    }
+
+    #[test]
+    fn parse_map() {
+        let tokens = scan("{1: a, k: [b], nil: {},};");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        assert!(!parser.has_errors());
+        match &node.node {
+            Expr::Map(entries) => {
+                assert_eq!(entries.len(), 3);
+                assert!(matches!(
+                    entries[0].0.node,
+                    Expr::Constant(super::Value::Number(OrderedFloat(1.0)))
+                ));
+                assert!(matches!(entries[1].0.node, Expr::Var("k")));
+                assert!(matches!(entries[1].1.node, Expr::List(_)));
+                assert!(matches!(
+                    entries[2].0.node,
+                    Expr::Constant(super::Value::Nil)
+                ));
+                assert!(matches!(&entries[2].1.node, Expr::Map(m) if m.is_empty()));
+            }
+            _ => panic!("Should be map"),
+        }
+
+        let tokens = scan("m[k] = {};");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+        assert!(!parser.has_errors());
+        assert!(matches!(node.node, Expr::IndexAssignment(_)));
+    }
+
+    #[test]
+    fn parse_invalid_map() {
+        for src in ["{1 2};", "{1: 2;", "{1: };"] {
+            let tokens = scan(src);
+            let mut parser = Parser::new(tokens);
+            parser.parse_expression(true);
+            assert!(parser.has_errors(), "{} should not parse", src);
+        }
+    }
+
+    #[test]
+    fn parse_index() {
+        let tokens = scan("matrix[i][j + 1] * 2;");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        assert!(!parser.has_errors());
+        match &node.node {
+            Expr::BinOp(bin) => match &bin.left.node {
+                Expr::Index(index) => {
+                    assert!(matches!(index.object.node, Expr::Index(_)));
+                    assert!(matches!(index.index.node, Expr::BinOp(_)));
+                }
+                _ => panic!("Should be index"),
+            },
+            _ => panic!("Should be binop"),
+        }
+    }
+
+    #[test]
+    fn parse_index_assignment() {
+        let tokens = scan("list[0] = 42;");
+        let mut parser = Parser::new(tokens);
+        let node = parser.parse_expression(true);
+
+        assert!(!parser.has_errors());
+        assert!(matches!(node.node, Expr::IndexAssignment(_)));
+    }
+
+    #[test]
+    fn parse_unterminated_list() {
+        let tokens = scan("[1, 2;");
+        let mut parser = Parser::new(tokens);
+        parser.parse_expression(true);
+        assert!(parser.has_errors());
+
+        let tokens = scan("list[0;");
+        let mut parser = Parser::new(tokens);
+        parser.parse_expression(true);
+        assert!(parser.has_errors());
+    }
+
+    #[test]
+    fn parse_prefix_power() {
+        // --- every prefix operator applies to the power, and binds tighter than other operators
+        for src in ["!x ** 2;", "-x ** 2;"] {
+            let mut parser = Parser::new(scan(src));
+            let node = parser.parse_expression(true);
+
+            assert!(!parser.has_errors());
+            match &node.node {
+                Expr::Unary(unary) => match &unary.operand.node {
+                    Expr::BinOp(bin) => assert_eq!(bin.op, TokenType::StarStar, "{}", src),
+                    _ => panic!("Should be binop"),
+                },
+                _ => panic!("Should be unary"),
+            }
+        }
+
+        let mut parser = Parser::new(scan("!x * 2;"));
+        let node = parser.parse_expression(true);
+        match &node.node {
+            Expr::BinOp(bin) => assert!(matches!(bin.left.node, Expr::Unary(_))),
+            _ => panic!("Should be binop"),
+        }
+    }
+
     #[test]
     fn parse_call_prop_access() {
         let tokens = scan("obj.methodOne(42).methodTwo(hello, goodbye)();");
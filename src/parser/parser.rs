@@ -1,12 +1,19 @@
+use ordered_float::OrderedFloat;
+
 use crate::{
     errors::RoxError,
-    scanner::token::{Token, TokenType},
+    scanner::{
+        escapes::{EscapeError, unescape},
+        numbers::parse_number,
+        token::{Token, TokenType},
+    },
 };
 
 use super::{
     ast::ExprNode,
     expressions::{
-        AssignmentExpr, BinaryExpr, CallExpr, Expr, PropertyAccessExpr, UnaryExpr, Value,
+        AssignmentExpr, BinaryExpr, CallExpr, Expr, IndexAssignmentExpr, IndexExpr,
+        PropertyAccessExpr, UnaryExpr, Value,
     },
     statements::{
         ClassDeclStatement, ForStmt, FuncDeclStatement, IfStmt, ReturnStmt, Stmt, VarDeclStatement,
@@ -26,6 +33,13 @@ macro_rules! valid_infix_op {
             | TokenType::Minus
             | TokenType::Star
             | TokenType::Slash
+            | TokenType::Percent
+            | TokenType::StarStar
+            | TokenType::Ampersand
+            | TokenType::Pipe
+            | TokenType::Caret
+            | TokenType::LessLess
+            | TokenType::GreaterGreater
             | TokenType::Equal
             | TokenType::Less
             | TokenType::LessEqual
@@ -37,6 +51,7 @@ macro_rules! valid_infix_op {
             | TokenType::Or
             | TokenType::Dot
             | TokenType::LeftParen
+            | TokenType::LeftBracket
     };
 }
 
@@ -353,11 +368,77 @@ impl<'a> Parser<'a> {
     fn parse_expr(&mut self, bp: usize) -> ExprNode<'a> {
         let tok = self.next().clone();
         let lhs = match tok.token_type {
-            TokenType::StringLiteral => {
-                Expr::Constant(Value::StringLiteral(tok.lexeme.unwrap().to_string()))
+            TokenType::StringLiteral => match string_contents(&tok) {
+                Ok(s) => Expr::Constant(Value::StringLiteral(s)),
+                Err(e) => {
+                    parsing_error!(self, tok, format!("invalid string literal: {}", e));
+                }
+            },
+            TokenType::Interpolation => {
+                // --- parts alternate between string literals and interpolated expressions, until
+                // the literal ending the string
+                let mut parts = vec![];
+                let mut literal = tok.clone();
+                loop {
+                    match string_contents(&literal) {
+                        Ok(s) if s.is_empty() => {}
+                        Ok(s) => parts.push(ExprNode::new(
+                            literal.clone(),
+                            Expr::Constant(Value::StringLiteral(s)),
+                        )),
+                        Err(e) => {
+                            parsing_error!(self, literal, format!("invalid string literal: {}", e));
+                        }
+                    }
+                    if literal.token_type == TokenType::StringLiteral {
+                        break;
+                    }
+
+                    let expr = self.parse_expr(0);
+                    if expr.node.is_error() {
+                        return expr;
+                    }
+                    parts.push(ExprNode::new(
+                        expr.token.clone(),
+                        Expr::Stringify(Box::new(expr)),
+                    ));
+
+                    literal = self.next().clone();
+                    if !matches!(
+                        literal.token_type,
+                        TokenType::StringLiteral | TokenType::Interpolation
+                    ) {
+                        parsing_error!(
+                            self,
+                            literal,
+                            format!(
+                                "unexpected token: expected '}}' but got '{}'",
+                                literal.token_type
+                            )
+                        );
+                    }
+                }
+
+                // --- desugar into the concatenation of the parts, from left to right
+                let mut parts = parts.into_iter();
+                let first = parts
+                    .next()
+                    .expect("interpolated strings have at least one expression");
+                parts
+                    .fold(first, |left, right| {
+                        ExprNode::new(
+                            tok.clone(),
+                            Expr::BinOp(BinaryExpr {
+                                op: TokenType::Plus,
+                                left: Box::new(left),
+                                right: Box::new(right),
+                            }),
+                        )
+                    })
+                    .node
             }
             TokenType::Identifier => Expr::Var(tok.lexeme.unwrap()),
-            TokenType::Minus | TokenType::Plus | TokenType::Bang => {
+            TokenType::Minus | TokenType::Plus | TokenType::Tilde | TokenType::Bang => {
                 let (_, rbp) = prefix_binding_power(tok.token_type);
                 let operand = self.parse_expr(rbp);
                 Expr::Unary(UnaryExpr {
@@ -365,15 +446,17 @@ impl<'a> Parser<'a> {
                     operand: Box::new(operand),
                 })
             }
+            TokenType::Nil => Expr::Constant(Value::Nil),
             TokenType::True | TokenType::False => {
                 let parsed_bool: bool = tok.lexeme.unwrap().parse().unwrap();
                 Expr::Constant(Value::Bool(parsed_bool))
             }
-            TokenType::Number => {
-                let num_as_str = tok.lexeme.unwrap();
-                let parsed_num = num_as_str.parse().unwrap();
-                Expr::Constant(Value::Number(parsed_num))
-            }
+            TokenType::Number => match parse_number(tok.lexeme.unwrap_or_default()) {
+                Ok(n) => Expr::Constant(Value::Number(OrderedFloat(n))),
+                Err(e) => {
+                    parsing_error!(self, tok, format!("invalid number literal: {}", e));
+                }
+            },
             TokenType::LeftParen => {
                 let group_expr = self.parse_expr(0);
                 if !group_expr.node.is_error() && !self.matches(TokenType::RightParen) {
@@ -389,6 +472,82 @@ impl<'a> Parser<'a> {
 
                 Expr::Grouping(Box::new(group_expr))
             }
+            TokenType::LeftBracket => {
+                let mut elements = vec![];
+                while !self.is_at_end()
+                    && !matches!(self.peek().token_type, TokenType::RightBracket)
+                {
+                    elements.push(self.parse_expr(0));
+
+                    // --- elements are separated by commas, with an optional trailing one
+                    if !self.matches(TokenType::Comma) {
+                        break;
+                    }
+                }
+
+                if !self.matches(TokenType::RightBracket) {
+                    let tok = self.peek().clone();
+                    parsing_error!(
+                        self,
+                        tok,
+                        format!(
+                            "unexpected token: expected ']' but got '{}'",
+                            tok.token_type
+                        )
+                    );
+                }
+
+                Expr::List(elements)
+            }
+            // --- blocks only follow statement keywords, which consume their opening brace before
+            // parsing any expression, so a brace in expression position always opens a map
+            TokenType::LeftBrace => {
+                let mut entries = vec![];
+                while !self.is_at_end() && !matches!(self.peek().token_type, TokenType::RightBrace)
+                {
+                    let key = self.parse_expr(0);
+                    if key.node.is_error() {
+                        return key;
+                    }
+
+                    if !self.matches(TokenType::Colon) {
+                        let tok = self.peek().clone();
+                        parsing_error!(
+                            self,
+                            tok,
+                            format!(
+                                "unexpected token: expected ':' but got '{}'",
+                                tok.token_type
+                            )
+                        );
+                    }
+
+                    let value = self.parse_expr(0);
+                    if value.node.is_error() {
+                        return value;
+                    }
+                    entries.push((key, value));
+
+                    // --- entries are separated by commas, with an optional trailing one
+                    if !self.matches(TokenType::Comma) {
+                        break;
+                    }
+                }
+
+                if !self.matches(TokenType::RightBrace) {
+                    let tok = self.peek().clone();
+                    parsing_error!(
+                        self,
+                        tok,
+                        format!(
+                            "unexpected token: expected '}}' but got '{}'",
+                            tok.token_type
+                        )
+                    );
+                }
+
+                Expr::Map(entries)
+            }
             _ => Expr::Error,
         };
 
@@ -411,6 +570,11 @@ impl<'a> Parser<'a> {
                 TokenType::EOF
                 | TokenType::Semicolon
                 | TokenType::RightParen
+                | TokenType::RightBracket
+                | TokenType::RightBrace
+                | TokenType::Colon
+                | TokenType::StringLiteral
+                | TokenType::Interpolation
                 | TokenType::Comma => break,
                 _ => {
                     parsing_error!(
@@ -489,6 +653,28 @@ impl<'a> Parser<'a> {
                     }),
                 )
             }
+            TokenType::LeftBracket => {
+                let index = self.parse_expr(0);
+                if !index.node.is_error() && !self.matches(TokenType::RightBracket) {
+                    let tok = self.peek().clone();
+                    parsing_error!(
+                        self,
+                        tok,
+                        format!(
+                            "unexpected token: expected ']' but got '{}'",
+                            tok.token_type
+                        )
+                    );
+                }
+
+                ExprNode::new(
+                    op.clone(),
+                    Expr::Index(IndexExpr {
+                        object: Box::new(lhs),
+                        index: Box::new(index),
+                    }),
+                )
+            }
             _ => panic!("Invalid postfix operator"),
         }
     }
@@ -507,13 +693,13 @@ impl<'a> Parser<'a> {
         // --- emit ast node based on the type of the operator
         match &op.token_type {
             TokenType::Equal => {
-                // --- left hand side needs to be an identifier
-                if !matches!(lhs.node, Expr::Var(_)) {
+                // --- left hand side needs to be an identifier or an index expression
+                if !matches!(lhs.node, Expr::Var(_) | Expr::Index(_)) {
                     parsing_error!(self, lhs.token, "invalid variable assignment".to_string());
                 }
 
                 // --- if the right hand side is an assignment, this is also invalid
-                if matches!(rhs.node, Expr::Assignment(_)) {
+                if matches!(rhs.node, Expr::Assignment(_) | Expr::IndexAssignment(_)) {
                     parsing_error!(
                         self,
                         lhs.token,
@@ -521,13 +707,19 @@ impl<'a> Parser<'a> {
                     );
                 }
 
-                ExprNode::new(
-                    op.clone(),
-                    Expr::Assignment(AssignmentExpr {
+                let assignment = match lhs.node {
+                    Expr::Index(index) => Expr::IndexAssignment(IndexAssignmentExpr {
+                        object: index.object,
+                        index: index.index,
+                        expr: Box::new(rhs),
+                    }),
+                    _ => Expr::Assignment(AssignmentExpr {
                         name: lhs.token,
                         expr: Box::new(rhs),
                     }),
-                )
+                };
+
+                ExprNode::new(op.clone(), assignment)
             }
             _ => ExprNode::new(
                 op,
@@ -654,9 +846,21 @@ impl<'a> Parser<'a> {
     }
 }
 
+/// contents of a string literal, or of a part of an interpolated string, without its delimiters and
+/// with its escape sequences resolved
+fn string_contents(tok: &Token) -> Result<String, EscapeError> {
+    let lexeme = tok.lexeme.unwrap_or_default();
+    let end = match tok.token_type {
+        TokenType::Interpolation => "${".len(),
+        _ => "\"".len(),
+    };
+    // --- the lexeme starts with the opening quote, or the '}' ending an interpolation
+    unescape(&lexeme[1..lexeme.len() - end])
+}
+
 fn postfix_binding_power(token_type: TokenType) -> Option<(usize, usize)> {
     let res = match token_type {
-        TokenType::LeftParen => (41, 42),
+        TokenType::LeftParen | TokenType::LeftBracket => (41, 42),
         TokenType::Dot => (51, 52),
         _ => return None,
     };
@@ -664,7 +868,7 @@ fn postfix_binding_power(token_type: TokenType) -> Option<(usize, usize)> {
     Some(res)
 }
 
-fn infix_binding_power(token_type: TokenType) -> Option<(usize, usize)> {
+pub(super) fn infix_binding_power(token_type: TokenType) -> Option<(usize, usize)> {
     let res = match token_type {
         TokenType::Equal => (5, 6),
         TokenType::Or => (7, 8),
@@ -673,18 +877,25 @@ fn infix_binding_power(token_type: TokenType) -> Option<(usize, usize)> {
         TokenType::Less | TokenType::LessEqual | TokenType::Greater | TokenType::GreaterEqual => {
             (17, 18)
         }
-        TokenType::Plus | TokenType::Minus => (21, 22),
-        TokenType::Star | TokenType::Slash => (31, 32),
+        // --- bitwise operators bind tighter than comparisons, `x & 1 == 0` tests the lowest bit
+        TokenType::Pipe => (19, 20),
+        TokenType::Caret => (21, 22),
+        TokenType::Ampersand => (23, 24),
+        TokenType::LessLess | TokenType::GreaterGreater => (25, 26),
+        TokenType::Plus | TokenType::Minus => (27, 28),
+        TokenType::Star | TokenType::Slash | TokenType::Percent => (31, 32),
+        // --- right associative, `2 ** 3 ** 2` is `2 ** (3 ** 2)`
+        TokenType::StarStar => (36, 35),
         _ => return None,
     };
 
     Some(res)
 }
 
-fn prefix_binding_power(token_type: TokenType) -> ((), usize) {
+pub(super) fn prefix_binding_power(token_type: TokenType) -> ((), usize) {
     match token_type {
-        TokenType::Minus | TokenType::Plus => ((), 90),
-        TokenType::Bang => ((), 100),
+        // --- looser than '**', `-2 ** 2` is `-(2 ** 2)` and `!x ** 2` is `!(x ** 2)`
+        TokenType::Minus | TokenType::Plus | TokenType::Tilde | TokenType::Bang => ((), 33),
         _ => panic!("invalid prefix token_type: '{}'", token_type),
     }
 }
@@ -2,9 +2,15 @@ use std::fmt::Display;
 
 use itertools::Itertools;
 
-use crate::scanner::token::Token;
+use crate::{
+    optimizer::passes::{Pass, PassContext},
+    scanner::token::Token,
+};
 
-use super::ast::{AstNode, ExprNode};
+use super::{
+    ast::{AstNode, ExprNode},
+    expressions::{Expr, Value},
+};
 
 #[derive(Clone)]
 pub struct IfStmt<'a> {
@@ -100,7 +106,7 @@ pub enum Stmt<'a> {
     Error,
 }
 
-impl<'a> AstNode for Stmt<'a> {
+impl<'a> AstNode<'a> for Stmt<'a> {
     fn count_nodes(&self) -> usize {
         match self {
             Stmt::Error => 1,
@@ -161,38 +167,41 @@ impl<'a> AstNode for Stmt<'a> {
         }
     }
 
-    fn optimize(&self) -> Self {
+    fn optimize(&self, ctx: &mut PassContext<'a>) -> Self {
         match self {
-            Stmt::Expression(expr) => Stmt::Expression(expr.optimize()),
+            Stmt::Expression(expr) => Stmt::Expression(expr.optimize(ctx)),
             Stmt::If(payload) => Stmt::If(IfStmt {
-                condition: payload.condition.optimize(),
-                if_body: payload.if_body.iter().map(Stmt::optimize).collect_vec(),
-                else_body: payload.else_body.iter().map(Stmt::optimize).collect_vec(),
+                condition: payload.condition.optimize_condition(ctx),
+                if_body: Stmt::optimize_block(&payload.if_body, ctx),
+                else_body: Stmt::optimize_block(&payload.else_body, ctx),
             }),
             Stmt::While(payload) => Stmt::While(WhileStmt {
-                condition: payload.condition.optimize(),
-                body: payload.body.iter().map(Stmt::optimize).collect_vec(),
+                condition: payload.condition.optimize_condition(ctx),
+                body: Stmt::optimize_block(&payload.body, ctx),
             }),
             Stmt::For(payload) => Stmt::For(ForStmt {
                 initializer: payload
                     .initializer
                     .as_ref()
-                    .map(|init| Box::new(init.optimize())),
-                condition: payload.condition.as_ref().map(|cond| cond.optimize()),
-                increment: payload.increment.as_ref().map(|incr| incr.optimize()),
-                body: payload.body.iter().map(Stmt::optimize).collect_vec(),
+                    .map(|init| Box::new(init.optimize(ctx))),
+                condition: payload
+                    .condition
+                    .as_ref()
+                    .map(|cond| cond.optimize_condition(ctx)),
+                increment: payload.increment.as_ref().map(|incr| incr.optimize(ctx)),
+                body: Stmt::optimize_block(&payload.body, ctx),
             }),
             Stmt::VarDecl(var) => Stmt::VarDecl(VarDeclStatement {
                 var_name: var.var_name.clone(),
-                initializer: var.initializer.as_ref().map(|init| init.optimize()),
+                initializer: var.initializer.as_ref().map(|init| init.optimize(ctx)),
             }),
             Stmt::Return(ret) => Stmt::Return(ReturnStmt {
-                value: ret.value.as_ref().map(|val| val.optimize()),
+                value: ret.value.as_ref().map(|val| val.optimize(ctx)),
             }),
             Stmt::FuncDecl(func) => Stmt::FuncDecl(FuncDeclStatement {
                 name: func.name.clone(),
                 parameters: func.parameters.clone(),
-                body: func.body.iter().map(Stmt::optimize).collect_vec(),
+                body: Stmt::optimize_block(&func.body, ctx),
             }),
             Stmt::ClassDecl(class) => Stmt::ClassDecl(ClassDeclStatement {
                 name: class.name.clone(),
@@ -202,7 +211,7 @@ impl<'a> AstNode for Stmt<'a> {
                     .map(|method| FuncDeclStatement {
                         name: method.name.clone(),
                         parameters: method.parameters.clone(),
-                        body: method.body.iter().map(Stmt::optimize).collect_vec(),
+                        body: Stmt::optimize_block(&method.body, ctx),
                     })
                     .collect_vec(),
             }),
@@ -212,6 +221,75 @@ impl<'a> AstNode for Stmt<'a> {
 }
 
 impl<'a> Stmt<'a> {
+    /// Optimizes a list of statements. When running dead code elimination, the statements that
+    /// can never be executed are removed:
+    ///   - statements following a return
+    ///   - branches of if statements whose condition folds to a constant
+    ///   - while loops whose condition folds to a falsey constant
+    pub fn optimize_block(block: &[Stmt<'a>], ctx: &mut PassContext<'a>) -> Vec<Stmt<'a>> {
+        if ctx.pass != Pass::DeadCodeElimination {
+            return block.iter().map(|stmt| stmt.optimize(ctx)).collect_vec();
+        }
+
+        let mut optimized = vec![];
+
+        for stmt in block {
+            match stmt.optimize(ctx) {
+                Stmt::If(payload) => match payload.condition.node.as_constant() {
+                    Some(condition) => {
+                        let taken_branch = if condition.is_truthy() {
+                            payload.if_body
+                        } else {
+                            payload.else_body
+                        };
+                        Stmt::splice_branch(payload.condition.token, taken_branch, &mut optimized);
+                    }
+                    None => optimized.push(Stmt::If(payload)),
+                },
+                Stmt::While(payload)
+                    if payload
+                        .condition
+                        .node
+                        .as_constant()
+                        .is_some_and(|condition| !condition.is_truthy()) => {}
+                stmt => optimized.push(stmt),
+            }
+
+            // --- nothing after a return is reachable
+            if matches!(optimized.last(), Some(Stmt::Return(_))) {
+                break;
+            }
+        }
+
+        optimized
+    }
+
+    /// Inlines the statements of a branch that is always taken into the enclosing block.
+    /// If the branch declares anything, inlining it would leak the declarations into the enclosing
+    /// scope, so the branch is kept behind an `if (true)` instead
+    fn splice_branch(token: Token<'a>, branch: Vec<Stmt<'a>>, block: &mut Vec<Stmt<'a>>) {
+        if branch.is_empty() {
+            return;
+        }
+
+        let has_declarations = branch.iter().any(|stmt| {
+            matches!(
+                stmt,
+                Stmt::VarDecl(_) | Stmt::FuncDecl(_) | Stmt::ClassDecl(_)
+            )
+        });
+
+        if has_declarations {
+            block.push(Stmt::If(IfStmt {
+                condition: ExprNode::new(token, Expr::Constant(Value::Bool(true))),
+                if_body: branch,
+                else_body: vec![],
+            }));
+        } else {
+            block.extend(branch);
+        }
+    }
+
     pub fn log(&self) {
         println!("{}", self);
     }
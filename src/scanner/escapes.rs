@@ -0,0 +1,147 @@
+use std::fmt::Display;
+
+/// maximum number of hex digits of a `\u{...}` escape
+const MAX_UNICODE_DIGITS: usize = 6;
+
+/// Invalid escape sequence in the contents of a string literal
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EscapeError {
+    /// byte offset of the backslash starting the sequence, into the contents
+    pub offset: usize,
+    pub msg: String,
+}
+
+impl Display for EscapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for EscapeError {}
+
+/// Resolves the escape sequences of contents, the characters of a string literal between its
+/// quotes:
+///  - `\n`, `\t`, `\"` and `\\` for a new line, a tab, a quote and a backslash
+///  - `\$` for a dollar sign, which does not start an interpolation
+///  - `\u{...}` for the unicode scalar value with 1 to 6 hex digits, e.g. `\u{1F980}`
+pub fn unescape(contents: &str) -> Result<String, EscapeError> {
+    let mut s = String::with_capacity(contents.len());
+    let mut chars = contents.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+
+        let error = |msg: String| EscapeError { offset, msg };
+        match chars.next() {
+            Some((_, 'n')) => s.push('\n'),
+            Some((_, 't')) => s.push('\t'),
+            Some((_, '"')) => s.push('"'),
+            Some((_, '\\')) => s.push('\\'),
+            Some((_, '$')) => s.push('$'),
+            Some((_, 'u')) => {
+                if chars.next_if(|&(_, c)| c == '{').is_none() {
+                    return Err(error(String::from("expected '{' after '\\u'")));
+                }
+
+                let mut digits = String::new();
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphanumeric()) {
+                    digits.push(c);
+                }
+                if chars.next_if(|&(_, c)| c == '}').is_none() {
+                    return Err(error(format!(
+                        "unterminated unicode escape '\\u{{{}'",
+                        digits
+                    )));
+                }
+
+                let valid_digits = (1..=MAX_UNICODE_DIGITS).contains(&digits.len())
+                    && digits.chars().all(|c| c.is_ascii_hexdigit());
+                if !valid_digits {
+                    return Err(error(format!(
+                        "invalid unicode escape '\\u{{{}}}': expected 1 to {} hex digits",
+                        digits, MAX_UNICODE_DIGITS
+                    )));
+                }
+
+                let scalar = u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| {
+                        error(format!(
+                            "'\\u{{{}}}' is not a valid unicode scalar value",
+                            digits
+                        ))
+                    })?;
+                s.push(scalar);
+            }
+            Some((_, c)) => return Err(error(format!("invalid escape sequence '\\{}'", c))),
+            None => return Err(error(String::from("unterminated escape sequence"))),
+        }
+    }
+
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_sequences() {
+        let cases = [
+            ("rox", "rox"),
+            ("", ""),
+            (r"a\nb\tc", "a\nb\tc"),
+            (r#"say \"hi\""#, "say \"hi\""),
+            (r"C:\\rox", "C:\\rox"),
+            (r"\${rox}", "${rox}"),
+            (r"\u{48}\u{e9}\u{1F980}", "Hé🦀"),
+            ("multi\nline", "multi\nline"),
+        ];
+        for (contents, expected) in cases {
+            assert_eq!(unescape(contents).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        let cases = [
+            (r"ab\q", 2, "invalid escape sequence '\\q'"),
+            (r"\u48", 0, "expected '{' after '\\u'"),
+            (r"é\u{48", 2, "unterminated unicode escape '\\u{48'"),
+            (
+                r"\u{}",
+                0,
+                "invalid unicode escape '\\u{}': expected 1 to 6 hex digits",
+            ),
+            (
+                r"\u{12345678}",
+                0,
+                "invalid unicode escape '\\u{12345678}': expected 1 to 6 hex digits",
+            ),
+            (
+                r"\u{xyz}",
+                0,
+                "invalid unicode escape '\\u{xyz}': expected 1 to 6 hex digits",
+            ),
+            (
+                r"\u{D800}",
+                0,
+                "'\\u{D800}' is not a valid unicode scalar value",
+            ),
+            ("rox\\", 3, "unterminated escape sequence"),
+        ];
+        for (contents, offset, msg) in cases {
+            let err = unescape(contents).unwrap_err();
+            assert_eq!(
+                (err.offset, err.msg.as_str()),
+                (offset, msg),
+                "{}",
+                contents
+            );
+        }
+    }
+}
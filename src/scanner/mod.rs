@@ -1,2 +1,4 @@
+pub mod escapes;
+pub mod numbers;
 pub mod scanner;
 pub mod token;
@@ -0,0 +1,214 @@
+use std::fmt::Display;
+
+/// Malformed number literal
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NumberError {
+    /// byte offset of the offending character, into the literal
+    pub offset: usize,
+    pub msg: String,
+}
+
+impl NumberError {
+    fn new(offset: usize, msg: String) -> Self {
+        Self { offset, msg }
+    }
+}
+
+impl Display for NumberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for NumberError {}
+
+/// Parses the lexeme of a number literal:
+///  - decimals with an optional fraction and exponent, e.g. `42`, `3.14` or `1e-9`
+///  - hexadecimal and binary integers, e.g. `0xFF` or `0b1010`
+///
+/// Digits may be grouped with `_` separators, e.g. `1_000_000`, which must be placed between two
+/// digits. Hexadecimal and binary integers must fit in 64 bits and, as every number, are rounded
+/// to the closest f64
+pub fn parse_number(lexeme: &str) -> Result<f64, NumberError> {
+    if lexeme.starts_with("0x") {
+        parse_integer(lexeme, 16, "hexadecimal")
+    } else if lexeme.starts_with("0b") {
+        parse_integer(lexeme, 2, "binary")
+    } else {
+        parse_decimal(lexeme)
+    }
+}
+
+/// parses a literal made of a two character prefix and digits in the given radix
+fn parse_integer(lexeme: &str, radix: u32, kind: &str) -> Result<f64, NumberError> {
+    let (prefix, body) = lexeme.split_at(2);
+    if body.is_empty() {
+        return Err(NumberError::new(
+            lexeme.len(),
+            format!("expected {} digits after '{}'", kind, prefix),
+        ));
+    }
+
+    for (offset, c) in body.char_indices() {
+        let offset = prefix.len() + offset;
+        match c {
+            '_' => check_separator(lexeme, offset, radix)?,
+            '.' => {
+                return Err(NumberError::new(
+                    offset,
+                    format!("{} literals cannot have a fractional part", kind),
+                ));
+            }
+            c if c.is_digit(radix) => {}
+            c => {
+                return Err(NumberError::new(
+                    offset,
+                    format!("invalid digit '{}' in {} literal", c, kind),
+                ));
+            }
+        }
+    }
+
+    let digits: String = body.chars().filter(|&c| c != '_').collect();
+    u64::from_str_radix(&digits, radix)
+        .map(|n| n as f64)
+        .map_err(|_| {
+            NumberError::new(
+                0,
+                format!("{} literal '{}' does not fit in 64 bits", kind, lexeme),
+            )
+        })
+}
+
+fn parse_decimal(lexeme: &str) -> Result<f64, NumberError> {
+    let mut chars = lexeme.char_indices().peekable();
+    let mut fraction = false;
+    let mut exponent = false;
+
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '0'..='9' => {}
+            '_' => check_separator(lexeme, offset, 10)?,
+            '.' if !fraction && !exponent => {
+                fraction = true;
+                if !chars.peek().is_some_and(|&(_, c)| c.is_ascii_digit()) {
+                    return Err(NumberError::new(
+                        offset,
+                        String::from("expected digits after the decimal point"),
+                    ));
+                }
+            }
+            'e' | 'E' if !exponent => {
+                exponent = true;
+                chars.next_if(|&(_, c)| c == '+' || c == '-');
+                if !chars.peek().is_some_and(|&(_, c)| c.is_ascii_digit()) {
+                    return Err(NumberError::new(
+                        offset,
+                        String::from("expected digits in the exponent"),
+                    ));
+                }
+            }
+            '.' => {
+                return Err(NumberError::new(
+                    offset,
+                    String::from("unexpected '.' in number literal"),
+                ));
+            }
+            c => {
+                return Err(NumberError::new(
+                    offset,
+                    format!("invalid digit '{}' in number literal", c),
+                ));
+            }
+        }
+    }
+
+    // --- the literal is well-formed at this point, which the standard parser accepts
+    let digits: String = lexeme.chars().filter(|&c| c != '_').collect();
+    match digits.parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(n),
+        _ => Err(NumberError::new(
+            0,
+            format!("number literal '{}' is too large", lexeme),
+        )),
+    }
+}
+
+/// a digit separator must sit between two digits of the literal
+fn check_separator(lexeme: &str, offset: usize, radix: u32) -> Result<(), NumberError> {
+    let is_digit = |c: Option<char>| c.is_some_and(|c| c.is_digit(radix));
+    let before = lexeme[..offset].chars().next_back();
+    let after = lexeme[offset + 1..].chars().next();
+
+    if is_digit(before) && is_digit(after) {
+        Ok(())
+    } else {
+        Err(NumberError::new(
+            offset,
+            String::from("digit separator '_' must be between two digits"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_numbers() {
+        let cases = [
+            ("0", 0.0),
+            ("42", 42.0),
+            ("2.5", 2.5),
+            ("1_000_000", 1_000_000.0),
+            ("1e3", 1e3),
+            ("1E+3", 1e3),
+            ("2.5e-9", 2.5e-9),
+            ("1_0.0_1e1_0", 10.01e10),
+            ("0xFF", 255.0),
+            ("0xdead_beef", 3_735_928_559.0),
+            ("0b1010", 10.0),
+            ("0b1111_0000", 240.0),
+            ("0xffffffffffffffff", u64::MAX as f64),
+        ];
+        for (lexeme, expected) in cases {
+            assert_eq!(parse_number(lexeme).unwrap(), expected, "{}", lexeme);
+        }
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        let cases = [
+            ("1__0", 1, "digit separator '_' must be between two digits"),
+            ("10_", 2, "digit separator '_' must be between two digits"),
+            ("1_.5", 1, "digit separator '_' must be between two digits"),
+            ("0x_1", 2, "digit separator '_' must be between two digits"),
+            ("0x", 2, "expected hexadecimal digits after '0x'"),
+            ("0b", 2, "expected binary digits after '0b'"),
+            ("0b102", 4, "invalid digit '2' in binary literal"),
+            ("0xFG", 3, "invalid digit 'G' in hexadecimal literal"),
+            (
+                "0x1.5",
+                3,
+                "hexadecimal literals cannot have a fractional part",
+            ),
+            ("1e", 1, "expected digits in the exponent"),
+            ("1e+", 1, "expected digits in the exponent"),
+            ("1e_5", 1, "expected digits in the exponent"),
+            ("1.", 1, "expected digits after the decimal point"),
+            ("1.2.3", 3, "unexpected '.' in number literal"),
+            ("1e5.0", 3, "unexpected '.' in number literal"),
+            ("12abc", 2, "invalid digit 'a' in number literal"),
+            ("1e400", 0, "number literal '1e400' is too large"),
+            (
+                "0x1_0000_0000_0000_0000",
+                0,
+                "hexadecimal literal '0x1_0000_0000_0000_0000' does not fit in 64 bits",
+            ),
+        ];
+        for (lexeme, offset, msg) in cases {
+            let err = parse_number(lexeme).unwrap_err();
+            assert_eq!((err.offset, err.msg.as_str()), (offset, msg), "{}", lexeme);
+        }
+    }
+}
@@ -1,13 +1,11 @@
+use super::escapes::unescape;
+use super::numbers::parse_number;
 use super::token::{Token, TokenType};
 use crate::{scanning_error, token};
 
 macro_rules! if_then {
     ($cond:expr, $true:expr, $false:expr) => {
-        if $cond {
-            $true
-        } else {
-            $false
-        }
+        if $cond { $true } else { $false }
     };
 }
 
@@ -19,6 +17,9 @@ pub struct Scanner<'a> {
     /// iterator over src, points to the next char to be scanned
     cur: usize,
     line: usize,
+    /// braces opened and not yet closed in each interpolation being scanned, innermost last - the
+    /// interpolation ends at the first `}` closing none of them
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
@@ -28,6 +29,7 @@ impl<'a> Scanner<'a> {
             start: 0,
             cur: 0,
             line: 1,
+            interpolations: vec![],
         }
     }
 
@@ -36,13 +38,16 @@ impl<'a> Scanner<'a> {
         while !self.is_at_end() {
             tokens.push(self.scan_token()?);
         }
+        if !self.interpolations.is_empty() {
+            scanning_error!(self, "unterminated string interpolation");
+        }
         tokens.push(self.scan_token()?);
 
         Ok(tokens)
     }
 
     fn scan_token(&mut self) -> anyhow::Result<Token<'a>> {
-        self.skip_whitespaces();
+        self.skip_whitespaces()?;
 
         // --- point start to the current token
         self.start = self.cur;
@@ -55,46 +60,78 @@ impl<'a> Scanner<'a> {
         match self.advance().unwrap() {
             '(' => return token!(self, TokenType::LeftParen, 1),
             ')' => return token!(self, TokenType::RightParen, 1),
-            '{' => return token!(self, TokenType::LeftBrace, 1),
-            '}' => return token!(self, TokenType::RightBrace, 1),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                return token!(self, TokenType::LeftBrace, 1);
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    // --- the interpolated expression ended, scan the rest of the string
+                    Some(0) => {
+                        self.interpolations.pop();
+                        return self.string();
+                    }
+                    Some(depth) => *depth -= 1,
+                    None => {}
+                }
+                return token!(self, TokenType::RightBrace, 1);
+            }
+            '[' => return token!(self, TokenType::LeftBracket, 1),
+            ']' => return token!(self, TokenType::RightBracket, 1),
             ';' => return token!(self, TokenType::Semicolon, 1),
             ',' => return token!(self, TokenType::Comma, 1),
+            ':' => return token!(self, TokenType::Colon, 1),
             '.' => return token!(self, TokenType::Dot, 1),
             '-' => return token!(self, TokenType::Minus, 1),
             '+' => return token!(self, TokenType::Plus, 1),
             '/' => return token!(self, TokenType::Slash, 1),
-            '*' => return token!(self, TokenType::Star, 1),
-            '!' => {
+            '*' => {
                 return token!(
                     self,
-                    if_then!(self.matches('='), TokenType::BangEqual, TokenType::Bang),
+                    if_then!(self.matches('*'), TokenType::StarStar, TokenType::Star),
                     self.cur_span()
-                )
+                );
             }
-            '<' => {
+            '%' => return token!(self, TokenType::Percent, 1),
+            '&' => return token!(self, TokenType::Ampersand, 1),
+            '|' => return token!(self, TokenType::Pipe, 1),
+            '^' => return token!(self, TokenType::Caret, 1),
+            '~' => return token!(self, TokenType::Tilde, 1),
+            '!' => {
                 return token!(
                     self,
-                    if_then!(self.matches('='), TokenType::LessEqual, TokenType::Less),
+                    if_then!(self.matches('='), TokenType::BangEqual, TokenType::Bang),
                     self.cur_span()
-                )
+                );
+            }
+            '<' => {
+                let token_type = if self.matches('=') {
+                    TokenType::LessEqual
+                } else if self.matches('<') {
+                    TokenType::LessLess
+                } else {
+                    TokenType::Less
+                };
+                return token!(self, token_type, self.cur_span());
             }
             '>' => {
-                return token!(
-                    self,
-                    if_then!(
-                        self.matches('='),
-                        TokenType::GreaterEqual,
-                        TokenType::Greater
-                    ),
-                    self.cur_span()
-                )
+                let token_type = if self.matches('=') {
+                    TokenType::GreaterEqual
+                } else if self.matches('>') {
+                    TokenType::GreaterGreater
+                } else {
+                    TokenType::Greater
+                };
+                return token!(self, token_type, self.cur_span());
             }
             '=' => {
                 return token!(
                     self,
                     if_then!(self.matches('='), TokenType::EqualEqual, TokenType::Equal),
                     self.cur_span()
-                )
+                );
             }
             '"' => return self.string(),
             '0'..='9' => return self.number(),
@@ -159,7 +196,7 @@ impl<'a> Scanner<'a> {
         self.cur - self.start
     }
 
-    fn skip_whitespaces(&mut self) {
+    fn skip_whitespaces(&mut self) -> anyhow::Result<()> {
         loop {
             let c = self.peek();
             if c.is_none() {
@@ -174,50 +211,146 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if let Some('/') = self.peek_next() {
+                '/' => match self.peek_next() {
+                    Some('/') => {
                         while !self.is_at_end() && self.peek().unwrap() != '\n' {
                             self.advance();
                         }
-                    } else {
-                        break;
                     }
-                }
+                    Some('*') => self.block_comment()?,
+                    _ => break,
+                },
                 _ => break,
             };
         }
+
+        Ok(())
     }
 
+    /// Skips a `/* ... */` comment, which may contain other block comments and new lines
+    fn block_comment(&mut self) -> anyhow::Result<()> {
+        let open = self.cur;
+        // --- block comments opened and not yet closed, including this one
+        let mut depth = 0;
+
+        loop {
+            match (self.peek(), self.peek_next()) {
+                (Some('/'), Some('*')) => {
+                    depth += 1;
+                    self.advance();
+                }
+                (Some('*'), Some('/')) => {
+                    depth -= 1;
+                    self.advance();
+                    if depth == 0 {
+                        self.advance();
+                        return Ok(());
+                    }
+                }
+                (Some('\n'), _) => self.line += 1,
+                (Some(_), _) => {}
+                (None, _) => {
+                    let (line, column) = self.position(open);
+                    anyhow::bail!(
+                        "scanning error in line {} at column {}: unterminated block comment",
+                        line,
+                        column
+                    );
+                }
+            }
+            self.advance();
+        }
+    }
+
+    /// Scans a string literal, whose lexeme keeps its quotes and escape sequences - see `unescape`.
+    /// A `${` ends the literal as an interpolation, and the string is scanned again from the `}`
+    /// closing the interpolated expression, which starts the lexeme instead of the opening quote
     fn string(&mut self) -> anyhow::Result<Token<'a>> {
+        let mut token_type = TokenType::StringLiteral;
         while !self.is_at_end() && self.peek().unwrap() != '"' {
+            if self.peek().unwrap() == '$' && self.peek_next() == Some('{') {
+                token_type = TokenType::Interpolation;
+                break;
+            }
+
+            // --- the escaped character never ends the string, even if it is a quote
+            if self.peek().unwrap() == '\\' {
+                self.advance();
+            }
+
             // --- increase line number if we're at a new line
-            if self.peek().unwrap() == '\n' {
+            if self.peek() == Some('\n') {
                 self.line += 1;
             }
 
             self.advance();
         }
 
-        // println!("scanning: {}", &self.src[self.start..=self.cur]);
-
         // --- if we're at the end, we have an unterminated string
         if self.is_at_end() {
             scanning_error!(self, "unterminated string");
         }
 
-        token!(self, TokenType::StringLiteral, self.cur_span())
+        // --- consume the closing quote, or the opening of the interpolation
+        let delimiter = match token_type {
+            TokenType::Interpolation => {
+                self.interpolations.push(0);
+                "${"
+            }
+            _ => "\"",
+        };
+        self.cur += delimiter.len();
+
+        // --- escape sequences are checked here, where their position is known
+        let contents = &self.src[self.start + 1..self.cur - delimiter.len()];
+        if let Err(e) = unescape(contents) {
+            let (line, column) = self.position(self.start + 1 + e.offset);
+            anyhow::bail!(
+                "scanning error in line {} at column {}: {}",
+                line,
+                column,
+                e
+            );
+        }
+
+        token!(self, token_type, self.cur_span())
+    }
+
+    /// line and column, counting characters from 1, of the byte at offset into the source
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.src[..offset];
+        let line = 1 + before.matches('\n').count();
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        (line, before[line_start..].chars().count() + 1)
     }
 
     fn number(&mut self) -> anyhow::Result<Token<'a>> {
-        while !self.is_at_end() && self.peek().unwrap().is_digit(10) {
+        let prefixed = ["0x", "0b"]
+            .iter()
+            .any(|p| self.src[self.start..].starts_with(p));
+
+        // --- consume the whole literal, including invalid digits, so that malformed literals are
+        // reported instead of being split into several tokens. A point is only part of the
+        // literal when a digit follows, `1.method` is a call on 1
+        while let Some(c) = self.peek() {
+            let exponent_sign = matches!(c, '+' | '-')
+                && !prefixed
+                && matches!(self.src[..self.cur].chars().next_back(), Some('e' | 'E'));
+            let point = c == '.' && self.peek_next().is_some_and(|c| c.is_ascii_digit());
+            if !(is_alphanumeric(c) || exponent_sign || point) {
+                break;
+            }
             self.advance();
         }
 
-        // check for decimal points
-        if self.matches('.') {
-            while !self.is_at_end() && self.peek().unwrap().is_digit(10) {
-                self.advance();
-            }
+        if let Err(e) = parse_number(&self.src[self.start..self.cur]) {
+            let (line, column) = self.position(self.start + e.offset);
+            anyhow::bail!(
+                "scanning error in line {} at column {}: {}",
+                line,
+                column,
+                e
+            );
         }
 
         token!(self, TokenType::Number, self.cur_span())
@@ -327,6 +460,213 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scan_extended_numbers() {
+        let mut scanner = Scanner::new("0xFF 0b1010 1_000_000 1e-9 2.5E+3");
+        let tokens = scanner.scan().unwrap();
+        let lexemes: Vec<_> = tokens.iter().filter_map(|token| token.lexeme).collect();
+        assert_eq!(
+            lexemes,
+            vec!["0xFF", "0b1010", "1_000_000", "1e-9", "2.5E+3"]
+        );
+
+        // --- a point not followed by a digit is not part of the number
+        let mut scanner = Scanner::new("1.len");
+        let tokens = scanner.scan().unwrap();
+        let token_types: Vec<_> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::Number,
+                TokenType::Dot,
+                TokenType::Identifier,
+                TokenType::EOF,
+            ]
+        );
+
+        // --- the sign of an exponent, not a subtraction
+        let mut scanner = Scanner::new("0xe-1");
+        let tokens = scanner.scan().unwrap();
+        assert_eq!(tokens[0].lexeme, Some("0xe"));
+        assert_eq!(tokens[1].token_type, TokenType::Minus);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        let cases = [
+            (
+                "x = 0b102;",
+                "scanning error in line 1 at column 9: invalid digit '2' in binary literal",
+            ),
+            (
+                "x =\n  1__000;",
+                "scanning error in line 2 at column 4: digit separator '_' must be between two digits",
+            ),
+            (
+                "1e+;",
+                "scanning error in line 1 at column 2: expected digits in the exponent",
+            ),
+            (
+                "0x;",
+                "scanning error in line 1 at column 3: expected hexadecimal digits after '0x'",
+            ),
+        ];
+        for (src, msg) in cases {
+            let mut scanner = Scanner::new(src);
+            assert_eq!(scanner.scan().unwrap_err().to_string(), msg, "{}", src);
+        }
+    }
+
+    #[test]
+    fn scan_strings() {
+        let mut scanner = Scanner::new(r#""say \"hi\"" "" "a\\";"#);
+        let tokens = scanner.scan().unwrap();
+        let lexemes: Vec<_> = tokens.iter().map(|token| token.lexeme).collect();
+        assert_eq!(
+            lexemes,
+            vec![
+                Some(r#""say \"hi\"""#),
+                Some(r#""""#),
+                Some(r#""a\\""#),
+                Some(";"),
+                None
+            ]
+        );
+
+        // --- lines are counted inside strings
+        let mut scanner = Scanner::new("\"multi\nline\" rox");
+        let tokens = scanner.scan().unwrap();
+        assert_eq!(tokens[1].line, 2);
+    }
+
+    #[test]
+    fn scan_interpolations() {
+        let src = r#""a ${f({k: "${x}"}) + 1} b ${y}" "\${z}";"#;
+        let mut scanner = Scanner::new(src);
+        let tokens = scanner.scan().unwrap();
+        let tokens: Vec<_> = tokens
+            .iter()
+            .map(|token| (token.token_type, token.lexeme.unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Interpolation, r#""a ${"#),
+                (TokenType::Identifier, "f"),
+                (TokenType::LeftParen, "("),
+                (TokenType::LeftBrace, "{"),
+                (TokenType::Identifier, "k"),
+                (TokenType::Colon, ":"),
+                (TokenType::Interpolation, r#""${"#),
+                (TokenType::Identifier, "x"),
+                (TokenType::StringLiteral, r#"}""#),
+                (TokenType::RightBrace, "}"),
+                (TokenType::RightParen, ")"),
+                (TokenType::Plus, "+"),
+                (TokenType::Number, "1"),
+                (TokenType::Interpolation, "} b ${"),
+                (TokenType::Identifier, "y"),
+                (TokenType::StringLiteral, r#"}""#),
+                (TokenType::StringLiteral, r#""\${z}""#),
+                (TokenType::Semicolon, ";"),
+                (TokenType::EOF, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_interpolations() {
+        for src in [r#""a ${x"#, r#""a ${ {x} "#, r#""a ${x} b"#] {
+            let mut scanner = Scanner::new(src);
+            assert!(scanner.scan().is_err(), "{} should not scan", src);
+        }
+    }
+
+    #[test]
+    fn invalid_string_escapes() {
+        let mut scanner = Scanner::new("var s;\nvar t = \"tab\\q\";");
+        let err = scanner.scan().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "scanning error in line 2 at column 13: invalid escape sequence '\\q'"
+        );
+
+        let mut scanner = Scanner::new("\"é\n\\u{110000}\"");
+        let err = scanner.scan().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "scanning error in line 2 at column 1: '\\u{110000}' is not a valid unicode scalar value"
+        );
+
+        let mut scanner = Scanner::new("\"unterminated\\\"");
+        assert!(scanner.scan().is_err());
+    }
+
+    #[test]
+    fn scan_brackets() {
+        let mut scanner = Scanner::new("[1, 2][0]");
+        let tokens = scanner.scan().unwrap();
+        let token_types: Vec<_> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::LeftBracket,
+                TokenType::Number,
+                TokenType::Comma,
+                TokenType::Number,
+                TokenType::RightBracket,
+                TokenType::LeftBracket,
+                TokenType::Number,
+                TokenType::RightBracket,
+                TokenType::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_operators() {
+        let mut scanner = Scanner::new("% ** * & | ^ ~ << <= < >> >= >");
+        let tokens = scanner.scan().unwrap();
+        let token_types: Vec<_> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::Percent,
+                TokenType::StarStar,
+                TokenType::Star,
+                TokenType::Ampersand,
+                TokenType::Pipe,
+                TokenType::Caret,
+                TokenType::Tilde,
+                TokenType::LessLess,
+                TokenType::LessEqual,
+                TokenType::Less,
+                TokenType::GreaterGreater,
+                TokenType::GreaterEqual,
+                TokenType::Greater,
+                TokenType::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_colon() {
+        let mut scanner = Scanner::new("{k: 1}");
+        let tokens = scanner.scan().unwrap();
+        let token_types: Vec<_> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::LeftBrace,
+                TokenType::Identifier,
+                TokenType::Colon,
+                TokenType::Number,
+                TokenType::RightBrace,
+                TokenType::EOF,
+            ]
+        );
+    }
+
     #[test]
     fn scan_whitespaces() {
         let mut scanner = Scanner::new("      \t\r\n");
@@ -336,9 +676,40 @@ mod tests {
 
     #[test]
     fn scan_whitespaces_and_comment() {
-        let mut scanner = Scanner::new("      \t\r\n// this is a comment and should be ignored\n// this should also be a comment even though afterwards we simply get EOF");
+        let mut scanner = Scanner::new(
+            "      \t\r\n// this is a comment and should be ignored\n// this should also be a comment even though afterwards we simply get EOF",
+        );
         let token = scanner.scan_token().unwrap();
         assert_eq!(token.token_type, TokenType::EOF);
         assert_eq!(token.line, 3);
     }
+
+    #[test]
+    fn scan_block_comments() {
+        let mut scanner = Scanner::new("1 /* a\n/* nested\n*/ comment */ + /**/ 2 /*/ */\n3");
+        let tokens = scanner.scan().unwrap();
+        let token_types: Vec<_> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::Number,
+                TokenType::Plus,
+                TokenType::Number,
+                TokenType::Number,
+                TokenType::EOF,
+            ]
+        );
+        let lines: Vec<_> = tokens.iter().map(|token| token.line).collect();
+        assert_eq!(lines, vec![1, 3, 3, 4, 4]);
+
+        // --- reported where the outermost comment opened
+        let mut scanner = Scanner::new("x = 1;\n  /* outer /* inner */\n");
+        assert_eq!(
+            scanner.scan().unwrap_err().to_string(),
+            "scanning error in line 2 at column 3: unterminated block comment"
+        );
+
+        let mut scanner = Scanner::new("/*");
+        assert!(scanner.scan().is_err());
+    }
 }
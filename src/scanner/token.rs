@@ -58,7 +58,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     //
     Minus,
@@ -66,6 +69,14 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    StarStar,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    LessLess,
+    GreaterGreater,
     Bang,
     BangEqual,
     Equal,
@@ -76,6 +87,9 @@ pub enum TokenType {
     LessEqual,
     Identifier,
     StringLiteral,
+    /// part of an interpolated string up to a `${`, followed by the tokens of the interpolated
+    /// expression and by the rest of the string, starting at the closing `}`
+    Interpolation,
     Number,
     //
     And,
@@ -105,13 +119,24 @@ impl Display for TokenType {
             TokenType::RightParen => ")",
             TokenType::LeftBrace => "{",
             TokenType::RightBrace => "}",
+            TokenType::LeftBracket => "[",
+            TokenType::RightBracket => "]",
             TokenType::Comma => ",",
+            TokenType::Colon => ":",
             TokenType::Dot => ".",
             TokenType::Minus => "-",
             TokenType::Plus => "+",
             TokenType::Semicolon => ";",
             TokenType::Slash => "/",
             TokenType::Star => "*",
+            TokenType::StarStar => "**",
+            TokenType::Percent => "%",
+            TokenType::Ampersand => "&",
+            TokenType::Pipe => "|",
+            TokenType::Caret => "^",
+            TokenType::Tilde => "~",
+            TokenType::LessLess => "<<",
+            TokenType::GreaterGreater => ">>",
             TokenType::Bang => "!",
             TokenType::BangEqual => "!=",
             TokenType::Equal => "=",
@@ -122,6 +147,7 @@ impl Display for TokenType {
             TokenType::LessEqual => "<=",
             TokenType::Identifier => "IDENT",
             TokenType::StringLiteral => "LITERAL",
+            TokenType::Interpolation => "INTERPOLATION",
             TokenType::Number => "NUMBER",
             TokenType::And => "AND",
             TokenType::Class => "CLASS",
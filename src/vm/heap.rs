@@ -0,0 +1,843 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    mem,
+};
+
+use ordered_float::OrderedFloat;
+
+use crate::chunks::value::{ObjRef, Value};
+use crate::errors::{RuntimeError, RuntimeErrorKind};
+
+/// Object allocated at runtime, referenced from values through its `ObjRef`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Object {
+    String(String),
+    /// elements of a list, which grows and shrinks in place - each change in its length is
+    /// accounted for by the code making it, `ELEMENT_SIZE` bytes per element
+    List(Vec<Value>),
+    /// entries of a map, accounted for like the elements of a list, `MapKey::size` bytes per entry
+    Map(HashMap<MapKey, Value>),
+}
+
+/// bytes accounted for each element of a list
+pub const ELEMENT_SIZE: usize = mem::size_of::<Value>();
+
+/// Key of an entry of a map. Keys are hashed by contents, so that values equal under
+/// `Heap::equals` find the same entry: a string literal and an allocated string with the same
+/// characters are the same key, and so are `0` and `-0`.
+///
+/// Keys are ordered nil, booleans, numbers and strings, which is the order maps are iterated in
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(OrderedFloat<f64>),
+    String(String),
+}
+
+impl MapKey {
+    /// bytes accounted for an entry of a map with this key
+    pub fn size(&self) -> usize {
+        mem::size_of::<(MapKey, Value)>()
+            + match self {
+                MapKey::String(s) => s.capacity(),
+                _ => 0,
+            }
+    }
+}
+
+impl Display for MapKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapKey::Nil => write!(f, "nil"),
+            MapKey::Bool(b) => write!(f, "{}", b),
+            MapKey::Number(n) => write!(f, "{}", n),
+            MapKey::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+/// entries of a map in iteration order, see `MapKey`
+pub fn sorted_entries(entries: &HashMap<MapKey, Value>) -> Vec<(&MapKey, &Value)> {
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_unstable_by_key(|&(key, _)| key);
+    entries
+}
+
+impl Object {
+    /// bytes accounted for the object
+    fn size(&self) -> usize {
+        mem::size_of::<Object>()
+            + match self {
+                Object::String(s) => s.capacity(),
+                Object::List(elements) => elements.len() * ELEMENT_SIZE,
+                Object::Map(entries) => entries.keys().map(MapKey::size).sum(),
+            }
+    }
+
+    /// calls mark with each value referenced by the object, which a collection has to keep alive
+    /// along with it
+    pub fn trace(&self, mark: impl FnMut(&Value)) {
+        match self {
+            Object::String(_) => {}
+            Object::List(elements) => elements.iter().for_each(mark),
+            // --- keys are held by the map itself and never reference objects
+            Object::Map(entries) => entries.values().for_each(mark),
+        }
+    }
+}
+
+/// Values from which a collection finds the objects still in use: those in the stack and the
+/// globals of the VM, and those held by the operation allocating
+#[derive(Debug, Clone, Copy)]
+pub struct Roots<'r> {
+    pub stack: &'r [Value],
+    pub globals: &'r HashMap<String, Value>,
+    /// values the operation allocating holds outside of the VM, e.g. the elements of a list it
+    /// is building
+    pub held: &'r [Value],
+}
+
+impl<'r> Roots<'r> {
+    pub fn new(stack: &'r [Value], globals: &'r HashMap<String, Value>) -> Self {
+        Self {
+            stack,
+            globals,
+            held: &[],
+        }
+    }
+
+    /// the same roots, along with held
+    pub fn holding(self, held: &'r [Value]) -> Self {
+        Self { held, ..self }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.stack
+            .iter()
+            .chain(self.globals.values())
+            .chain(self.held)
+    }
+}
+
+#[cfg(test)]
+impl Roots<'static> {
+    /// roots of a heap used without a VM: nothing is reachable but the values given to the heap
+    /// operations themselves
+    pub fn empty() -> Self {
+        static GLOBALS: std::sync::LazyLock<HashMap<String, Value>> =
+            std::sync::LazyLock::new(HashMap::new);
+        Roots::new(&[], &GLOBALS)
+    }
+}
+
+/// Objects allocated by a VM, with the accounting of their bytes, bounded by an optional limit.
+///
+/// Objects report their size through `allocate` and `free`: when an allocation would go over the
+/// limit, a collection is attempted first, and the allocation fails with an out of memory runtime
+/// error only if the heap is still over the limit afterwards.
+///
+/// Collections mark the objects reachable from the roots they are given, see `Roots`, and free
+/// the others. Slots of freed objects are reused by later allocations, so an `ObjRef` stays valid
+/// as long as its object is reachable
+#[derive(Debug, Clone, Default)]
+pub struct Heap {
+    /// maximum number of bytes allocated at once, without limit if None
+    limit: Option<usize>,
+    bytes: usize,
+    peak: usize,
+    /// objects by the index of their `ObjRef`, None for the slots of freed objects
+    objects: Vec<Option<Object>>,
+    /// slots of freed objects, to be reused
+    free_slots: Vec<u32>,
+}
+
+impl Heap {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            bytes: 0,
+            peak: 0,
+            objects: vec![],
+            free_slots: vec![],
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// bytes currently allocated
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// maximum number of bytes allocated at once since the heap was created or reset
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// number of objects alive
+    pub fn objects(&self) -> usize {
+        self.objects.len() - self.free_slots.len()
+    }
+
+    /// Accounts for an allocation of size bytes. If it would go over the limit, collect is called
+    /// to free unreachable objects, reporting their size with `free`
+    pub fn allocate(
+        &mut self,
+        size: usize,
+        collect: impl FnOnce(&mut Self),
+    ) -> Result<(), RuntimeError> {
+        if !self.fits(size) {
+            collect(self);
+            if !self.fits(size) {
+                return Err(self.out_of_memory(size));
+            }
+        }
+
+        self.bytes += size;
+        self.peak = self.peak.max(self.bytes);
+        Ok(())
+    }
+
+    /// accounts for size bytes being freed
+    pub fn free(&mut self, size: usize) {
+        self.bytes = self.bytes.saturating_sub(size);
+    }
+
+    /// forgets every allocation and object, along with the peak
+    pub fn reset(&mut self) {
+        self.bytes = 0;
+        self.peak = 0;
+        self.objects.clear();
+        self.free_slots.clear();
+    }
+
+    /// Frees the objects that cannot be reached from roots, following the references of each
+    /// object reached (see `Object::trace`)
+    pub fn collect<'r>(&mut self, roots: impl IntoIterator<Item = &'r Value>) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<Value> = roots.into_iter().copied().collect();
+
+        while let Some(value) = pending.pop() {
+            let (Value::String(obj) | Value::List(obj) | Value::Map(obj)) = value else {
+                continue;
+            };
+            if !mem::replace(&mut marked[obj.0 as usize], true) {
+                self.get(obj).trace(|value| pending.push(*value));
+            }
+        }
+
+        for (idx, slot) in self.objects.iter_mut().enumerate() {
+            if marked[idx] {
+                continue;
+            }
+            if let Some(object) = slot.take() {
+                self.bytes = self.bytes.saturating_sub(object.size());
+                self.free_slots.push(idx as u32);
+            }
+        }
+    }
+
+    /// allocates object, which lives as long as it is reachable from the roots of later
+    /// collections. A collection run to make room for it keeps the objects object references
+    pub fn alloc(&mut self, object: Object, roots: Roots) -> Result<ObjRef, RuntimeError> {
+        self.allocate(object.size(), |heap| {
+            let mut references = vec![];
+            object.trace(|value| references.push(*value));
+            heap.collect(roots.values().chain(&references));
+        })?;
+
+        match self.free_slots.pop() {
+            Some(idx) => {
+                self.objects[idx as usize] = Some(object);
+                Ok(ObjRef(idx))
+            }
+            None => {
+                self.objects.push(Some(object));
+                Ok(ObjRef((self.objects.len() - 1) as u32))
+            }
+        }
+    }
+
+    /// allocates a string, returning the value referencing it
+    pub fn alloc_string(&mut self, s: String, roots: Roots) -> Result<Value, RuntimeError> {
+        Ok(Value::String(self.alloc(Object::String(s), roots)?))
+    }
+
+    /// allocates a list, returning the value referencing it
+    pub fn alloc_list(
+        &mut self,
+        elements: Vec<Value>,
+        roots: Roots,
+    ) -> Result<Value, RuntimeError> {
+        Ok(Value::List(self.alloc(Object::List(elements), roots)?))
+    }
+
+    /// Allocates a map with the entries of pairs, holding each key followed by its value. Later
+    /// entries replace earlier ones with the same key
+    pub fn alloc_map(&mut self, pairs: &[Value], roots: Roots) -> Result<Value, RuntimeError> {
+        let mut entries = HashMap::with_capacity(pairs.len() / 2);
+        for pair in pairs.chunks_exact(2) {
+            entries.insert(self.key(&pair[0])?, pair[1]);
+        }
+        Ok(Value::Map(self.alloc(Object::Map(entries), roots)?))
+    }
+
+    /// object referenced by obj, which must have been allocated by this heap and still be alive
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        self.objects[obj.0 as usize]
+            .as_ref()
+            .expect("object was collected")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        self.objects[obj.0 as usize]
+            .as_mut()
+            .expect("object was collected")
+    }
+
+    /// contents of value, if it is a string literal or a string allocated by this heap
+    pub fn str<'h>(&'h self, value: &Value) -> Option<&'h str> {
+        match value {
+            Value::Literal(s) => Some(s),
+            Value::String(obj) => match self.get(*obj) {
+                Object::String(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// elements of value, if it is a list allocated by this heap
+    pub fn list(&self, value: &Value) -> Option<&Vec<Value>> {
+        match value {
+            Value::List(obj) => match self.get(*obj) {
+                Object::List(elements) => Some(elements),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn list_mut(&mut self, value: &Value) -> Option<&mut Vec<Value>> {
+        match value {
+            Value::List(obj) => match self.get_mut(*obj) {
+                Object::List(elements) => Some(elements),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// entries of value, if it is a map allocated by this heap
+    pub fn map(&self, value: &Value) -> Option<&HashMap<MapKey, Value>> {
+        match value {
+            Value::Map(obj) => match self.get(*obj) {
+                Object::Map(entries) => Some(entries),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn map_mut(&mut self, value: &Value) -> Option<&mut HashMap<MapKey, Value>> {
+        match value {
+            Value::Map(obj) => match self.get_mut(*obj) {
+                Object::Map(entries) => Some(entries),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// key of the map entries for value, which must be a string, a number, a boolean or nil
+    pub fn key(&self, value: &Value) -> Result<MapKey, RuntimeError> {
+        match value {
+            Value::Empty => Ok(MapKey::Nil),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            // --- zeros are hashed alike, whatever their sign
+            Value::Number(n) => Ok(MapKey::Number(*n)),
+            _ => match self.str(value) {
+                Some(s) => Ok(MapKey::String(s.to_string())),
+                None => Err(index_error(
+                    RuntimeErrorKind::Type,
+                    format!("'{}' cannot be used as a map key", value.value_type()),
+                )),
+            },
+        }
+    }
+
+    /// value of key, as stored in a map - string keys are allocated as new strings
+    pub fn key_value(&mut self, key: &MapKey, roots: Roots) -> Result<Value, RuntimeError> {
+        match key {
+            MapKey::Nil => Ok(Value::Empty),
+            MapKey::Bool(b) => Ok(Value::Bool(*b)),
+            MapKey::Number(n) => Ok(Value::Number(*n)),
+            MapKey::String(s) => self.alloc_string(s.clone(), roots),
+        }
+    }
+
+    /// element at index of the list value, or value of the entry with the key index of the map
+    /// value
+    pub fn get_index(&self, value: &Value, index: &Value) -> Result<Value, RuntimeError> {
+        if let Some(entries) = self.map(value) {
+            let key = self.key(index)?;
+            return entries.get(&key).copied().ok_or_else(|| missing_key(&key));
+        }
+
+        let elements = self.list(value).ok_or_else(|| not_indexable(value))?;
+        Ok(elements[element_index(index, elements.len())?])
+    }
+
+    /// replaces the element at index of the list value with element, or sets the entry with the
+    /// key index of the map value to element
+    pub fn set_index(
+        &mut self,
+        value: &Value,
+        index: &Value,
+        element: Value,
+        roots: Roots,
+    ) -> Result<(), RuntimeError> {
+        if self.map(value).is_some() {
+            let key = self.key(index)?;
+            let entries = self.map_mut(value).expect("value is a map");
+            if let Some(entry) = entries.get_mut(&key) {
+                *entry = element;
+                return Ok(());
+            }
+
+            // --- the map and the element may have been taken off the stack already
+            self.allocate(key.size(), |heap| {
+                heap.collect(roots.values().chain([value, &element]))
+            })?;
+            self.map_mut(value)
+                .expect("value is a map")
+                .insert(key, element);
+            return Ok(());
+        }
+
+        let elements = self.list_mut(value).ok_or_else(|| not_indexable(value))?;
+        let idx = element_index(index, elements.len())?;
+        elements[idx] = element;
+        Ok(())
+    }
+
+    /// Compares the contents of a and b: strings are equal when their characters are, whether
+    /// literals or allocated, lists when their elements are and maps when they hold the same keys
+    /// with equal values. Other values are equal when they are the same value
+    pub fn equals(&self, a: &Value, b: &Value) -> bool {
+        self.equals_visiting(a, b, &mut HashSet::new())
+    }
+
+    /// lists and maps are compared once per pair, as cyclic ones would be compared forever
+    /// otherwise - a pair being compared is assumed equal, and any difference shows up elsewhere
+    fn equals_visiting(
+        &self,
+        a: &Value,
+        b: &Value,
+        visited: &mut HashSet<(ObjRef, ObjRef)>,
+    ) -> bool {
+        match (a, b) {
+            (Value::List(l), Value::List(r)) | (Value::Map(l), Value::Map(r)) => {
+                if l == r || !visited.insert((*l, *r)) {
+                    return true;
+                }
+                match (self.get(*l), self.get(*r)) {
+                    (Object::List(l), Object::List(r)) => {
+                        l.len() == r.len()
+                            && l.iter()
+                                .zip(r)
+                                .all(|(l, r)| self.equals_visiting(l, r, visited))
+                    }
+                    (Object::Map(l), Object::Map(r)) => {
+                        l.len() == r.len()
+                            && l.iter().all(|(key, l)| {
+                                r.get(key)
+                                    .is_some_and(|r| self.equals_visiting(l, r, visited))
+                            })
+                    }
+                    _ => false,
+                }
+            }
+            _ => match (self.str(a), self.str(b)) {
+                (Some(l), Some(r)) => l == r,
+                _ => a == b,
+            },
+        }
+    }
+
+    /// formats value, with the contents of the objects it references. Lists are written as
+    /// `[1, "rox"]` and maps as `{"name": "rox", 1: nil}`, with their strings quoted, and lists or
+    /// maps containing themselves as `[...]` or `{...}`
+    pub fn display(&self, value: &Value) -> String {
+        match self.str(value) {
+            Some(s) => s.to_string(),
+            None => {
+                let mut output = String::new();
+                self.display_element(value, &mut vec![], &mut output);
+                output
+            }
+        }
+    }
+
+    /// writes value as an element of a list, tracking the lists and maps being written in path
+    fn display_element(&self, value: &Value, path: &mut Vec<ObjRef>, output: &mut String) {
+        match value {
+            Value::List(obj) if path.contains(obj) => output.push_str("[...]"),
+            Value::Map(obj) if path.contains(obj) => output.push_str("{...}"),
+            Value::List(obj) => {
+                let Some(elements) = self.list(value) else {
+                    output.push_str(&value.to_string());
+                    return;
+                };
+                path.push(*obj);
+                output.push('[');
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        output.push_str(", ");
+                    }
+                    self.display_element(element, path, output);
+                }
+                output.push(']');
+                path.pop();
+            }
+            Value::Map(obj) => {
+                let Some(entries) = self.map(value) else {
+                    output.push_str(&value.to_string());
+                    return;
+                };
+                path.push(*obj);
+                output.push('{');
+                for (i, (key, value)) in sorted_entries(entries).into_iter().enumerate() {
+                    if i > 0 {
+                        output.push_str(", ");
+                    }
+                    output.push_str(&format!("{}: ", key));
+                    self.display_element(value, path, output);
+                }
+                output.push('}');
+                path.pop();
+            }
+            Value::Empty => output.push_str("nil"),
+            _ => match self.str(value) {
+                Some(s) => output.push_str(&format!("{:?}", s)),
+                None => output.push_str(&value.to_string()),
+            },
+        }
+    }
+
+    fn fits(&self, size: usize) -> bool {
+        self.limit
+            .is_none_or(|limit| self.bytes.saturating_add(size) <= limit)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn out_of_memory(&self, size: usize) -> RuntimeError {
+        RuntimeError::new(
+            RuntimeErrorKind::OutOfMemory,
+            format!(
+                "Out of memory: allocating {} bytes with {} of {} bytes in use",
+                size,
+                self.bytes,
+                self.limit.unwrap_or(usize::MAX)
+            ),
+        )
+    }
+}
+
+/// Index of the element at index in a list of len elements. Indexes are integers counting from 0,
+/// negative indexes are not allowed
+fn element_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    match index {
+        Value::Number(n) if n.fract() != 0.0 => Err(index_error(
+            RuntimeErrorKind::Index,
+            format!("list index must be an integer, got {}", n),
+        )),
+        Value::Number(n) if n.0 < 0.0 => Err(index_error(
+            RuntimeErrorKind::Index,
+            format!("negative list index {}", n),
+        )),
+        Value::Number(n) if n.0 < len as f64 => Ok(n.0 as usize),
+        Value::Number(n) => Err(index_error(
+            RuntimeErrorKind::Index,
+            format!("index {} is out of bounds for a list of length {}", n, len),
+        )),
+        _ => Err(index_error(
+            RuntimeErrorKind::Type,
+            format!("list index must be a number, got '{}'", index.value_type()),
+        )),
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn index_error(kind: RuntimeErrorKind, msg: String) -> RuntimeError {
+    RuntimeError::new(kind, msg)
+}
+
+#[cold]
+#[inline(never)]
+fn missing_key(key: &MapKey) -> RuntimeError {
+    RuntimeError::new(
+        RuntimeErrorKind::Key,
+        format!("key {} not found in map", key),
+    )
+}
+
+#[cold]
+#[inline(never)]
+fn not_indexable(value: &Value) -> RuntimeError {
+    RuntimeError::new(
+        RuntimeErrorKind::Type,
+        format!("'{}' cannot be indexed", value.value_type()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_usage() {
+        let mut heap = Heap::new(None);
+        heap.allocate(10, |_| panic!("no collection expected"))
+            .unwrap();
+        heap.allocate(20, |_| panic!("no collection expected"))
+            .unwrap();
+        heap.free(25);
+
+        assert_eq!(heap.bytes(), 5);
+        assert_eq!(heap.peak(), 30);
+
+        heap.reset();
+        assert_eq!((heap.bytes(), heap.peak()), (0, 0));
+    }
+
+    #[test]
+    fn alloc_strings() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let value = heap.alloc_string(String::from("rox"), roots).unwrap();
+
+        assert_eq!(heap.str(&value), Some("rox"));
+        assert_eq!(heap.display(&value), "rox");
+        assert_eq!(heap.str(&Value::Literal("lox")), Some("lox"));
+        assert_eq!(heap.str(&Value::Empty), None);
+        assert!(heap.bytes() >= "rox".len());
+
+        // --- over the limit, the string is not allocated
+        let mut heap = Heap::new(Some(8));
+        let err = heap.alloc_string(String::from("rox"), roots).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfMemory);
+        assert_eq!(heap.bytes(), 0);
+    }
+
+    fn number(n: f64) -> Value {
+        Value::Number(ordered_float::OrderedFloat(n))
+    }
+
+    #[test]
+    fn alloc_lists() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let rox = heap.alloc_string(String::from("rox"), roots).unwrap();
+        let list = heap.alloc_list(vec![number(1.0), rox], roots).unwrap();
+
+        assert_eq!(heap.list(&list).unwrap().len(), 2);
+        let mut references = vec![];
+        heap.get(ObjRef(1)).trace(|value| references.push(*value));
+        assert_eq!(references, vec![number(1.0), rox]);
+        assert_eq!(heap.display(&list), "[1, \"rox\"]");
+        assert!(heap.bytes() >= 2 * ELEMENT_SIZE);
+
+        heap.set_index(&list, &number(0.0), Value::Empty, roots)
+            .unwrap();
+        assert_eq!(heap.get_index(&list, &number(0.0)).unwrap(), Value::Empty);
+        assert_eq!(
+            heap.get_index(&list, &number(-1.0)).unwrap_err().kind,
+            RuntimeErrorKind::Index
+        );
+        assert_eq!(
+            heap.set_index(&list, &number(2.0), Value::Empty, roots)
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::Index
+        );
+        assert_eq!(
+            heap.get_index(&rox, &number(0.0)).unwrap_err().kind,
+            RuntimeErrorKind::Type
+        );
+    }
+
+    #[test]
+    fn list_equality() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let rox = heap.alloc_string(String::from("rox"), roots).unwrap();
+        let inner = heap.alloc_list(vec![rox], roots).unwrap();
+        let a = heap.alloc_list(vec![number(1.0), inner], roots).unwrap();
+        let inner = heap.alloc_list(vec![Value::Literal("rox")], roots).unwrap();
+        let b = heap.alloc_list(vec![number(1.0), inner], roots).unwrap();
+        let c = heap.alloc_list(vec![number(1.0)], roots).unwrap();
+
+        assert!(heap.equals(&a, &b));
+        assert!(heap.equals(&a, &a));
+        assert!(!heap.equals(&a, &c));
+        assert!(!heap.equals(&c, &number(1.0)));
+        assert!(heap.equals(&rox, &Value::Literal("rox")));
+        assert!(heap.equals(&number(1.0), &number(1.0)));
+    }
+
+    #[test]
+    fn cyclic_lists() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let a = heap.alloc_list(vec![number(1.0)], roots).unwrap();
+        let b = heap.alloc_list(vec![number(1.0)], roots).unwrap();
+        heap.list_mut(&a).unwrap().push(a);
+        heap.list_mut(&b).unwrap().push(b);
+
+        assert_eq!(heap.display(&a), "[1, [...]]");
+        assert!(heap.equals(&a, &b));
+
+        heap.set_index(&b, &number(0.0), number(2.0), roots)
+            .unwrap();
+        assert!(!heap.equals(&a, &b));
+    }
+
+    #[test]
+    fn alloc_maps() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let rox = heap.alloc_string(String::from("rox"), roots).unwrap();
+        let map = heap
+            .alloc_map(&[rox, number(1.0), number(0.0), Value::Bool(true)], roots)
+            .unwrap();
+        let bytes = heap.bytes();
+
+        // --- keys are hashed by contents
+        assert_eq!(
+            heap.get_index(&map, &Value::Literal("rox")).unwrap(),
+            number(1.0)
+        );
+        assert_eq!(
+            heap.get_index(&map, &number(-0.0)).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            heap.get_index(&map, &Value::Empty).unwrap_err().kind,
+            RuntimeErrorKind::Key
+        );
+
+        heap.set_index(&map, &Value::Empty, rox, roots).unwrap();
+        heap.set_index(&map, &Value::Literal("rox"), number(2.0), roots)
+            .unwrap();
+        assert_eq!(heap.map(&map).unwrap().len(), 3);
+        assert_eq!(heap.bytes(), bytes + MapKey::Nil.size());
+        assert_eq!(heap.display(&map), "{nil: \"rox\", 0: true, \"rox\": 2}");
+
+        let list = heap.alloc_list(vec![], roots).unwrap();
+        let err = heap.set_index(&map, &list, number(1.0), roots).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.msg, "'list' cannot be used as a map key");
+    }
+
+    #[test]
+    fn map_equality() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let a = heap
+            .alloc_map(
+                &[Value::Literal("k"), number(1.0), number(2.0), Value::Empty],
+                roots,
+            )
+            .unwrap();
+        let k = heap.alloc_string(String::from("k"), roots).unwrap();
+        let b = heap
+            .alloc_map(&[number(2.0), Value::Empty, k, number(1.0)], roots)
+            .unwrap();
+        let c = heap.alloc_map(&[number(2.0), Value::Empty], roots).unwrap();
+
+        assert!(heap.equals(&a, &b));
+        assert!(!heap.equals(&a, &c));
+        assert!(!heap.equals(&c, &a));
+
+        heap.set_index(&a, &number(3.0), a, roots).unwrap();
+        heap.set_index(&b, &number(3.0), b, roots).unwrap();
+        assert!(heap.equals(&a, &b));
+        assert_eq!(heap.display(&c), "{2: nil}");
+        assert_eq!(heap.display(&a), "{2: nil, 3: {...}, \"k\": 1}");
+    }
+
+    #[test]
+    fn collect_unreachable_objects() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let rox = heap.alloc_string(String::from("rox"), roots).unwrap();
+        let list = heap.alloc_list(vec![rox], roots).unwrap();
+        let lox = heap.alloc_string(String::from("lox"), roots).unwrap();
+        let cycle = heap.alloc_list(vec![], roots).unwrap();
+        heap.list_mut(&cycle).unwrap().push(cycle);
+        let bytes = heap.bytes();
+
+        // --- objects are reachable through the lists referencing them, and cycles alone do not
+        // keep objects alive
+        heap.collect(&[list]);
+        assert_eq!(heap.objects(), 2);
+        assert_eq!(heap.display(&list), "[\"rox\"]");
+        assert!(heap.bytes() < bytes);
+
+        // --- the slots of freed objects are reused
+        let lox_again = heap.alloc_string(String::from("lox"), roots).unwrap();
+        let (Value::String(lox_again), Value::String(lox), Value::List(cycle)) =
+            (lox_again, lox, cycle)
+        else {
+            panic!("strings and lists are allocated");
+        };
+        assert!(lox_again == lox || lox_again == cycle);
+        assert_eq!(heap.objects(), 3);
+
+        heap.collect(&[]);
+        assert_eq!((heap.objects(), heap.bytes()), (0, 0));
+    }
+
+    #[test]
+    fn collect_while_setting_index() {
+        let garbage = "x".repeat(64);
+        let limit = Object::String(garbage.clone()).size()
+            + Object::Map(HashMap::new()).size()
+            + Object::List(vec![]).size();
+        let mut heap = Heap::new(Some(limit));
+        let roots = Roots::empty();
+        heap.alloc_string(garbage, roots).unwrap();
+        let map = heap.alloc_map(&[], roots).unwrap();
+        let element = heap.alloc_list(vec![], roots.holding(&[map])).unwrap();
+
+        // --- the new entry only fits once the string is collected, while the map and the
+        // element are only held by the assignment
+        heap.set_index(&map, &Value::Bool(true), element, roots)
+            .unwrap();
+        assert_eq!(heap.objects(), 2);
+        assert_eq!(heap.display(&map), "{true: []}");
+    }
+
+    #[test]
+    fn collect_before_failing() {
+        let mut heap = Heap::new(Some(32));
+        heap.allocate(24, |_| {}).unwrap();
+
+        // --- the collection frees enough for the allocation to fit
+        heap.allocate(16, |heap| heap.free(16)).unwrap();
+        assert_eq!(heap.bytes(), 24);
+        assert_eq!(heap.peak(), 24);
+
+        // --- the collection frees nothing
+        let err = heap.allocate(16, |_| {}).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfMemory);
+        assert_eq!(heap.bytes(), 24);
+    }
+}
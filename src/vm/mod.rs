@@ -1,2 +1,7 @@
+pub mod heap;
+pub mod natives;
+pub mod profiler;
 pub mod stack;
+pub mod trace;
+pub mod verifier;
 pub mod vm;
@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ordered_float::OrderedFloat;
+
+use crate::chunks::value::Value;
+use crate::errors::{RuntimeError, RuntimeErrorKind};
+
+use super::heap::{ELEMENT_SIZE, Heap, MapKey, Roots, sorted_entries};
+
+/// Implementation of a native function, called with exactly `arity` arguments. Roots are those of
+/// the VM calling it, which hold the arguments, for the collections its allocations may run
+pub type NativeFn = fn(&mut Heap, Roots, &[Value]) -> Result<Value, RuntimeError>;
+
+/// Function implemented in Rust, callable from scripts
+#[derive(Debug)]
+pub struct Native {
+    pub name: &'static str,
+    /// number of arguments the function takes - the VM checks it before calling function
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// natives are identified by their name, as function pointers may not be unique
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Native {}
+
+/// Natives working on lists and maps, which are always defined as globals: scripts have no other
+/// way to grow or query their collections
+pub static CORE: &[Native] = &[
+    Native {
+        name: "len",
+        arity: 1,
+        function: len,
+    },
+    Native {
+        name: "push",
+        arity: 2,
+        function: push,
+    },
+    Native {
+        name: "pop",
+        arity: 1,
+        function: pop,
+    },
+    Native {
+        name: "insert",
+        arity: 3,
+        function: insert,
+    },
+    Native {
+        name: "remove",
+        arity: 2,
+        function: remove,
+    },
+    Native {
+        name: "keys",
+        arity: 1,
+        function: |heap, roots, args| entries(heap, roots, args, "keys", |key, _| key),
+    },
+    Native {
+        name: "values",
+        arity: 1,
+        function: |heap, roots, args| entries(heap, roots, args, "values", |_, value| value),
+    },
+    Native {
+        name: "has",
+        arity: 2,
+        function: has,
+    },
+];
+
+/// Natives of the standard library, defined as globals in every VM unless `VMConfig::stdlib` is
+/// disabled
+pub static STDLIB: &[Native] = &[
+    Native {
+        name: "clock",
+        arity: 0,
+        function: clock,
+    },
+    Native {
+        name: "sqrt",
+        arity: 1,
+        function: |_, _, args| math(args, "sqrt", f64::sqrt),
+    },
+    Native {
+        name: "floor",
+        arity: 1,
+        function: |_, _, args| math(args, "floor", f64::floor),
+    },
+    Native {
+        name: "ceil",
+        arity: 1,
+        function: |_, _, args| math(args, "ceil", f64::ceil),
+    },
+    Native {
+        name: "abs",
+        arity: 1,
+        function: |_, _, args| math(args, "abs", f64::abs),
+    },
+    Native {
+        name: "min",
+        arity: 2,
+        function: |_, _, args| math2(args, "min", f64::min),
+    },
+    Native {
+        name: "max",
+        arity: 2,
+        function: |_, _, args| math2(args, "max", f64::max),
+    },
+    Native {
+        name: "pow",
+        arity: 2,
+        function: |_, _, args| math2(args, "pow", f64::powf),
+    },
+    Native {
+        name: "substr",
+        arity: 3,
+        function: substr,
+    },
+    Native {
+        name: "upper",
+        arity: 1,
+        function: |heap, roots, args| {
+            let s = string(heap, args, "upper", 0)?.to_uppercase();
+            heap.alloc_string(s, roots)
+        },
+    },
+    Native {
+        name: "lower",
+        arity: 1,
+        function: |heap, roots, args| {
+            let s = string(heap, args, "lower", 0)?.to_lowercase();
+            heap.alloc_string(s, roots)
+        },
+    },
+    Native {
+        name: "str",
+        arity: 1,
+        function: str,
+    },
+    Native {
+        name: "num",
+        arity: 1,
+        function: num,
+    },
+    Native {
+        name: "type",
+        arity: 1,
+        function: type_of,
+    },
+];
+
+/// error of a native called with an invalid argument
+#[cold]
+#[inline(never)]
+fn invalid_argument(kind: RuntimeErrorKind, name: &str, msg: String) -> RuntimeError {
+    RuntimeError::new(kind, format!("'{}': {}", name, msg))
+}
+
+/// argument at idx, which must be a number
+fn number(args: &[Value], name: &str, idx: usize) -> Result<f64, RuntimeError> {
+    match args[idx] {
+        Value::Number(n) => Ok(n.0),
+        v => Err(invalid_argument(
+            RuntimeErrorKind::Type,
+            name,
+            format!(
+                "expected a number as argument {}, got '{}'",
+                idx + 1,
+                v.value_type()
+            ),
+        )),
+    }
+}
+
+/// argument at idx, which must be a non negative integer
+fn index(args: &[Value], name: &str, idx: usize) -> Result<usize, RuntimeError> {
+    let n = number(args, name, idx)?;
+    match n >= 0.0 && n.fract() == 0.0 {
+        true => Ok(n as usize),
+        false => Err(invalid_argument(
+            RuntimeErrorKind::InvalidArgument,
+            name,
+            format!(
+                "expected a non negative integer as argument {}, got {}",
+                idx + 1,
+                n
+            ),
+        )),
+    }
+}
+
+/// argument at idx, which must be a string
+fn string<'h>(
+    heap: &'h Heap,
+    args: &[Value],
+    name: &str,
+    idx: usize,
+) -> Result<&'h str, RuntimeError> {
+    heap.str(&args[idx]).ok_or_else(|| {
+        invalid_argument(
+            RuntimeErrorKind::Type,
+            name,
+            format!(
+                "expected a string as argument {}, got '{}'",
+                idx + 1,
+                args[idx].value_type()
+            ),
+        )
+    })
+}
+
+/// elements of the argument at idx, which must be a list
+fn list<'h>(
+    heap: &'h mut Heap,
+    args: &[Value],
+    name: &str,
+    idx: usize,
+) -> Result<&'h mut Vec<Value>, RuntimeError> {
+    heap.list_mut(&args[idx]).ok_or_else(|| {
+        invalid_argument(
+            RuntimeErrorKind::Type,
+            name,
+            format!(
+                "expected a list as argument {}, got '{}'",
+                idx + 1,
+                args[idx].value_type()
+            ),
+        )
+    })
+}
+
+/// argument at idx, which must be a map
+fn map<'h>(
+    heap: &'h Heap,
+    args: &[Value],
+    name: &str,
+    idx: usize,
+) -> Result<&'h HashMap<MapKey, Value>, RuntimeError> {
+    heap.map(&args[idx]).ok_or_else(|| {
+        invalid_argument(
+            RuntimeErrorKind::Type,
+            name,
+            format!(
+                "expected a map as argument {}, got '{}'",
+                idx + 1,
+                args[idx].value_type()
+            ),
+        )
+    })
+}
+
+fn math(args: &[Value], name: &str, f: fn(f64) -> f64) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(OrderedFloat(f(number(args, name, 0)?))))
+}
+
+fn math2(args: &[Value], name: &str, f: fn(f64, f64) -> f64) -> Result<Value, RuntimeError> {
+    let (l, r) = (number(args, name, 0)?, number(args, name, 1)?);
+    Ok(Value::Number(OrderedFloat(f(l, r))))
+}
+
+/// seconds since the unix epoch
+fn clock(_heap: &mut Heap, _roots: Roots, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Value::Number(OrderedFloat(now.as_secs_f64())))
+}
+
+/// number of characters of a string, of elements of a list or of entries of a map
+fn len(heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match (heap.list(&args[0]), heap.map(&args[0])) {
+        (Some(elements), _) => elements.len(),
+        (_, Some(entries)) => entries.len(),
+        _ => string(heap, args, "len", 0)?.chars().count(),
+    };
+    Ok(Value::Number(OrderedFloat(len as f64)))
+}
+
+/// `substr(s, start, length)`: length characters of s from the character at start
+fn substr(heap: &mut Heap, roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let s = string(heap, args, "substr", 0)?;
+    let start = index(args, "substr", 1)?;
+    let length = index(args, "substr", 2)?;
+
+    let chars = s.chars().count();
+    if start.saturating_add(length) > chars {
+        return Err(invalid_argument(
+            RuntimeErrorKind::InvalidArgument,
+            "substr",
+            format!(
+                "range {}..{} is out of bounds for a string of length {}",
+                start,
+                start.saturating_add(length),
+                chars
+            ),
+        ));
+    }
+
+    let sub = s.chars().skip(start).take(length).collect();
+    heap.alloc_string(sub, roots)
+}
+
+/// converts any value to a string
+fn str(heap: &mut Heap, roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Literal(_) | Value::String(_) => Ok(args[0]),
+        Value::Empty => Ok(Value::Literal("nil")),
+        v => {
+            let s = heap.display(&v);
+            heap.alloc_string(s, roots)
+        }
+    }
+}
+
+/// converts a number, or a string holding one, to a number
+fn num(heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    if let Value::Number(_) = args[0] {
+        return Ok(args[0]);
+    }
+
+    let s = string(heap, args, "num", 0)?;
+    s.trim()
+        .parse()
+        .map(|n| Value::Number(OrderedFloat(n)))
+        .map_err(|_| {
+            invalid_argument(
+                RuntimeErrorKind::InvalidArgument,
+                "num",
+                format!("cannot convert \"{}\" to a number", s),
+            )
+        })
+}
+
+/// name of the type of a value
+fn type_of(_heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = match args[0] {
+        Value::Number(_) => "number",
+        Value::Bool(_) => "boolean",
+        Value::Literal(_) | Value::String(_) => "string",
+        Value::List(_) => "list",
+        Value::Map(_) => "map",
+        Value::Native(_) => "function",
+        Value::Empty => "nil",
+    };
+    Ok(Value::Literal(name))
+}
+
+/// `push(list, value)`: appends value to the end of list
+fn push(heap: &mut Heap, roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    list(heap, args, "push", 0)?;
+    heap.allocate(ELEMENT_SIZE, |heap| heap.collect(roots.values()))?;
+    list(heap, args, "push", 0)?.push(args[1]);
+    Ok(Value::Empty)
+}
+
+/// `pop(list)`: removes the last element of list and returns it
+fn pop(heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let element = list(heap, args, "pop", 0)?.pop().ok_or_else(|| {
+        invalid_argument(
+            RuntimeErrorKind::InvalidArgument,
+            "pop",
+            String::from("cannot pop from an empty list"),
+        )
+    })?;
+    heap.free(ELEMENT_SIZE);
+    Ok(element)
+}
+
+/// `insert(list, index, value)`: inserts value at index, shifting the elements after it. Index
+/// may be the length of the list, to append value
+fn insert(heap: &mut Heap, roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let idx = index(args, "insert", 1)?;
+    let len = list(heap, args, "insert", 0)?.len();
+    if idx > len {
+        return Err(out_of_bounds("insert", idx, len));
+    }
+
+    heap.allocate(ELEMENT_SIZE, |heap| heap.collect(roots.values()))?;
+    list(heap, args, "insert", 0)?.insert(idx, args[2]);
+    Ok(Value::Empty)
+}
+
+/// `remove(list, index)`: removes the element at index and returns it, shifting the elements after
+/// it. `remove(map, key)`: removes the entry with key and returns its value
+fn remove(heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    if heap.map(&args[0]).is_some() {
+        let key = heap.key(&args[1])?;
+        let value = heap
+            .map_mut(&args[0])
+            .and_then(|entries| entries.remove(&key))
+            .ok_or_else(|| {
+                invalid_argument(
+                    RuntimeErrorKind::Key,
+                    "remove",
+                    format!("key {} not found in map", key),
+                )
+            })?;
+        heap.free(key.size());
+        return Ok(value);
+    }
+
+    let idx = index(args, "remove", 1)?;
+    let elements = list(heap, args, "remove", 0)?;
+    if idx >= elements.len() {
+        return Err(out_of_bounds("remove", idx, elements.len()));
+    }
+
+    let element = elements.remove(idx);
+    heap.free(ELEMENT_SIZE);
+    Ok(element)
+}
+
+/// list of the keys or values of a map, as chosen by entry, in the iteration order of the map
+fn entries(
+    heap: &mut Heap,
+    roots: Roots,
+    args: &[Value],
+    name: &str,
+    entry: fn(Value, Value) -> Value,
+) -> Result<Value, RuntimeError> {
+    let entries: Vec<_> = sorted_entries(map(heap, args, name, 0)?)
+        .into_iter()
+        .map(|(key, value)| (key.clone(), *value))
+        .collect();
+
+    let mut elements = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        // --- the keys allocated so far are only reachable from elements
+        let key = heap.key_value(&key, roots.holding(&elements))?;
+        elements.push(entry(key, value));
+    }
+    heap.alloc_list(elements, roots)
+}
+
+/// `has(map, key)`: whether map holds an entry with key
+fn has(heap: &mut Heap, _roots: Roots, args: &[Value]) -> Result<Value, RuntimeError> {
+    let entries = map(heap, args, "has", 0)?;
+    let key = heap.key(&args[1])?;
+    Ok(Value::Bool(entries.contains_key(&key)))
+}
+
+#[cold]
+#[inline(never)]
+fn out_of_bounds(name: &str, idx: usize, len: usize) -> RuntimeError {
+    invalid_argument(
+        RuntimeErrorKind::Index,
+        name,
+        format!(
+            "index {} is out of bounds for a list of length {}",
+            idx, len
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(n: f64) -> Value {
+        Value::Number(OrderedFloat(n))
+    }
+
+    /// calls the native named name with args
+    fn call(heap: &mut Heap, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        let native = CORE
+            .iter()
+            .chain(STDLIB)
+            .find(|native| native.name == name)
+            .unwrap();
+        assert_eq!(native.arity, args.len());
+        // --- the arguments are the only values on the stack of the caller
+        (native.function)(heap, Roots::new(args, &HashMap::new()), args)
+    }
+
+    fn call_str(name: &str, args: &[Value]) -> String {
+        let mut heap = Heap::new(None);
+        let value = call(&mut heap, name, args).unwrap();
+        heap.str(&value).unwrap().to_string()
+    }
+
+    fn error(name: &str, args: &[Value]) -> RuntimeErrorKind {
+        call(&mut Heap::new(None), name, args).unwrap_err().kind
+    }
+
+    #[test]
+    fn math() {
+        let mut heap = Heap::new(None);
+        let cases = [
+            ("sqrt", vec![number(16.0)], 4.0),
+            ("floor", vec![number(1.5)], 1.0),
+            ("ceil", vec![number(1.5)], 2.0),
+            ("abs", vec![number(-3.0)], 3.0),
+            ("min", vec![number(1.0), number(2.0)], 1.0),
+            ("max", vec![number(1.0), number(2.0)], 2.0),
+            ("pow", vec![number(2.0), number(10.0)], 1024.0),
+        ];
+        for (name, args, expected) in cases {
+            assert_eq!(call(&mut heap, name, &args).unwrap(), number(expected));
+        }
+
+        assert_eq!(
+            error("sqrt", &[Value::Literal("16")]),
+            RuntimeErrorKind::Type
+        );
+        assert_eq!(
+            error("pow", &[number(2.0), Value::Empty]),
+            RuntimeErrorKind::Type
+        );
+    }
+
+    #[test]
+    fn clock_is_positive() {
+        let Value::Number(now) = call(&mut Heap::new(None), "clock", &[]).unwrap() else {
+            panic!("clock should return a number");
+        };
+        assert!(now.0 > 0.0);
+    }
+
+    #[test]
+    fn strings() {
+        let mut heap = Heap::new(None);
+        assert_eq!(
+            call(&mut heap, "len", &[Value::Literal("héllo")]).unwrap(),
+            number(5.0)
+        );
+        assert_eq!(call_str("upper", &[Value::Literal("héllo")]), "HÉLLO");
+        assert_eq!(call_str("lower", &[Value::Literal("HeLLo")]), "hello");
+        assert_eq!(
+            call_str(
+                "substr",
+                &[Value::Literal("héllo"), number(1.0), number(3.0)]
+            ),
+            "éll"
+        );
+
+        // --- strings allocated at runtime are strings too
+        let upper = call(&mut heap, "upper", &[Value::Literal("rox")]).unwrap();
+        assert_eq!(call(&mut heap, "len", &[upper]).unwrap(), number(3.0));
+
+        assert_eq!(error("len", &[number(1.0)]), RuntimeErrorKind::Type);
+        assert_eq!(
+            error("substr", &[Value::Literal("rox"), number(2.0), number(2.0)]),
+            RuntimeErrorKind::InvalidArgument
+        );
+        assert_eq!(
+            error("substr", &[Value::Literal("rox"), number(0.5), number(1.0)]),
+            RuntimeErrorKind::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(call_str("str", &[number(1.5)]), "1.5");
+        assert_eq!(call_str("str", &[Value::Literal("rox")]), "rox");
+        assert_eq!(call_str("str", &[Value::Empty]), "nil");
+
+        let mut heap = Heap::new(None);
+        assert_eq!(
+            call(&mut heap, "num", &[Value::Literal(" 42.5 ")]).unwrap(),
+            number(42.5)
+        );
+        assert_eq!(call(&mut heap, "num", &[number(1.0)]).unwrap(), number(1.0));
+        assert_eq!(
+            error("num", &[Value::Literal("rox")]),
+            RuntimeErrorKind::InvalidArgument
+        );
+        assert_eq!(error("num", &[Value::Empty]), RuntimeErrorKind::Type);
+    }
+
+    #[test]
+    fn lists() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let list = heap.alloc_list(vec![number(1.0)], roots).unwrap();
+        let bytes = heap.bytes();
+
+        call(&mut heap, "push", &[list, number(3.0)]).unwrap();
+        call(&mut heap, "insert", &[list, number(1.0), number(2.0)]).unwrap();
+        assert_eq!(heap.display(&list), "[1, 2, 3]");
+        assert_eq!(call(&mut heap, "len", &[list]).unwrap(), number(3.0));
+        assert_eq!(heap.bytes(), bytes + 2 * ELEMENT_SIZE);
+
+        assert_eq!(call(&mut heap, "pop", &[list]).unwrap(), number(3.0));
+        assert_eq!(
+            call(&mut heap, "remove", &[list, number(0.0)]).unwrap(),
+            number(1.0)
+        );
+        assert_eq!(heap.display(&list), "[2]");
+        assert_eq!(heap.bytes(), bytes);
+
+        assert_eq!(
+            call(&mut heap, "insert", &[list, number(2.0), number(0.0)])
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::Index
+        );
+        assert_eq!(
+            call(&mut heap, "remove", &[list, number(1.0)])
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::Index
+        );
+        call(&mut heap, "pop", &[list]).unwrap();
+        assert_eq!(
+            call(&mut heap, "pop", &[list]).unwrap_err().kind,
+            RuntimeErrorKind::InvalidArgument
+        );
+        assert_eq!(
+            error("push", &[number(1.0), number(1.0)]),
+            RuntimeErrorKind::Type
+        );
+
+        // --- growing a list counts against the limit of the heap
+        let mut heap = Heap::new(Some(heap.bytes() + 64));
+        let list = heap.alloc_list(vec![], roots).unwrap();
+        let err = (0..64)
+            .find_map(|_| call(&mut heap, "push", &[list, number(1.0)]).err())
+            .unwrap();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn maps() {
+        let mut heap = Heap::new(None);
+        let roots = Roots::empty();
+        let map = heap
+            .alloc_map(
+                &[
+                    Value::Literal("b"),
+                    number(2.0),
+                    Value::Literal("a"),
+                    number(1.0),
+                ],
+                roots,
+            )
+            .unwrap();
+
+        let keys = call(&mut heap, "keys", &[map]).unwrap();
+        assert_eq!(heap.display(&keys), "[\"a\", \"b\"]");
+        let values = call(&mut heap, "values", &[map]).unwrap();
+        assert_eq!(heap.display(&values), "[1, 2]");
+        assert_eq!(call(&mut heap, "len", &[map]).unwrap(), number(2.0));
+
+        assert_eq!(
+            call(&mut heap, "has", &[map, Value::Literal("a")]).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            call(&mut heap, "has", &[map, number(1.0)]).unwrap(),
+            Value::Bool(false)
+        );
+
+        let bytes = heap.bytes();
+        assert_eq!(
+            call(&mut heap, "remove", &[map, Value::Literal("a")]).unwrap(),
+            number(1.0)
+        );
+        assert_eq!(
+            heap.bytes(),
+            bytes - MapKey::String(String::from("a")).size()
+        );
+        assert_eq!(heap.display(&map), "{\"b\": 2}");
+        assert_eq!(
+            call(&mut heap, "remove", &[map, Value::Literal("a")])
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::Key
+        );
+        assert_eq!(error("keys", &[number(1.0)]), RuntimeErrorKind::Type);
+    }
+
+    #[test]
+    fn types() {
+        let cases = [
+            (number(1.0), "number"),
+            (Value::Literal("rox"), "string"),
+            (Value::Native(&STDLIB[0]), "function"),
+            (Value::List(crate::chunks::value::ObjRef(0)), "list"),
+            (Value::Map(crate::chunks::value::ObjRef(0)), "map"),
+            (Value::Bool(false), "boolean"),
+            (Value::Empty, "nil"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(call_str("type", &[value]), expected);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    io,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::chunks::{Chunk, opcodes::OpCode};
+
+use super::vm::{Flow, Hook, VMState};
+
+/// number of hottest lines shown in the report of a profile
+const HOT_LINES: usize = 10;
+
+/// Output formats of a profile
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ProfileFormat {
+    /// tables of opcodes, functions and hot lines, for humans - see `Profile`
+    #[default]
+    Report,
+    /// folded stacks, as consumed by flamegraph tools - see `Profile::write_folded`
+    Folded,
+}
+
+impl FromStr for ProfileFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(ProfileFormat::Report),
+            "folded" => Ok(ProfileFormat::Folded),
+            _ => anyhow::bail!(
+                "unknown profile format '{}', expected 'report' or 'folded'",
+                s
+            ),
+        }
+    }
+}
+
+/// Call frame being profiled
+struct ProfiledFrame {
+    function: String,
+    start: Instant,
+    /// inclusive time of the calls made from this frame
+    children: Duration,
+}
+
+/// Times and calls of a function
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    /// time spent in the function and in the functions it called. Recursive calls are only
+    /// counted once, as part of the outermost call
+    pub inclusive: Duration,
+    /// time spent in the function itself
+    pub exclusive: Duration,
+    /// number of calls of the function currently running
+    active: u64,
+}
+
+/// Hook profiling the instructions run by the VM.
+///
+/// Each instruction only bumps counters indexed by its opcode and offset, lines are only resolved
+/// when building the profile. Clocks are read when a frame is entered or left, so function times
+/// cost nothing while no calls are made
+pub struct Profiler {
+    /// executions per opcode byte
+    opcodes: Box<[u64; 256]>,
+    /// executions per offset into the code
+    offsets: Vec<u64>,
+    functions: HashMap<String, FunctionProfile>,
+    frames: Vec<ProfiledFrame>,
+    /// instructions run per stack of function names, joined with ';'
+    folded: HashMap<String, u64>,
+    /// instructions run since the call stack last changed
+    executed: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            opcodes: Box::new([0; 256]),
+            offsets: vec![],
+            functions: HashMap::new(),
+            frames: vec![],
+            folded: HashMap::new(),
+            executed: 0,
+        }
+    }
+
+    /// Closes the frames still running, as the run ended, and builds the profile of the runs of
+    /// chunk, as run by the VM (see `VM::chunk`). The script frame stays open across runs until
+    /// then, so times and calls of the script are only meaningful when profiling a single run
+    pub fn finish(&mut self, chunk: &Chunk) -> Profile {
+        let now = Instant::now();
+        while !self.frames.is_empty() {
+            self.leave(now);
+        }
+
+        let mut opcodes: Vec<_> = (0..=u8::MAX)
+            .filter(|&byte| self.opcodes[byte as usize] > 0)
+            .filter_map(|byte| Some((OpCode::try_from(byte).ok()?, self.opcodes[byte as usize])))
+            .collect();
+        opcodes.sort_by_key(|&(_, count)| Reverse(count));
+
+        let mut lines = BTreeMap::new();
+        for (offset, &count) in self.offsets.iter().enumerate() {
+            if count > 0 {
+                *lines
+                    .entry(chunk.get_line_info_from_offset(offset).line)
+                    .or_insert(0) += count;
+            }
+        }
+        let mut lines: Vec<_> = lines.into_iter().collect();
+        lines.sort_by_key(|&(_, count)| Reverse(count));
+
+        let mut functions: Vec<_> = self
+            .functions
+            .iter()
+            .map(|(name, profile)| (name.clone(), profile.clone()))
+            .collect();
+        functions.sort_by_key(|(_, profile)| Reverse(profile.inclusive));
+
+        let mut folded: Vec<_> = self
+            .folded
+            .iter()
+            .map(|(stack, &count)| (stack.clone(), count))
+            .collect();
+        folded.sort();
+
+        Profile {
+            opcodes,
+            functions,
+            lines,
+            folded,
+        }
+    }
+
+    fn stack(&self) -> String {
+        self.frames
+            .iter()
+            .map(|frame| frame.function.as_str())
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// attributes the instructions run since the last change of the call stack to the current one
+    fn flush(&mut self) {
+        if self.executed > 0 && !self.frames.is_empty() {
+            *self.folded.entry(self.stack()).or_insert(0) += self.executed;
+        }
+        self.executed = 0;
+    }
+
+    fn enter(&mut self, function: &str, now: Instant) {
+        self.flush();
+
+        let profile = self.functions.entry(function.to_string()).or_default();
+        profile.calls += 1;
+        profile.active += 1;
+        self.frames.push(ProfiledFrame {
+            function: function.to_string(),
+            start: now,
+            children: Duration::ZERO,
+        });
+    }
+
+    fn leave(&mut self, now: Instant) {
+        self.flush();
+
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        let inclusive = now - frame.start;
+        let profile = self
+            .functions
+            .get_mut(&frame.function)
+            .expect("entered functions are profiled");
+        profile.active -= 1;
+        profile.exclusive += inclusive.saturating_sub(frame.children);
+        if profile.active == 0 {
+            profile.inclusive += inclusive;
+        }
+
+        if let Some(caller) = self.frames.last_mut() {
+            caller.children += inclusive;
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hook for Profiler {
+    #[inline]
+    fn on_instruction(&mut self, state: &VMState) -> Flow {
+        if state.depth != self.frames.len() {
+            let now = Instant::now();
+            while self.frames.len() > state.depth {
+                self.leave(now);
+            }
+            // --- frames are entered one at a time, only the innermost function is known
+            while self.frames.len() < state.depth {
+                self.enter(state.function, now);
+            }
+        }
+
+        if self.offsets.len() < state.chunk.code.len() {
+            self.offsets.resize(state.chunk.code.len(), 0);
+        }
+        self.opcodes[state.chunk.code[state.offset] as usize] += 1;
+        self.offsets[state.offset] += 1;
+        self.executed += 1;
+
+        Flow::Continue
+    }
+}
+
+/// Profile of the runs of a chunk, with each table sorted from the most to the least expensive
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// instructions run per opcode
+    pub opcodes: Vec<(OpCode, u64)>,
+    pub functions: Vec<(String, FunctionProfile)>,
+    /// instructions run per source line
+    pub lines: Vec<(usize, u64)>,
+    /// instructions run per stack of function names, from the outermost frame, joined with ';'
+    pub folded: Vec<(String, u64)>,
+}
+
+impl Profile {
+    /// writes the folded stacks, one `<stack> <instructions>` line per stack
+    pub fn write_folded<W: io::Write>(&self, mut output: W) -> io::Result<()> {
+        for (stack, count) in &self.folded {
+            writeln!(output, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total: u64 = self.opcodes.iter().map(|(_, count)| count).sum();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+
+        writeln!(f, "------ OPCODES ------")?;
+        for (op, count) in &self.opcodes {
+            writeln!(f, "{:<20} {:>12} {:>6.2}%", op, count, percent(*count))?;
+        }
+
+        writeln!(f, "------ FUNCTIONS ------")?;
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>14} {:>14}",
+            "function", "calls", "inclusive", "exclusive"
+        )?;
+        for (name, profile) in &self.functions {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>14?} {:>14?}",
+                name, profile.calls, profile.inclusive, profile.exclusive
+            )?;
+        }
+
+        writeln!(f, "------ HOT LINES ------")?;
+        for (line, count) in self.lines.iter().take(HOT_LINES) {
+            writeln!(
+                f,
+                "line {:<15} {:>12} {:>6.2}%",
+                line,
+                count,
+                percent(*count)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+    use crate::chunks::value::Value;
+    use crate::vm::vm::{SCRIPT, VM, VMConfig, VMResult};
+
+    /// chunk computing `1 + 2` in line 1 and negating it three times in line 2
+    fn chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.set_line(1);
+        chunk.write_constant(Value::Number(OrderedFloat(1.0)));
+        chunk.write_constant(Value::Number(OrderedFloat(2.0)));
+        chunk.write(OpCode::Add);
+        chunk.set_line(2);
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Negate);
+        chunk
+    }
+
+    fn profile(runs: usize) -> Profile {
+        let mut profiler = Profiler::new();
+        // --- counts are those of the instructions as written
+        let config = VMConfig {
+            peephole: false,
+            ..Default::default()
+        };
+        let mut vm = VM::with_config(chunk(), config);
+        for _ in 0..runs {
+            assert_eq!(vm.run_with_hook(&mut profiler), VMResult::Ok);
+        }
+        profiler.finish(vm.chunk())
+    }
+
+    #[test]
+    fn parse_profile_format() {
+        assert_eq!(
+            "report".parse::<ProfileFormat>().unwrap(),
+            ProfileFormat::Report
+        );
+        assert_eq!(
+            "folded".parse::<ProfileFormat>().unwrap(),
+            ProfileFormat::Folded
+        );
+        assert!("svg".parse::<ProfileFormat>().is_err());
+    }
+
+    #[test]
+    fn count_opcodes_and_lines() {
+        let profile = profile(2);
+        assert_eq!(
+            profile.opcodes,
+            vec![(OpCode::Negate, 6), (OpCode::Load, 4), (OpCode::Add, 2)]
+        );
+        assert_eq!(profile.lines, vec![(1, 6), (2, 6)]);
+    }
+
+    #[test]
+    fn profile_functions() {
+        let profile = profile(1);
+        assert_eq!(profile.functions.len(), 1);
+
+        let (name, script) = &profile.functions[0];
+        assert_eq!(name, SCRIPT);
+        assert_eq!(script.calls, 1);
+        assert_eq!(script.inclusive, script.exclusive);
+    }
+
+    #[test]
+    fn profile_nested_frames() {
+        let mut profiler = Profiler::new();
+        let chunk = chunk();
+        let stack = crate::vm::stack::Stack::new();
+        let heap = crate::vm::heap::Heap::default();
+        let mut step = |depth, function| {
+            let state = VMState {
+                chunk: &chunk,
+                stack: &stack,
+                offset: 0,
+                depth,
+                function,
+                heap: &heap,
+            };
+            profiler.on_instruction(&state);
+        };
+        step(1, SCRIPT);
+        step(2, "fib");
+        step(3, "fib");
+        step(3, "fib");
+        step(2, "fib");
+        step(1, SCRIPT);
+
+        let profile = profiler.finish(&chunk);
+        let fib = &profile
+            .functions
+            .iter()
+            .find(|(name, _)| name == "fib")
+            .unwrap()
+            .1;
+        assert_eq!(fib.calls, 2);
+        assert!(fib.inclusive >= fib.exclusive);
+
+        assert_eq!(
+            profile.folded,
+            vec![
+                (String::from("script"), 2),
+                (String::from("script;fib"), 2),
+                (String::from("script;fib;fib"), 2),
+            ]
+        );
+        let mut folded = vec![];
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "script 2\nscript;fib 2\nscript;fib;fib 2\n"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let report = profile(1).to_string();
+        assert!(report.contains("NEGATE"));
+        assert!(report.contains(SCRIPT));
+        assert!(report.contains("line 2"));
+    }
+}
@@ -1,11 +1,13 @@
-use std::{array, ptr};
+use std::ptr;
 
 use crate::chunks::value::Value;
+use crate::errors::{RuntimeError, RuntimeErrorKind};
 
-const STACK_SIZE: usize = 4096;
+/// default maximum number of values in the stack
+pub const STACK_SIZE: usize = 4096;
 
 pub struct Stack {
-    stack: Box<[Value; STACK_SIZE]>,
+    stack: Box<[Value]>,
     /// Pointer to the next chunk of memory in stack where the next item can be inserted
     /// If the stack is at capacity, the pointer will be pointing to invalid memory
     top: *mut Value,
@@ -13,7 +15,12 @@ pub struct Stack {
 
 impl Stack {
     pub fn new() -> Self {
-        let mut stack = Box::new(array::from_fn(|_| Value::default()));
+        Self::with_size(STACK_SIZE)
+    }
+
+    /// creates a stack holding at most size values
+    pub fn with_size(size: usize) -> Self {
+        let mut stack = vec![Value::default(); size].into_boxed_slice();
         let top = stack.as_mut_ptr();
 
         Self { stack, top }
@@ -32,22 +39,46 @@ impl Stack {
         Some(unsafe { &*self.top.offset(-1) })
     }
 
-    /// Attempts to push v onto the stack. If the stack size has been reach, push panics
+    /// Returns the top-most value of the stack without checking whether the stack is empty
+    ///
+    /// # Safety
+    /// The stack must not be empty
+    #[inline]
+    pub unsafe fn peek_mut_unchecked(&mut self) -> &mut Value {
+        unsafe { &mut *self.top.offset(-1) }
+    }
+
+    /// Attempts to push v onto the stack, failing with a stack overflow if the stack is full
     /// Internally, the pointer to the top of the stack is updated
     #[inline]
-    pub fn push(&mut self, v: Value) {
-        // --- assert that there is still enough space in the stack
-        assert!(
-            self.top_offset() < STACK_SIZE,
-            "Stack overflow: maximum stack size of {} reached",
-            STACK_SIZE
-        );
+    pub fn push(&mut self, v: Value) -> Result<(), RuntimeError> {
+        // --- check that there is still enough space in the stack
+        if self.top_offset() >= self.stack.len() {
+            return Err(RuntimeError::new(
+                RuntimeErrorKind::StackOverflow,
+                "Stack overflow".to_string(),
+            ));
+        }
 
         // --- write value onto the stack
         unsafe {
             *self.top = v;
             self.top = self.top.offset(1);
         }
+
+        Ok(())
+    }
+
+    /// Pushes v onto the stack without checking whether the stack is full
+    ///
+    /// # Safety
+    /// The stack must have room for v
+    #[inline]
+    pub unsafe fn push_unchecked(&mut self, v: Value) {
+        unsafe {
+            *self.top = v;
+            self.top = self.top.offset(1);
+        }
     }
 
     /// Pops the top-most value of the stack or None if the stack is empty
@@ -69,25 +100,32 @@ impl Stack {
         Some(value)
     }
 
-    pub fn reset(&mut self) {
-        self.top = self.stack.as_mut_ptr();
+    /// Pops the top-most value of the stack without checking whether the stack is empty
+    ///
+    /// # Safety
+    /// The stack must not be empty
+    #[inline]
+    pub unsafe fn pop_unchecked(&mut self) -> Value {
+        unsafe {
+            self.top = self.top.offset(-1);
+            ptr::read(self.top)
+        }
     }
 
-    pub fn trace(&self) {
-        print!("[DEBUG]\t\t\tstack: [");
-        let mut iter = self.stack.as_ptr();
-        let start = self.stack.as_ptr();
-
-        while iter < self.top {
-            let is_last = unsafe { iter.offset(1) } == self.top;
-            print!("{}", unsafe { &*iter });
-            if !is_last {
-                print!(", ")
-            }
+    /// values in the stack, from the bottom to the top
+    pub fn values(&self) -> &[Value] {
+        &self.stack[..self.top_offset()]
+    }
 
-            iter = unsafe { iter.offset(1) };
+    /// drops the values above the first len ones, if there are more than len values
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.top_offset() {
+            self.top = unsafe { self.stack.as_mut_ptr().add(len) };
         }
-        print!("]\n");
+    }
+
+    pub fn reset(&mut self) {
+        self.top = self.stack.as_mut_ptr();
     }
 
     fn top_offset(&self) -> usize {
@@ -111,10 +149,10 @@ mod tests {
         let mut stack = Stack::new();
         assert_eq!(stack.len(), 0);
 
-        stack.push(Value::Number(OrderedFloat(42.0)));
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
         assert_eq!(stack.len(), 1);
 
-        stack.push(Value::Literal("Hello, world!"));
+        stack.push(Value::Literal("Hello, world!")).unwrap();
         assert_eq!(stack.len(), 2);
     }
 
@@ -123,22 +161,38 @@ mod tests {
         let mut stack = Stack::new();
         assert_eq!(stack.len(), 0);
 
-        stack.push(Value::Number(OrderedFloat(42.0)));
-        stack.push(Value::Literal("Hello, world!"));
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
+        stack.push(Value::Literal("Hello, world!")).unwrap();
 
         assert_eq!(stack.pop().unwrap(), Value::Literal("Hello, world!"));
         assert_eq!(stack.pop().unwrap(), Value::Number(OrderedFloat(42.0)));
         assert_eq!(stack.pop(), None);
     }
 
+    #[test]
+    fn overflow() {
+        let mut stack = Stack::with_size(2);
+
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
+        assert_eq!(
+            stack
+                .push(Value::Number(OrderedFloat(42.0)))
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::StackOverflow
+        );
+        assert_eq!(stack.len(), 2);
+    }
+
     #[test]
     fn reset() {
         let mut stack = Stack::new();
 
-        stack.push(Value::Number(OrderedFloat(42.0)));
-        stack.push(Value::Literal("Hello, world!"));
-        stack.push(Value::Number(OrderedFloat(42.0)));
-        stack.push(Value::Literal("Hello, world!"));
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
+        stack.push(Value::Literal("Hello, world!")).unwrap();
+        stack.push(Value::Number(OrderedFloat(42.0))).unwrap();
+        stack.push(Value::Literal("Hello, world!")).unwrap();
         assert_eq!(stack.len(), 4);
 
         stack.reset();
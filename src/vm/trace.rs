@@ -0,0 +1,367 @@
+use std::{fmt::Display, io, str::FromStr};
+
+use itertools::Itertools;
+use serde_json::{Value as Json, json};
+
+use crate::chunks::{
+    opcodes::OpCode,
+    value::{ObjRef, Value},
+};
+
+use super::heap::{Heap, MapKey};
+use super::vm::{Flow, Hook, VMState};
+
+/// Instruction about to be run by the VM, as reported to tracers
+pub struct Step<'v> {
+    /// offset of the instruction into the code of the chunk
+    pub offset: usize,
+    pub line: usize,
+    pub op: OpCode,
+    /// raw operand bytes of the instruction
+    pub operands: &'v [u8],
+    /// value of the constant referenced by the operands, for instructions reading a constant
+    pub constant: Option<Value>,
+    /// values in the stack before the instruction runs, from the bottom to the top
+    pub stack: &'v [Value],
+    /// heap holding the objects referenced by the values
+    pub heap: &'v Heap,
+}
+
+/// Sink for the steps of a traced run
+pub trait Tracer {
+    fn step(&mut self, step: &Step) -> io::Result<()>;
+}
+
+/// Output formats of the built-in tracers
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TraceFormat {
+    /// one line per step, for humans - see `TextTracer`
+    #[default]
+    Text,
+    /// one JSON object per line and step - see `JsonTracer`
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            _ => anyhow::bail!("unknown trace format '{}', expected 'text' or 'json'", s),
+        }
+    }
+}
+
+impl Display for TraceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFormat::Text => write!(f, "text"),
+            TraceFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Writes each step as a line in the format of the disassembler, followed by the stack, e.g.
+/// `0x000004     1 ADD                  [1, 2]`, with a tab after the line
+pub struct TextTracer<W: io::Write> {
+    output: W,
+}
+
+impl<W: io::Write> TextTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: io::Write> Tracer for TextTracer<W> {
+    fn step(&mut self, step: &Step) -> io::Result<()> {
+        let mut instruction = step.op.to_string();
+        for operand in step.operands {
+            instruction.push_str(&format!(" {:#04x}", operand));
+        }
+        if let Some(constant) = step.constant {
+            instruction.push_str(&format!(" ({})", step.heap.display(&constant)));
+        }
+
+        writeln!(
+            self.output,
+            "0x{:0>6} {:>5}\t{:<20} [{}]",
+            step.offset,
+            step.line,
+            instruction,
+            step.stack
+                .iter()
+                .map(|value| step.heap.display(value))
+                .join(", ")
+        )
+    }
+}
+
+/// Writes each step as a JSON object on its own line:
+/// `{"offset":4,"line":1,"op":"ADD","operands":[],"constant":null,"stack":[1.0,2.0]}`.
+/// Numbers and booleans are written as JSON numbers and booleans, strings as strings, lists as
+/// arrays, maps as objects, nil as null and functions by name
+pub struct JsonTracer<W: io::Write> {
+    output: W,
+}
+
+impl<W: io::Write> JsonTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: io::Write> Tracer for JsonTracer<W> {
+    fn step(&mut self, step: &Step) -> io::Result<()> {
+        let step = json!({
+            "offset": step.offset,
+            "line": step.line,
+            "op": step.op.to_string(),
+            "operands": step.operands,
+            "constant": step.constant.map(|value| json_value(step.heap, value)),
+            "stack": step
+                .stack
+                .iter()
+                .map(|value| json_value(step.heap, *value))
+                .collect::<Vec<_>>(),
+        });
+        writeln!(self.output, "{}", step)
+    }
+}
+
+fn json_value(heap: &Heap, value: Value) -> Json {
+    json_element(heap, value, &mut vec![])
+}
+
+/// value as JSON, tracking the lists being written in path - lists containing themselves are
+/// written as they are displayed, see `Heap::display`
+fn json_element(heap: &Heap, value: Value, path: &mut Vec<ObjRef>) -> Json {
+    match value {
+        Value::List(obj) if path.contains(&obj) => json!("[...]"),
+        Value::Map(obj) if path.contains(&obj) => json!("{...}"),
+        Value::List(obj) => {
+            let Some(elements) = heap.list(&value) else {
+                return json!(value.to_string());
+            };
+            path.push(obj);
+            let elements = elements
+                .iter()
+                .map(|element| json_element(heap, *element, path))
+                .collect::<Vec<_>>();
+            path.pop();
+            Json::Array(elements)
+        }
+        // --- JSON keys are strings, so keys are written as they are displayed
+        Value::Map(obj) => {
+            let Some(entries) = heap.map(&value) else {
+                return json!(value.to_string());
+            };
+            path.push(obj);
+            let entries = entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        MapKey::String(s) => s.clone(),
+                        key => key.to_string(),
+                    };
+                    (key, json_element(heap, *value, path))
+                })
+                .collect();
+            path.pop();
+            Json::Object(entries)
+        }
+        Value::Bool(b) => json!(b),
+        // --- non finite numbers have no JSON representation and are written as null
+        Value::Number(n) => json!(n.0),
+        Value::Literal(_) | Value::String(_) => json!(heap.display(&value)),
+        Value::Native(_) => json!(value.to_string()),
+        Value::Empty => Json::Null,
+    }
+}
+
+/// Hook reporting every instruction the VM runs to tracer. Tracing stops at the first error
+/// writing a step, which is kept until `finish`
+pub struct Tracing<T: Tracer> {
+    tracer: T,
+    error: Option<io::Error>,
+}
+
+impl<T: Tracer> Tracing<T> {
+    pub fn new(tracer: T) -> Self {
+        Self {
+            tracer,
+            error: None,
+        }
+    }
+
+    /// returns the tracer, or the error that stopped tracing
+    pub fn finish(self) -> io::Result<T> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.tracer),
+        }
+    }
+}
+
+impl<T: Tracer> Hook for Tracing<T> {
+    fn on_instruction(&mut self, state: &VMState) -> Flow {
+        if self.error.is_some() {
+            return Flow::Continue;
+        }
+
+        let chunk = state.chunk;
+        let op =
+            OpCode::try_from(chunk.code[state.offset]).expect("the VM only runs verified chunks");
+        let operands = &chunk.code[state.offset + 1..state.offset + 1 + op.operand_len()];
+        let constant = match op {
+            OpCode::Load
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => Some(chunk.constants[operands[0] as usize]),
+            OpCode::LoadLong => {
+                let idx = crate::bitwise::u32_from_bytes(&[operands[0], operands[1], operands[2]]);
+                Some(chunk.constants[idx as usize])
+            }
+            OpCode::Return
+            | OpCode::Pop
+            | OpCode::Jump
+            | OpCode::Call
+            | OpCode::BuildList
+            | OpCode::BuildMap
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::Negate
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::BitNot
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::NotEqual => None,
+        };
+
+        let step = Step {
+            offset: state.offset,
+            line: state.line(),
+            op,
+            operands,
+            constant,
+            stack: state.stack.values(),
+            heap: state.heap,
+        };
+        if let Err(e) = self.tracer.step(&step) {
+            self.error = Some(e);
+        }
+
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+    use crate::chunks::Chunk;
+    use crate::vm::vm::{VM, VMConfig, VMResult};
+
+    /// chunk computing `-(1 + 2)`, with the negation in line 2
+    fn chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.set_line(1);
+        chunk.write_constant(Value::Number(OrderedFloat(1.0)));
+        chunk.write_constant(Value::Number(OrderedFloat(2.0)));
+        chunk.write(OpCode::Add);
+        chunk.set_line(2);
+        chunk.write(OpCode::Negate);
+        chunk
+    }
+
+    fn trace<T: Tracer>(tracer: T) -> T {
+        let mut tracing = Tracing::new(tracer);
+        // --- traces show the instructions as written
+        let config = VMConfig {
+            peephole: false,
+            ..Default::default()
+        };
+        let mut vm = VM::with_config(chunk(), config);
+        assert_eq!(vm.run_with_hook(&mut tracing), VMResult::Ok);
+        tracing.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_trace_format() {
+        assert_eq!("text".parse::<TraceFormat>().unwrap(), TraceFormat::Text);
+        assert_eq!("json".parse::<TraceFormat>().unwrap(), TraceFormat::Json);
+        assert!("xml".parse::<TraceFormat>().is_err());
+    }
+
+    #[test]
+    fn text_trace() {
+        let output = trace(TextTracer::new(vec![])).output;
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("0x000000     1\tLOAD 0x00 (1)"));
+        assert!(lines[1].ends_with("[1]"));
+        assert!(lines[2].starts_with("0x000004     1\tADD"));
+        assert!(lines[2].ends_with("[1, 2]"));
+        assert!(lines[3].starts_with("0x000005     2\tNEGATE"));
+        assert!(lines[3].ends_with("[3]"));
+    }
+
+    #[test]
+    fn json_trace() {
+        let output = trace(JsonTracer::new(vec![])).output;
+        let steps: Vec<Json> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(steps.len(), 4);
+        assert_eq!(
+            steps[1],
+            json!({
+                "offset": 2,
+                "line": 1,
+                "op": "LOAD",
+                "operands": [1],
+                "constant": 2.0,
+                "stack": [1.0],
+            })
+        );
+        assert_eq!(steps[3]["line"], 2);
+        assert_eq!(steps[3]["operands"], json!([]));
+        assert_eq!(steps[3]["constant"], Json::Null);
+        assert_eq!(steps[3]["stack"], json!([3.0]));
+    }
+
+    #[test]
+    fn failed_write_stops_tracing() {
+        struct Failing(usize);
+        impl Tracer for Failing {
+            fn step(&mut self, _step: &Step) -> io::Result<()> {
+                self.0 += 1;
+                Err(io::Error::other("closed"))
+            }
+        }
+
+        let mut tracing = Tracing::new(Failing(0));
+        let mut vm = VM::new(chunk());
+        assert_eq!(vm.run_with_hook(&mut tracing), VMResult::Ok);
+        assert_eq!(tracing.tracer.0, 1);
+        assert!(tracing.finish().is_err());
+    }
+}
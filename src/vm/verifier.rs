@@ -0,0 +1,379 @@
+use std::fmt::Display;
+
+use crate::bitwise;
+use crate::chunks::{Chunk, opcodes::OpCode};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum VerifyError {
+    /// byte at offset does not encode any opcode
+    InvalidOpCode { offset: usize, byte: u8 },
+    /// the code ends before all operands of the operation at offset
+    MissingOperand { offset: usize, op: OpCode },
+    /// the operation at offset references a constant that is not in the constants pool
+    InvalidConstant { offset: usize, idx: usize },
+    /// the jump at offset does not land at the start of an instruction or at the end of the code
+    InvalidJumpTarget { offset: usize, target: usize },
+    /// the operation at offset pops more values than there are in the stack
+    StackUnderflow { offset: usize, op: OpCode },
+    /// the instruction at offset is reached with different stack depths
+    StackMismatch {
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::InvalidOpCode { offset, byte } => {
+                write!(f, "0x{:0>6}: invalid opcode {:#04x}", offset, byte)
+            }
+            VerifyError::MissingOperand { offset, op } => {
+                write!(f, "0x{:0>6}: missing operand for {}", offset, op)
+            }
+            VerifyError::InvalidConstant { offset, idx } => {
+                write!(f, "0x{:0>6}: invalid constant index {}", offset, idx)
+            }
+            VerifyError::InvalidJumpTarget { offset, target } => {
+                write!(f, "0x{:0>6}: invalid jump target 0x{:0>6}", offset, target)
+            }
+            VerifyError::StackUnderflow { offset, op } => {
+                write!(f, "0x{:0>6}: stack underflow on {}", offset, op)
+            }
+            VerifyError::StackMismatch {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "0x{:0>6}: reached with a stack depth of {}, expected {}",
+                offset, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Instruction decoded by the verifier
+struct Instruction {
+    op: OpCode,
+    offset: usize,
+    /// absolute offset the instruction jumps to, if it is a jump
+    target: Option<usize>,
+    /// number of values popped on top of the stack effect of the op: the arguments of a call, the
+    /// elements of a list or the keys and values of a map
+    args: usize,
+}
+
+/// Checks that a chunk is well formed before it gets executed, so that the VM can run it without
+/// checking each instruction:
+///  - every opcode is valid and has all of its operands
+///  - every constant index is in the constants pool
+///  - every jump lands at the start of an instruction or at the end of the code
+///  - no operation pops from an empty stack, and each instruction is always reached with the same
+///    stack depth
+///
+/// Returns the maximum depth the stack reaches when running the chunk
+pub fn verify(chunk: &Chunk) -> Result<usize, VerifyError> {
+    let instructions = decode(chunk)?;
+
+    // --- stack depth on entry of each instruction, with the end of the code as the last entry.
+    // Jumps only go forward, so every predecessor of an instruction is visited before it
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len() + 1];
+    depths[0] = Some(0);
+    let mut max_depth = 0;
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        // --- instructions that are never reached are never executed
+        let Some(depth) = depths[i] else {
+            continue;
+        };
+
+        let (pops, pushes) = instruction.op.stack_effect();
+        let pops = pops + instruction.args;
+        let depth = depth.checked_sub(pops).ok_or(VerifyError::StackUnderflow {
+            offset: instruction.offset,
+            op: instruction.op,
+        })? + pushes;
+        max_depth = max_depth.max(depth);
+
+        let next = match (instruction.op, instruction.target) {
+            (OpCode::Return, _) => continue,
+            (OpCode::Jump, Some(target)) => instructions
+                .binary_search_by_key(&target, |instruction| instruction.offset)
+                .or_else(|idx| match target == chunk.code.len() {
+                    true => Ok(idx),
+                    false => Err(VerifyError::InvalidJumpTarget {
+                        offset: instruction.offset,
+                        target,
+                    }),
+                })?,
+            _ => i + 1,
+        };
+
+        match depths[next] {
+            None => depths[next] = Some(depth),
+            Some(expected) if expected != depth => {
+                return Err(VerifyError::StackMismatch {
+                    offset: instructions
+                        .get(next)
+                        .map_or(chunk.code.len(), |instruction| instruction.offset),
+                    expected,
+                    found: depth,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(max_depth)
+}
+
+/// decodes the instructions of chunk, checking opcodes and operands
+fn decode(chunk: &Chunk) -> Result<Vec<Instruction>, VerifyError> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let op = OpCode::try_from(byte).map_err(|_| VerifyError::InvalidOpCode { offset, byte })?;
+        let operands = chunk
+            .code
+            .get(offset + 1..offset + 1 + op.operand_len())
+            .ok_or(VerifyError::MissingOperand { offset, op })?;
+
+        let mut target = None;
+        let mut args = 0;
+        let constant = match op {
+            OpCode::Load
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant
+            | OpCode::MultiplyConstant
+            | OpCode::DivideConstant => Some(operands[0] as usize),
+            OpCode::LoadLong => {
+                Some(bitwise::u32_from_bytes(&[operands[0], operands[1], operands[2]]) as usize)
+            }
+            OpCode::Jump => {
+                let jump = bitwise::u16_from_bytes(&[operands[0], operands[1]]);
+                target = Some(offset + 3 + jump as usize);
+                None
+            }
+            OpCode::Call => {
+                args = operands[0] as usize;
+                None
+            }
+            OpCode::BuildList => {
+                args = bitwise::u16_from_bytes(&[operands[0], operands[1]]) as usize;
+                None
+            }
+            OpCode::BuildMap => {
+                args = 2 * bitwise::u16_from_bytes(&[operands[0], operands[1]]) as usize;
+                None
+            }
+            OpCode::Return
+            | OpCode::Pop
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::Negate
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::BitNot
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::NotEqual => None,
+        };
+
+        if let Some(idx) = constant
+            && idx >= chunk.constants.len()
+        {
+            return Err(VerifyError::InvalidConstant { offset, idx });
+        }
+
+        instructions.push(Instruction {
+            op,
+            offset,
+            target,
+            args,
+        });
+        offset += 1 + op.operand_len();
+    }
+
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+    use crate::chunks::value::Value;
+
+    fn number(n: f64) -> Value {
+        Value::Number(OrderedFloat(n))
+    }
+
+    #[test]
+    fn valid_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Add);
+        chunk.write(OpCode::Negate);
+        chunk.write(OpCode::Return);
+
+        assert_eq!(verify(&chunk), Ok(2));
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(0xffu8);
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidOpCode {
+                offset: 2,
+                byte: 0xff
+            })
+        );
+    }
+
+    #[test]
+    fn missing_operand() {
+        let mut chunk = Chunk::new();
+        chunk.constants.push(number(1.0));
+        chunk.write(OpCode::LoadLong);
+        chunk.write(0u8);
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::MissingOperand {
+                offset: 0,
+                op: OpCode::LoadLong
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_constant() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::AddConstant);
+        chunk.write(1u8);
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidConstant { offset: 2, idx: 1 })
+        );
+    }
+
+    #[test]
+    fn jump_into_operand() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump();
+        chunk.write_constant(number(1.0));
+        chunk.patch_jump(jump);
+        // --- land on the operand of the load
+        chunk.code[jump + 1] -= 1;
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidJumpTarget {
+                offset: 0,
+                target: 4
+            })
+        );
+    }
+
+    #[test]
+    fn jump_past_end() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump();
+        chunk.patch_jump(jump);
+        chunk.code[jump + 1] += 1;
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::InvalidJumpTarget {
+                offset: 0,
+                target: 4
+            })
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Add);
+
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::StackUnderflow {
+                offset: 2,
+                op: OpCode::Add
+            })
+        );
+    }
+
+    #[test]
+    fn call_pops_arguments() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::Call);
+        chunk.write(1u8);
+        assert_eq!(verify(&chunk), Ok(2));
+
+        chunk.code[5] = 2;
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::StackUnderflow {
+                offset: 4,
+                op: OpCode::Call
+            })
+        );
+    }
+
+    #[test]
+    fn build_list_pops_elements() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(2.0));
+        chunk.write(OpCode::BuildList);
+        chunk.write(0u8);
+        chunk.write(2u8);
+        chunk.write(OpCode::Pop);
+        assert_eq!(verify(&chunk), Ok(2));
+
+        chunk.code[6] = 3;
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::StackUnderflow {
+                offset: 4,
+                op: OpCode::BuildList
+            })
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_not_checked() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write(OpCode::Return);
+        chunk.write(OpCode::Add);
+
+        assert_eq!(verify(&chunk), Ok(1));
+    }
+}
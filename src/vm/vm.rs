@@ -1,146 +1,731 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
 use crate::chunks::value::Value;
-use crate::chunks::{opcodes::OpCode, Chunk};
+use crate::chunks::{Chunk, opcodes::OpCode};
+use crate::optimizer::peephole::Peephole;
 use crate::{bitwise, offset_ip, ptr_offset};
 
-use super::stack::Stack;
+use super::heap::{Heap, Roots};
+use super::natives::{CORE, Native, STDLIB};
+use super::stack::{STACK_SIZE, Stack};
+use super::verifier::{self, VerifyError};
+use crate::errors::{Frame, RuntimeError, RuntimeErrorKind};
 
-macro_rules! trace_instruction {
-    ($vm:expr, $idx:expr) => {{
-        #[cfg(feature = "trace")]
-        $vm.chunk.disassembleInstruction($idx)
+/// reads the constant indexed by the 1-byte operand at ip and advances ip past it
+macro_rules! read_constant {
+    ($constants:expr, $ip:expr) => {{
+        let constant = *$constants.add(*$ip as usize);
+        offset_ip!($ip);
+        constant
     }};
 }
-macro_rules! trace_stack {
-    ($vm:expr) => {
-        $vm.stack.trace();
+
+/// returns err from the enclosing function as a runtime error, located at the instruction at op_ip
+macro_rules! runtime_error {
+    ($chunk:expr, $start:expr, $op_ip:expr, $err:expr) => {
+        return runtime_error($chunk, ptr_offset!($start, $op_ip), $err)
     };
 }
 
+/// locates err at the instruction at offset - kept out of line, so that errors do not bloat the
+/// dispatch loop
+#[cold]
+#[inline(never)]
+fn runtime_error(chunk: &Chunk, offset: usize, err: RuntimeError) -> VMResult {
+    let line = chunk.get_line_info_from_offset(offset).line;
+    let frames = vec![Frame {
+        function: String::from(SCRIPT),
+        line,
+    }];
+    VMResult::RuntimeError(err.at(frames))
+}
+
+/// error of calling callee with args arguments, which is either not a function or a function
+/// taking a different number of arguments
+#[cold]
+#[inline(never)]
+fn call_error(callee: Value, args: usize) -> RuntimeError {
+    match callee {
+        Value::Native(native) => RuntimeError::new(
+            RuntimeErrorKind::Arity,
+            format!(
+                "'{}' expects {} arguments but got {}",
+                native.name, native.arity, args
+            ),
+        ),
+        _ => RuntimeError::new(
+            RuntimeErrorKind::Type,
+            format!("'{}' is not callable", callee.value_type()),
+        ),
+    }
+}
+
+/// pushes value onto the stack, which may overflow - on failure, the runtime error is returned
+/// from the enclosing function
+macro_rules! push {
+    ($stack:expr, $value:expr, $chunk:expr, $start:expr, $op_ip:expr) => {
+        if let Err(e) = $stack.push($value) {
+            runtime_error!($chunk, $start, $op_ip, e);
+        }
+    };
+}
+
+/// replaces the left hand side of a binary operation, at the top of the stack, with the result of
+/// applying op, written as symbol, to it and rhs - on failure, the runtime error is returned from
+/// the enclosing function
+macro_rules! binary_op {
+    ($stack:expr, $rhs:expr, $op:ident, $symbol:expr, $chunk:expr, $start:expr, $op_ip:expr) => {{
+        let rhs = $rhs;
+        // --- the result replaces the left hand side in place
+        let lhs = $stack.peek_mut_unchecked();
+        match lhs.$op(rhs) {
+            Ok(value) => *lhs = value,
+            Err(kind) => runtime_error!($chunk, $start, $op_ip, lhs.op_error(kind, $symbol, &rhs)),
+        }
+    }};
+}
+
+/// name of the outermost frame, running the top level code of the script
+pub const SCRIPT: &str = "script";
+
+/// default maximum depth of nested call frames
+pub const FRAMES_MAX: usize = 64;
+
+/// Limits on the resources used by a VM
+#[derive(Debug, Clone, Copy)]
+pub struct VMConfig {
+    /// maximum number of values in the stack - pushing over it is a runtime error
+    pub stack_size: usize,
+    /// maximum depth of nested call frames, with the script itself as the outermost frame -
+    /// entering a frame over it is a stack overflow runtime error
+    pub max_frames: usize,
+    /// maximum number of instructions each run may execute, without limit if None - once it is
+    /// exhausted, the run stops with `VMResult::OutOfFuel`
+    pub fuel: Option<u64>,
+    /// maximum number of bytes allocated on the heap at once, without limit if None - see `Heap`
+    pub max_heap: Option<usize>,
+    /// whether the natives of the standard library are defined as globals, see `natives::STDLIB` -
+    /// the natives of `natives::CORE` are defined regardless
+    pub stdlib: bool,
+    /// whether the chunk is run through `Peephole` once verified - hooks then see the offsets of
+    /// the optimized code
+    pub peephole: bool,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: STACK_SIZE,
+            max_frames: FRAMES_MAX,
+            fuel: None,
+            max_heap: None,
+            stdlib: true,
+            peephole: true,
+        }
+    }
+}
+
+/// number of instructions run between two checks for interruptions, as a power of 2
+const INTERRUPT_INTERVAL: u64 = 1024;
+
+/// Handle to interrupt the runs of a VM from other threads, e.g. from a Ctrl-C handler. The VM
+/// checks for interruptions every few instructions and stops with `VMResult::Interrupted`
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Fuel left to the run being dispatched, kept in a local and written back to the VM on every
+/// exit of dispatch
+struct Fuel<'v> {
+    /// u64::MAX if the run has no budget
+    left: u64,
+    vm_fuel: &'v mut Option<u64>,
+}
+
+impl Drop for Fuel<'_> {
+    fn drop(&mut self) {
+        if self.vm_fuel.is_some() {
+            *self.vm_fuel = Some(self.left);
+        }
+    }
+}
+
+/// Whether the VM should keep running after a hook inspected the next instruction
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Flow {
+    Continue,
+    /// stop before running the instruction - the run can then be resumed with `VM::resume`
+    Pause,
+}
+
+/// State of the VM right before it runs the instruction at offset
+pub struct VMState<'v> {
+    pub chunk: &'v Chunk,
+    pub stack: &'v Stack,
+    /// offset of the instruction to be run into the code of chunk
+    pub offset: usize,
+    /// number of active call frames, with the script as the outermost one
+    pub depth: usize,
+    /// name of the function running in the innermost frame
+    pub function: &'v str,
+    pub heap: &'v Heap,
+}
+
+impl<'v> VMState<'v> {
+    /// source line of the instruction to be run
+    pub fn line(&self) -> usize {
+        self.chunk.get_line_info_from_offset(self.offset).line
+    }
+}
+
+/// Called by the VM before running each instruction. Hooks are a type parameter of dispatch, so
+/// running without one (see `NoHook`) compiles the calls away
+pub trait Hook {
+    fn on_instruction(&mut self, state: &VMState) -> Flow;
+}
+
+/// Hook that never pauses, used when no hook is attached
+pub struct NoHook;
+
+impl Hook for NoHook {
+    #[inline(always)]
+    fn on_instruction(&mut self, _state: &VMState) -> Flow {
+        Flow::Continue
+    }
+}
+
 pub struct VM {
     /// current chunk being executed
     chunk: Chunk,
     stack: Stack,
+    config: VMConfig,
+    /// whether chunk has passed verification - only verified chunks are run
+    verified: bool,
+    /// offset of the next instruction to run, if the current run was paused
+    paused_at: Option<usize>,
+    /// instructions the current run may still execute, without limit if None
+    fuel: Option<u64>,
+    interrupted: Arc<AtomicBool>,
+    heap: Heap,
+    globals: HashMap<String, Value>,
 }
 
 impl VM {
     pub fn new(chunk: Chunk) -> Self {
-        Self {
-            stack: Stack::new(),
-            chunk: chunk.clone(),
+        Self::with_config(chunk, VMConfig::default())
+    }
+
+    pub fn with_config(chunk: Chunk, config: VMConfig) -> Self {
+        let mut vm = Self {
+            stack: Stack::with_size(config.stack_size),
+            chunk,
+            config,
+            verified: false,
+            paused_at: None,
+            fuel: config.fuel,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            heap: Heap::new(config.max_heap),
+            globals: HashMap::new(),
+        };
+        CORE.iter().for_each(|native| vm.define_native(native));
+        if config.stdlib {
+            STDLIB.iter().for_each(|native| vm.define_native(native));
+        }
+        vm
+    }
+
+    pub fn config(&self) -> &VMConfig {
+        &self.config
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// chunk run by the VM, optimized by `Peephole` since the first run if enabled in its config
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// heap of the VM, with its current and peak usage
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// defines native as a global, under its name
+    pub fn define_native(&mut self, native: &'static Native) {
+        self.globals
+            .insert(native.name.to_string(), Value::Native(native));
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).copied()
+    }
+
+    /// globals defined in the VM with their values, sorted by name
+    pub fn globals(&self) -> Vec<(&str, Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        globals.sort_by_key(|&(name, _)| name);
+        globals
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// instructions the current run may still execute, None if runs have no budget
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Adds fuel to the budget of the current run, so that a run stopped with
+    /// `VMResult::OutOfFuel` can be resumed. Runs without a budget are left unlimited
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = self.fuel.as_mut() {
+            *left = left.saturating_add(fuel);
         }
     }
 
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(Arc::clone(&self.interrupted))
+    }
+
     pub fn run(&mut self) -> VMResult {
-        let chunk = &self.chunk;
-        let mut ip = chunk.code.as_ptr();
-        let start = chunk.code.as_ptr();
+        self.run_with_hook(&mut NoHook)
+    }
 
-        #[cfg(feature = "trace")]
-        {
-            log::debug!("------ {} ------", "TRACE");
-            log::debug!("offset    line\top");
+    /// Runs the chunk from the start, calling hook before each instruction.
+    /// Verifies the chunk the first time it is run, so that dispatch can skip checking operands,
+    /// constant indexes and stack depths of each instruction
+    pub fn run_with_hook<H: Hook>(&mut self, hook: &mut H) -> VMResult {
+        if !self.verified {
+            match verifier::verify(&self.chunk) {
+                Ok(_) => {}
+                // --- popping an empty stack is an error of the program rather than of its
+                // encoding, so it is reported as a runtime error of the instruction popping
+                Err(VerifyError::StackUnderflow { offset, .. }) => {
+                    let underflow = RuntimeError::new(
+                        RuntimeErrorKind::StackUnderflow,
+                        "Stack underflow".to_string(),
+                    );
+                    return runtime_error(&self.chunk, offset, underflow);
+                }
+                Err(e) => return VMResult::InvalidBytecode(e),
+            }
+            // --- the peephole optimizer expects well-formed bytecode, so it only runs once the
+            // chunk has been verified, and it preserves the invariants verification checked
+            if self.config.peephole {
+                self.chunk = Peephole::optimize(std::mem::replace(&mut self.chunk, Chunk::new()));
+            }
+            self.verified = true;
         }
+        // --- verification assumes execution starts with an empty stack
+        self.stack.reset();
+        self.paused_at = None;
+        self.fuel = self.config.fuel;
+        // --- interruptions are meant for the run in progress when they were requested
+        self.interrupted.store(false, Ordering::Relaxed);
 
+        // --- the script runs in the outermost frame, the only one until functions are run in
+        // frames of their own
+        if self.config.max_frames < 1 {
+            let overflow = RuntimeError::new(
+                RuntimeErrorKind::StackOverflow,
+                "Stack overflow".to_string(),
+            );
+            return VMResult::RuntimeError(overflow);
+        }
+
+        self.dispatch(0, false, hook)
+    }
+
+    /// Resumes a paused run, starting with the instruction it was paused at. If the VM is not
+    /// paused, there is nothing to resume and Ok is returned
+    pub fn resume<H: Hook>(&mut self, hook: &mut H) -> VMResult {
+        match self.paused_at.take() {
+            Some(offset) => self.dispatch(offset, true, hook),
+            None => VMResult::Ok,
+        }
+    }
+
+    /// runs the verified chunk from the instruction at offset, which is not reported to hook when
+    /// resuming, as hook already saw it before pausing
+    fn dispatch<H: Hook>(&mut self, offset: usize, mut resuming: bool, hook: &mut H) -> VMResult {
+        // --- hot state is kept in locals, so that it can live in registers during dispatch
+        let chunk = &self.chunk;
+        let stack = &mut self.stack;
+        let heap = &mut self.heap;
+        let globals = &self.globals;
+        let constants = chunk.constants.as_ptr();
+        let start = chunk.code.as_ptr();
+        let end = unsafe { start.add(chunk.code.len()) };
+        let mut ip = unsafe { start.add(offset) };
+        let interrupted = &*self.interrupted;
+        let mut fuel = Fuel {
+            left: self.fuel.unwrap_or(u64::MAX),
+            vm_fuel: &mut self.fuel,
+        };
+
+        // --- the chunk has been verified, so every opcode, operand and constant index is valid and
+        // the stack always holds the operands of each operation
         unsafe {
-            while ip < start.add(chunk.code.len()) {
-                trace_instruction!(self, ptr_offset!(start, ip));
+            while ip < end {
+                if !resuming {
+                    let state = VMState {
+                        chunk,
+                        stack,
+                        offset: ptr_offset!(start, ip),
+                        depth: 1,
+                        function: SCRIPT,
+                        heap,
+                    };
+                    if hook.on_instruction(&state) == Flow::Pause {
+                        self.paused_at = Some(state.offset);
+                        return VMResult::Paused;
+                    }
+                }
+                resuming = false;
+
+                // --- fuel and interruptions are only checked together every few instructions
+                if fuel.left.is_multiple_of(INTERRUPT_INTERVAL) {
+                    if fuel.left == 0 {
+                        self.paused_at = Some(ptr_offset!(start, ip));
+                        return VMResult::OutOfFuel;
+                    }
+                    if interrupted.swap(false, Ordering::Relaxed) {
+                        self.paused_at = Some(ptr_offset!(start, ip));
+                        return VMResult::Interrupted;
+                    }
+                }
+                fuel.left -= 1;
 
-                let op_code = *ip;
+                let op_ip = ip;
+                let op_code = OpCode::from_u8_unchecked(*ip);
                 offset_ip!(ip);
 
-                let op_code = OpCode::try_from(op_code).unwrap();
                 match op_code {
                     OpCode::Return => {
-                        let val = self.stack.pop().unwrap_or(Value::Empty);
-                        #[cfg(feature = "trace")]
-                        log::debug!("Returning {}", val);
-
+                        stack.pop();
                         return VMResult::Ok;
                     }
-                    OpCode::Load | OpCode::LoadLong => {
-                        let (constant, offset) = self
-                            .read_constant(op_code, ip)
-                            .expect("Should have a constant");
-                        offset_ip!(ip, offset);
-
-                        self.stack.push(constant.clone())
+                    OpCode::Load => {
+                        let constant = read_constant!(constants, ip);
+                        push!(stack, constant, chunk, start, op_ip);
                     }
-                    OpCode::Negate => {
-                        match self.stack.pop() {
-                            Some(Value::Number(n)) => self.stack.push(Value::Number(-n)),
-                            _ => return VMResult::RuntimeError,
-                        };
+                    OpCode::LoadLong => {
+                        let idx = bitwise::u32_from_bytes(&[*ip, *ip.add(1), *ip.add(2)]);
+                        offset_ip!(ip, 3);
+                        push!(stack, *constants.add(idx as usize), chunk, start, op_ip);
+                    }
+                    OpCode::Pop => {
+                        stack.pop_unchecked();
                     }
-                    OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
-                        // --- if parsing is correct, this should never happen
-                        let rhs = self.stack.pop().unwrap();
-                        let lhs = self.stack.pop().unwrap();
-
-                        let value = match op_code {
-                            OpCode::Add => lhs.add(rhs),
-                            OpCode::Subtract => lhs.sub(rhs),
-                            OpCode::Multiply => lhs.mult(rhs),
-                            OpCode::Divide => lhs.div(rhs),
-                            _ => unreachable!(),
+                    OpCode::Jump => {
+                        let jump = bitwise::u16_from_bytes(&[*ip, *ip.add(1)]);
+                        offset_ip!(ip, 2 + jump as usize);
+                    }
+                    OpCode::Call => {
+                        let args = *ip as usize;
+                        offset_ip!(ip);
+
+                        let values = stack.values();
+                        let callee = values.len() - args - 1;
+                        let result = match values[callee] {
+                            Value::Native(native) if native.arity == args => {
+                                let roots = Roots::new(values, globals);
+                                (native.function)(heap, roots, &values[callee + 1..])
+                            }
+                            callee => Err(call_error(callee, args)),
                         };
+                        match result {
+                            Ok(value) => {
+                                stack.truncate(values.len() - args);
+                                *stack.peek_mut_unchecked() = value;
+                            }
+                            Err(e) => runtime_error!(chunk, start, op_ip, e),
+                        }
+                    }
+                    OpCode::BuildList => {
+                        let len = bitwise::u16_from_bytes(&[*ip, *ip.add(1)]) as usize;
+                        offset_ip!(ip, 2);
 
-                        if let Err(e) = value {
-                            log::error!("{}", e);
-                            return VMResult::RuntimeError;
+                        let values = stack.values();
+                        let first = values.len() - len;
+                        // --- the elements stay on the stack until the list holds them
+                        let roots = Roots::new(values, globals);
+                        match heap.alloc_list(values[first..].to_vec(), roots) {
+                            Ok(list) => {
+                                stack.truncate(first);
+                                push!(stack, list, chunk, start, op_ip);
+                            }
+                            Err(e) => runtime_error!(chunk, start, op_ip, e),
                         }
+                    }
+                    OpCode::BuildMap => {
+                        let len = bitwise::u16_from_bytes(&[*ip, *ip.add(1)]) as usize;
+                        offset_ip!(ip, 2);
 
-                        self.stack.push(value.unwrap());
+                        let values = stack.values();
+                        let first = values.len() - 2 * len;
+                        let roots = Roots::new(values, globals);
+                        match heap.alloc_map(&values[first..], roots) {
+                            Ok(map) => {
+                                stack.truncate(first);
+                                push!(stack, map, chunk, start, op_ip);
+                            }
+                            Err(e) => runtime_error!(chunk, start, op_ip, e),
+                        }
+                    }
+                    OpCode::GetIndex => {
+                        let index = stack.pop_unchecked();
+                        // --- the element replaces the list in place
+                        let list = stack.peek_mut_unchecked();
+                        match heap.get_index(list, &index) {
+                            Ok(element) => *list = element,
+                            Err(e) => runtime_error!(chunk, start, op_ip, e),
+                        }
+                    }
+                    OpCode::SetIndex => {
+                        let element = stack.pop_unchecked();
+                        let index = stack.pop_unchecked();
+                        let values = stack.values();
+                        let list = values[values.len() - 1];
+                        match heap.set_index(&list, &index, element, Roots::new(values, globals)) {
+                            Ok(()) => *stack.peek_mut_unchecked() = element,
+                            Err(e) => runtime_error!(chunk, start, op_ip, e),
+                        }
+                    }
+                    OpCode::Negate => match stack.pop_unchecked() {
+                        Value::Number(n) => stack.push_unchecked(Value::Number(-n)),
+                        v => runtime_error!(
+                            chunk,
+                            start,
+                            op_ip,
+                            RuntimeError::new(
+                                RuntimeErrorKind::Type,
+                                format!("'{}' cannot be negated", v.value_type())
+                            )
+                        ),
+                    },
+                    OpCode::Add => {
+                        binary_op!(stack, stack.pop_unchecked(), add, "+", chunk, start, op_ip)
+                    }
+                    OpCode::Subtract => {
+                        binary_op!(stack, stack.pop_unchecked(), sub, "-", chunk, start, op_ip)
+                    }
+                    OpCode::Multiply => {
+                        binary_op!(stack, stack.pop_unchecked(), mult, "*", chunk, start, op_ip)
+                    }
+                    OpCode::Divide => {
+                        binary_op!(stack, stack.pop_unchecked(), div, "/", chunk, start, op_ip)
+                    }
+                    OpCode::Modulo => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            modulo,
+                            "%",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::Power => {
+                        binary_op!(stack, stack.pop_unchecked(), pow, "**", chunk, start, op_ip)
+                    }
+                    OpCode::BitAnd => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            bit_and,
+                            "&",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::BitOr => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            bit_or,
+                            "|",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::BitXor => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            bit_xor,
+                            "^",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::BitNot => {
+                        let operand = stack.peek_mut_unchecked();
+                        match operand.bit_not() {
+                            Ok(value) => *operand = value,
+                            Err(kind) => {
+                                runtime_error!(chunk, start, op_ip, operand.unary_error(kind, "~"))
+                            }
+                        }
+                    }
+                    OpCode::ShiftLeft => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            shift_left,
+                            "<<",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::ShiftRight => {
+                        binary_op!(
+                            stack,
+                            stack.pop_unchecked(),
+                            shift_right,
+                            ">>",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::Equal => {
+                        let rhs = stack.pop_unchecked();
+                        let lhs = stack.peek_mut_unchecked();
+                        *lhs = Value::Bool(heap.equals(lhs, &rhs));
+                    }
+                    OpCode::NotEqual => {
+                        let rhs = stack.pop_unchecked();
+                        let lhs = stack.peek_mut_unchecked();
+                        *lhs = Value::Bool(!heap.equals(lhs, &rhs));
+                    }
+                    // --- superinstructions take the right hand side from the constants
+                    OpCode::AddConstant => binary_op!(
+                        stack,
+                        read_constant!(constants, ip),
+                        add,
+                        "+",
+                        chunk,
+                        start,
+                        op_ip
+                    ),
+                    OpCode::SubtractConstant => {
+                        binary_op!(
+                            stack,
+                            read_constant!(constants, ip),
+                            sub,
+                            "-",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::MultiplyConstant => {
+                        binary_op!(
+                            stack,
+                            read_constant!(constants, ip),
+                            mult,
+                            "*",
+                            chunk,
+                            start,
+                            op_ip
+                        )
+                    }
+                    OpCode::DivideConstant => {
+                        binary_op!(
+                            stack,
+                            read_constant!(constants, ip),
+                            div,
+                            "/",
+                            chunk,
+                            start,
+                            op_ip
+                        )
                     }
                 }
-
-                trace_stack!(self);
             }
         }
 
-        #[cfg(feature = "trace")]
-        {
-            log::debug!("--------- EOF ---------");
-            self.stack.trace();
-        }
-
         VMResult::Ok
     }
-
-    #[inline]
-    fn read_constant(&self, op_code: OpCode, ip: *const u8) -> anyhow::Result<(&Value, usize)> {
-        let (const_idx, offset) = match op_code {
-            OpCode::Load => (unsafe { *ip as usize }, 1),
-            OpCode::LoadLong => {
-                let constant_idx_as_bytes = unsafe { std::slice::from_raw_parts(ip, 3) };
-                (
-                    bitwise::u32_from_bytes(constant_idx_as_bytes.try_into().unwrap()) as usize,
-                    3,
-                )
-            }
-            _ => panic!("invalid op_code for read_constant: {}", op_code),
-        };
-
-        Ok((self.chunk.constants.get(const_idx).unwrap(), offset))
-    }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum VMResult {
     Ok,
     CompileError,
-    RuntimeError,
+    RuntimeError(RuntimeError),
+    /// a hook paused the run, which can be resumed with `VM::resume`
+    Paused,
+    /// the run exhausted its fuel - it can be resumed with `VM::resume` once fuel is added with
+    /// `VM::add_fuel`
+    OutOfFuel,
+    /// the run was stopped through an `InterruptHandle`, and can be resumed with `VM::resume`
+    Interrupted,
+    /// the chunk failed verification and was not run - stack underflows found by verification are
+    /// runtime errors instead
+    InvalidBytecode(VerifyError),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scanner::{
+        scanner::Scanner,
+        token::{Token, TokenType},
+    };
+
+    /// config of tests relying on the exact instructions of their chunks
+    const UNOPTIMIZED: VMConfig = VMConfig {
+        stack_size: STACK_SIZE,
+        max_frames: FRAMES_MAX,
+        fuel: None,
+        max_heap: None,
+        stdlib: true,
+        peephole: false,
+    };
+
+    fn runtime_error(res: VMResult) -> RuntimeError {
+        match res {
+            VMResult::RuntimeError(e) => e,
+            _ => panic!("expected a runtime error, got {:?}", res),
+        }
+    }
 
     #[test]
     fn negation_without_value() {
         let mut chunk = Chunk::new();
         chunk.write(OpCode::Negate);
         let mut vm = VM::new(chunk);
-        assert_eq!(vm.run(), VMResult::RuntimeError);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::StackUnderflow);
+        assert_eq!(
+            err.to_string(),
+            "[RUNTIME ERROR]: at line 1: Stack underflow\n\t[line 1] in script"
+        );
     }
 
     #[test]
@@ -164,6 +749,24 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn run_optimized_chunk() {
+        let chunk = make_chunk!(10.0, OpCode::Add, 5.0);
+        let mut vm = VM::new(chunk.clone());
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(15.0))).as_ref()
+        );
+        // --- the load of 5 was fused with the addition
+        assert_eq!(vm.chunk().code[2], OpCode::AddConstant as u8);
+        assert_eq!(vm.chunk().code.len(), 4);
+
+        let mut vm = VM::with_config(chunk.clone(), UNOPTIMIZED);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.chunk().code, chunk.code);
+    }
+
     #[test]
     fn add() {
         let chunk = make_chunk!(10.0, OpCode::Add, 5.0);
@@ -212,11 +815,731 @@ mod tests {
         );
     }
 
+    #[test]
+    fn modulo_power_and_bitwise_ops() {
+        let cases = [
+            (7.0, OpCode::Modulo, 3.0, 1.0),
+            (-7.0, OpCode::Modulo, 3.0, -1.0),
+            (7.5, OpCode::Modulo, 2.0, 1.5),
+            (2.0, OpCode::Power, 10.0, 1024.0),
+            (4.0, OpCode::Power, 0.5, 2.0),
+            (6.0, OpCode::BitAnd, 3.0, 2.0),
+            (6.0, OpCode::BitOr, 3.0, 7.0),
+            (6.0, OpCode::BitXor, 3.0, 5.0),
+            (-6.0, OpCode::BitAnd, 0xFF as f64, 250.0),
+            (1.0, OpCode::ShiftLeft, 10.0, 1024.0),
+            (1.0, OpCode::ShiftLeft, 64.0, 0.0),
+            (-16.0, OpCode::ShiftRight, 2.0, -4.0),
+            (-16.0, OpCode::ShiftRight, 100.0, -1.0),
+        ];
+        for (l, op, r, expected) in cases {
+            let chunk = make_chunk!(l, op, r);
+            let mut vm = VM::new(chunk);
+            assert_eq!(vm.run(), VMResult::Ok, "{} {} {}", l, op, r);
+            assert_eq!(
+                vm.stack.peek(),
+                Some(Value::Number(ordered_float::OrderedFloat(expected))).as_ref(),
+                "{} {} {}",
+                l,
+                op,
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn bit_not() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(5.0)));
+        chunk.write(OpCode::BitNot);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(-6.0))).as_ref()
+        );
+    }
+
+    #[test]
+    fn pop() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(42.0)));
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(1337.0)));
+        chunk.write(OpCode::Pop);
+
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(42.0))).as_ref()
+        );
+    }
+
+    #[test]
+    fn jump() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump();
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(1337.0)));
+        chunk.patch_jump(jump);
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(42.0)));
+
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.len(), 1);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(42.0))).as_ref()
+        );
+    }
+
+    #[test]
+    fn superinstructions() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(10.0)));
+        for op in [
+            OpCode::AddConstant,
+            OpCode::SubtractConstant,
+            OpCode::MultiplyConstant,
+            OpCode::DivideConstant,
+        ] {
+            chunk.write_constant(Value::Number(ordered_float::OrderedFloat(2.0)));
+            let load = chunk.code.len() - 2;
+            chunk.code[load] = op.into();
+        }
+
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(10.0))).as_ref()
+        );
+    }
+
+    #[test]
+    fn negation_of_literal() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Literal("Hello, world!"));
+        chunk.write(OpCode::Negate);
+        let mut vm = VM::new(chunk);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.msg, "'string literal' cannot be negated");
+    }
+
+    #[test]
+    fn invalid_constant() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Load);
+        chunk.write(0u8);
+        let mut vm = VM::new(chunk);
+        assert!(matches!(
+            vm.run(),
+            VMResult::InvalidBytecode(VerifyError::InvalidConstant { .. })
+        ));
+    }
+
+    #[test]
+    fn rerun_starts_with_empty_stack() {
+        let chunk = make_chunk!(10.0, OpCode::Add, 5.0);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.len(), 1);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut chunk = Chunk::new();
+        for _ in 0..3 {
+            chunk.write_constant(Value::Number(ordered_float::OrderedFloat(42.0)));
+        }
+
+        let mut vm = VM::with_config(
+            chunk,
+            VMConfig {
+                stack_size: 2,
+                ..Default::default()
+            },
+        );
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::StackOverflow);
+        assert_eq!(vm.stack.len(), 2);
+    }
+
+    #[test]
+    fn frame_limit() {
+        let chunk = make_chunk!(1.0, OpCode::Add, 2.0);
+        let config = VMConfig {
+            max_frames: 1,
+            ..Default::default()
+        };
+        assert_eq!(VM::with_config(chunk.clone(), config).run(), VMResult::Ok);
+
+        // --- the script frame is over the limit
+        let config = VMConfig {
+            max_frames: 0,
+            ..Default::default()
+        };
+        let err = runtime_error(VM::with_config(chunk, config).run());
+        assert_eq!(err.kind, RuntimeErrorKind::StackOverflow);
+    }
+
+    /// pauses before the instruction at offset
+    struct PauseAt(usize);
+
+    impl Hook for PauseAt {
+        fn on_instruction(&mut self, state: &VMState) -> Flow {
+            match state.offset == self.0 {
+                true => Flow::Pause,
+                false => Flow::Continue,
+            }
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let chunk = make_chunk!(10.0, OpCode::Add, 5.0);
+        let mut vm = VM::with_config(chunk, UNOPTIMIZED);
+        let mut hook = PauseAt(4);
+
+        assert_eq!(vm.run_with_hook(&mut hook), VMResult::Paused);
+        assert!(vm.is_paused());
+        assert_eq!(vm.stack.len(), 2);
+
+        // --- the instruction paused at is run without asking the hook again
+        assert_eq!(vm.resume(&mut hook), VMResult::Ok);
+        assert!(!vm.is_paused());
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(15.0))).as_ref()
+        );
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let chunk = make_chunk!(10.0, OpCode::Add, 5.0);
+        let mut vm = VM::with_config(
+            chunk,
+            VMConfig {
+                fuel: Some(2),
+                ..UNOPTIMIZED
+            },
+        );
+
+        assert_eq!(vm.run(), VMResult::OutOfFuel);
+        assert!(vm.is_paused());
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.stack.len(), 2);
+
+        // --- resuming without fuel stops again at the same instruction
+        assert_eq!(vm.resume(&mut NoHook), VMResult::OutOfFuel);
+
+        vm.add_fuel(5);
+        assert_eq!(vm.resume(&mut NoHook), VMResult::Ok);
+        assert_eq!(vm.fuel(), Some(4));
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(15.0))).as_ref()
+        );
+
+        // --- each run starts with the configured budget
+        assert_eq!(vm.run(), VMResult::OutOfFuel);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    /// interrupts the VM from another thread before the instruction at offset
+    struct InterruptAt(usize, InterruptHandle);
+
+    impl Hook for InterruptAt {
+        fn on_instruction(&mut self, state: &VMState) -> Flow {
+            if state.offset == self.0 {
+                let handle = self.1.clone();
+                std::thread::spawn(move || handle.interrupt())
+                    .join()
+                    .unwrap();
+            }
+            Flow::Continue
+        }
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut chunk = Chunk::new();
+        chunk
+            .constants
+            .push(Value::Number(ordered_float::OrderedFloat(1.0)));
+        for _ in 0..2 * INTERRUPT_INTERVAL {
+            chunk.write(OpCode::Load);
+            chunk.write(0u8);
+            chunk.write(OpCode::Pop);
+        }
+        let mut vm = VM::with_config(chunk, UNOPTIMIZED);
+        let mut hook = InterruptAt(0, vm.interrupt_handle());
+
+        assert_eq!(vm.run_with_hook(&mut hook), VMResult::Interrupted);
+        assert!(vm.is_paused());
+        assert_eq!(vm.resume(&mut hook), VMResult::Ok);
+
+        // --- interruptions requested before a run do not stop it
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.run(), VMResult::Ok);
+    }
+
+    /// chunk calling the global native name with args
+    fn call_chunk(vm: &VM, name: &str, args: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(vm.global(name).unwrap());
+        args.iter().for_each(|arg| chunk.write_constant(*arg));
+        chunk.write(OpCode::Call);
+        chunk.write(args.len() as u8);
+        chunk
+    }
+
+    #[test]
+    fn call_native() {
+        let vm = VM::new(Chunk::new());
+        let chunk = call_chunk(
+            &vm,
+            "sqrt",
+            &[Value::Number(ordered_float::OrderedFloat(16.0))],
+        );
+        let mut vm = VM::new(chunk);
+
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.len(), 1);
+        assert_eq!(
+            vm.stack.peek(),
+            Some(Value::Number(ordered_float::OrderedFloat(4.0))).as_ref()
+        );
+
+        let chunk = call_chunk(&vm, "upper", &[Value::Literal("rox")]);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.heap().str(vm.stack.peek().unwrap()), Some("ROX"));
+    }
+
+    #[test]
+    fn call_errors() {
+        let vm = VM::new(Chunk::new());
+        let chunk = call_chunk(&vm, "sqrt", &[]);
+        let err = runtime_error(VM::new(chunk).run());
+        assert_eq!(err.kind, RuntimeErrorKind::Arity);
+        assert_eq!(err.msg, "'sqrt' expects 1 arguments but got 0");
+
+        let chunk = call_chunk(&vm, "sqrt", &[Value::Literal("16")]);
+        let err = runtime_error(VM::new(chunk).run());
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Literal("sqrt"));
+        chunk.write(OpCode::Call);
+        chunk.write(0u8);
+        let err = runtime_error(VM::new(chunk).run());
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.msg, "'string literal' is not callable");
+    }
+
+    /// chunk building the list [1, 2, 3], followed by index
+    fn list_chunk(index: Value) -> Chunk {
+        let mut chunk = Chunk::new();
+        for n in [1.0, 2.0, 3.0] {
+            chunk.write_constant(Value::Number(ordered_float::OrderedFloat(n)));
+        }
+        chunk.write(OpCode::BuildList);
+        chunk.write(0u8);
+        chunk.write(3u8);
+        chunk.write_constant(index);
+        chunk
+    }
+
+    #[test]
+    fn lists() {
+        let number = |n| Value::Number(ordered_float::OrderedFloat(n));
+
+        // --- [1, 2, 3][1] = 42
+        let mut chunk = list_chunk(number(1.0));
+        chunk.write_constant(number(42.0));
+        chunk.write(OpCode::SetIndex);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.values(), &[number(42.0)]);
+
+        // --- [1, 2, 3][2]
+        let mut chunk = list_chunk(number(2.0));
+        chunk.write(OpCode::GetIndex);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.values(), &[number(3.0)]);
+
+        // --- the list is left on the stack without indexing it
+        let mut chunk = list_chunk(number(0.0));
+        chunk.write(OpCode::Pop);
+        let mut vm = VM::new(chunk);
+        assert_eq!(vm.run(), VMResult::Ok);
+        let list = *vm.stack.peek().unwrap();
+        assert_eq!(list.value_type(), "list");
+        assert_eq!(vm.heap().display(&list), "[1, 2, 3]");
+    }
+
+    #[test]
+    fn list_equality() {
+        let write_list = |chunk: &mut Chunk, elements: &[f64]| {
+            for &n in elements {
+                chunk.write_constant(Value::Number(ordered_float::OrderedFloat(n)));
+            }
+            chunk.write(OpCode::BuildList);
+            chunk.write(0u8);
+            chunk.write(elements.len() as u8);
+        };
+
+        // --- distinct lists are compared by their elements
+        let cases = [
+            (
+                &[1.0, 2.0, 3.0][..],
+                &[1.0, 2.0, 3.0][..],
+                OpCode::Equal,
+                true,
+            ),
+            (&[1.0, 2.0, 3.0], &[1.0, 2.0], OpCode::Equal, false),
+            (&[], &[], OpCode::Equal, true),
+            (&[1.0, 2.0], &[1.0, 2.0], OpCode::NotEqual, false),
+            (&[1.0, 2.0], &[2.0, 1.0], OpCode::NotEqual, true),
+        ];
+        for (lhs, rhs, op, expected) in cases {
+            let mut chunk = Chunk::new();
+            write_list(&mut chunk, lhs);
+            write_list(&mut chunk, rhs);
+            chunk.write(op);
+            let mut vm = VM::new(chunk);
+            assert_eq!(vm.run(), VMResult::Ok);
+            assert_eq!(
+                vm.stack.values(),
+                &[Value::Bool(expected)],
+                "{:?} {} {:?}",
+                lhs,
+                op,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let number = |n| Value::Number(ordered_float::OrderedFloat(n));
+
+        // --- {1: 2, 1: 3, nil: true}[1] = 4
+        let mut chunk = Chunk::new();
+        for value in [number(1.0), number(2.0), number(1.0), number(3.0)] {
+            chunk.write_constant(value);
+        }
+        chunk.write_constant(Value::Empty);
+        chunk.write_constant(Value::Bool(true));
+        chunk.write(OpCode::BuildMap);
+        chunk.write(0u8);
+        chunk.write(3u8);
+        let mut vm = VM::new(chunk.clone());
+        assert_eq!(vm.run(), VMResult::Ok);
+        let map = *vm.stack.peek().unwrap();
+        assert_eq!(vm.heap().display(&map), "{nil: true, 1: 3}");
+
+        // --- the assignment replaces the map with the value assigned
+        let mut set = chunk.clone();
+        set.write_constant(number(1.0));
+        set.write_constant(number(4.0));
+        set.write(OpCode::SetIndex);
+        let mut vm = VM::new(set);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert_eq!(vm.stack.values(), &[number(4.0)]);
+        assert_eq!(vm.heap().display(&map), "{nil: true, 1: 4}");
+
+        chunk.write_constant(Value::Literal("rox"));
+        chunk.write(OpCode::GetIndex);
+        let err = runtime_error(VM::new(chunk).run());
+        assert_eq!(err.kind, RuntimeErrorKind::Key);
+        assert_eq!(err.msg, "key \"rox\" not found in map");
+    }
+
+    /// chunk building n empty lists, popping each one right away if pop
+    fn lists_chunk(n: usize, pop: bool) -> Chunk {
+        let mut chunk = Chunk::new();
+        for _ in 0..n {
+            chunk.write(OpCode::BuildList);
+            chunk.write(0u8);
+            chunk.write(0u8);
+            if pop {
+                chunk.write(OpCode::Pop);
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let config = VMConfig {
+            max_heap: Some(1024),
+            ..Default::default()
+        };
+
+        // --- far more lists than fit in the heap at once, but only one is alive at a time
+        let mut vm = VM::with_config(lists_chunk(1000, true), config);
+        assert_eq!(vm.run(), VMResult::Ok);
+        assert!(vm.heap().peak() <= 1024);
+        assert!(vm.heap().objects() < 1000);
+
+        // --- lists kept on the stack are never collected
+        let mut vm = VM::with_config(lists_chunk(100, false), config);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn list_index_errors() {
+        let number = |n| Value::Number(ordered_float::OrderedFloat(n));
+        let cases = [
+            (
+                number(-1.0),
+                RuntimeErrorKind::Index,
+                "negative list index -1",
+            ),
+            (
+                number(3.0),
+                RuntimeErrorKind::Index,
+                "index 3 is out of bounds for a list of length 3",
+            ),
+            (
+                number(0.5),
+                RuntimeErrorKind::Index,
+                "list index must be an integer, got 0.5",
+            ),
+            (
+                Value::Literal("0"),
+                RuntimeErrorKind::Type,
+                "list index must be a number, got 'string literal'",
+            ),
+        ];
+        for (index, kind, msg) in cases {
+            let mut chunk = list_chunk(index);
+            chunk.write(OpCode::GetIndex);
+            let err = runtime_error(VM::new(chunk).run());
+            assert_eq!((err.kind, err.msg.as_str()), (kind, msg));
+        }
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(number(1.0));
+        chunk.write_constant(number(0.0));
+        chunk.write(OpCode::GetIndex);
+        let err = runtime_error(VM::new(chunk).run());
+        assert_eq!(err.msg, "'number' cannot be indexed");
+    }
+
+    #[test]
+    fn stdlib_opt_out() {
+        assert!(VM::new(Chunk::new()).global("clock").is_some());
+
+        let config = VMConfig {
+            stdlib: false,
+            ..Default::default()
+        };
+        let mut vm = VM::with_config(Chunk::new(), config);
+        assert_eq!(vm.global("clock"), None);
+        // --- list and map natives are still defined
+        for native in CORE {
+            assert_eq!(vm.global(native.name), Some(Value::Native(native)));
+        }
+
+        vm.define_native(&STDLIB[0]);
+        assert_eq!(vm.global("clock"), Some(Value::Native(&STDLIB[0])));
+
+        // --- len([1, 2, 3]) and len({1: 2})
+        let number = |n| Value::Number(ordered_float::OrderedFloat(n));
+        let cases = [
+            (OpCode::BuildList, &[1.0, 2.0, 3.0][..], 3u8, 3.0),
+            (OpCode::BuildMap, &[1.0, 2.0], 1, 1.0),
+        ];
+        for (build, elements, len, expected) in cases {
+            let mut chunk = Chunk::new();
+            chunk.write_constant(vm.global("len").unwrap());
+            elements
+                .iter()
+                .for_each(|&n| chunk.write_constant(number(n)));
+            chunk.write(build);
+            chunk.write(0u8);
+            chunk.write(len);
+            chunk.write(OpCode::Call);
+            chunk.write(1u8);
+
+            let mut vm = VM::with_config(chunk, config);
+            assert_eq!(vm.run(), VMResult::Ok);
+            assert_eq!(vm.stack.values(), &[number(expected)]);
+        }
+    }
+
     #[test]
     fn divide_by_zero() {
         let chunk = make_chunk!(10.0, OpCode::Divide, 0.0);
         let mut vm = VM::new(chunk);
-        let res = vm.run();
-        assert_eq!(res, VMResult::RuntimeError);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn bitwise_errors() {
+        let cases = [
+            (
+                make_chunk!(1.5, OpCode::BitAnd, 1.0),
+                RuntimeErrorKind::InvalidArgument,
+                "'&' requires integer operands, got 1.5 and 1",
+            ),
+            (
+                make_chunk!(1.0, OpCode::BitOr, 1e20),
+                RuntimeErrorKind::InvalidArgument,
+                "'|' requires integer operands, got 1 and 100000000000000000000",
+            ),
+            (
+                make_chunk!(1.0, OpCode::ShiftLeft, -1.0),
+                RuntimeErrorKind::InvalidArgument,
+                "'<<' requires integers and a non-negative shift amount, got 1 and -1",
+            ),
+            (
+                make_chunk!(5.0, OpCode::Modulo, 0.0),
+                RuntimeErrorKind::DivisionByZero,
+                "right hand side of the modulo is 0",
+            ),
+        ];
+        for (chunk, kind, msg) in cases {
+            let mut vm = VM::new(chunk);
+            let err = runtime_error(vm.run());
+            assert_eq!((err.kind, err.msg.as_str()), (kind, msg));
+        }
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Literal("rox"));
+        chunk.write(OpCode::BitNot);
+        let mut vm = VM::new(chunk);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.msg, "'string literal' is not a valid operand for '~'");
+    }
+
+    #[test]
+    fn runtime_error_line_after_blank_lines() {
+        // --- 1 + 2
+        //
+        //
+        //     - "Hello, world!"
+        let mut chunk = Chunk::new();
+        chunk.set_line(1);
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(1.0)));
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(2.0)));
+        chunk.write(OpCode::Add);
+        chunk.set_line(4);
+        chunk.write_constant(Value::Literal("Hello, world!"));
+        chunk.write(OpCode::Subtract);
+
+        let mut vm = VM::new(chunk);
+        assert_eq!(runtime_error(vm.run()).line, 4);
+    }
+
+    #[test]
+    fn runtime_error_line_after_multiline_string() {
+        // --- "Hello,
+        //     world!"
+        //     ;
+        //     -42 / 0
+        let mut chunk = Chunk::new();
+        chunk.set_line(2);
+        chunk.write_constant(Value::Literal("Hello,\nworld!"));
+        chunk.write(OpCode::Pop);
+        chunk.set_line(4);
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(42.0)));
+        chunk.write(OpCode::Negate);
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(0.0)));
+        chunk.write(OpCode::Divide);
+
+        let mut vm = VM::new(chunk);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+        assert_eq!(err.line, 4);
+    }
+
+    /// Emits src, statements made of number and string literals joined by arithmetic operators,
+    /// as the compiler would: each instruction is located at the line of the token it comes from
+    fn emit(src: &'static str) -> Chunk {
+        let tokens = Scanner::new(src).scan().unwrap();
+        let mut chunk = Chunk::new();
+        // --- binary operators are emitted once their right operand has been
+        let mut operator: Option<Token> = None;
+
+        for token in tokens {
+            let lexeme = token.lexeme.unwrap_or_default();
+            let value = match token.token_type {
+                TokenType::Number => {
+                    Value::Number(ordered_float::OrderedFloat(lexeme.parse().unwrap()))
+                }
+                TokenType::StringLiteral => Value::Literal(&lexeme[1..lexeme.len() - 1]),
+                TokenType::Semicolon => {
+                    chunk.set_line(token.line);
+                    chunk.write(OpCode::Pop);
+                    continue;
+                }
+                TokenType::EOF => continue,
+                _ => {
+                    operator = Some(token);
+                    continue;
+                }
+            };
+            chunk.set_line(token.line);
+            chunk.write_constant(value);
+
+            if let Some(op) = operator.take() {
+                chunk.set_line(op.line);
+                chunk.write(match op.token_type {
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Star => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    op => panic!("unexpected operator '{}'", op),
+                });
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn runtime_error_line_from_tokens() {
+        let src = "\"Hello,\nworld!\";\n\n1 +\n  2;\n42 /\n\n  0;";
+        let err = runtime_error(VM::new(emit(src)).run());
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+        // --- the error is located at the operator, not at its operands
+        assert_eq!(err.line, 6);
+
+        let src = "1 + \"multi\n\nline\";";
+        let err = runtime_error(VM::new(emit(src)).run());
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn runtime_error_location() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(ordered_float::OrderedFloat(42.0)));
+        chunk.set_line(2);
+        chunk.write_constant(Value::Literal("Hello, world!"));
+        chunk.write(OpCode::Add);
+
+        let mut vm = VM::new(chunk);
+        let err = runtime_error(vm.run());
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.frames,
+            vec![Frame {
+                function: String::from("script"),
+                line: 2
+            }]
+        );
+        assert_eq!(
+            err.to_string(),
+            "[RUNTIME ERROR]: at line 2: 'number' + 'string literal' is not a valid operation\n\t[line 2] in script"
+        );
     }
 }